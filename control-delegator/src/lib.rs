@@ -1,5 +1,8 @@
+use email_auth_types::{method, AddKeyArgs, DeleteKeyArgs, SetControllerArgs, TransferArgs};
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::{env, near_bindgen, AccountId, Balance, PanicOnDefault, Promise, PublicKey};
+use near_sdk::serde::de::DeserializeOwned;
+use near_sdk::serde_json;
+use near_sdk::{env, near_bindgen, require, AccountId, PanicOnDefault, Promise, PublicKey};
 
 // Define the contract structure
 #[near_bindgen]
@@ -8,26 +11,135 @@ pub struct ControlDelegator {
     controller_id: AccountId,
 }
 
-// Implement the contract structure
+/// The arguments of the method `name`, deserialized from the JSON input of the
+/// call into their `email_auth_types` struct
+fn args<T: DeserializeOwned>(name: &str) -> T {
+    let input = env::input().unwrap_or_default();
+    serde_json::from_slice(&input)
+        .unwrap_or_else(|err| env::panic_str(&format!("{}: invalid arguments: {}", name, err)))
+}
+
+fn account_id(account_id: &str) -> AccountId {
+    account_id
+        .parse()
+        .unwrap_or_else(|_| env::panic_str(&format!("invalid account id {}", account_id)))
+}
+
+fn public_key(public_key: &str) -> PublicKey {
+    public_key
+        .parse()
+        .unwrap_or_else(|_| env::panic_str(&format!("invalid public key {}", public_key)))
+}
+
+// Implement the contract structure. The methods are called by the
+// dkim-controller, their names are the ones of `email_auth_types::method` and
+// their arguments are read from the `email_auth_types` structs.
 #[near_bindgen]
 impl ControlDelegator {
     #[init]
-    pub fn set_controller(controller_id: AccountId) -> Self {
-        Self { controller_id }
+    pub fn set_controller() -> Self {
+        let SetControllerArgs { controller_id } = args(method::SET_CONTROLLER);
+        Self {
+            controller_id: account_id(&controller_id),
+        }
+    }
+
+    pub fn add_key(self) {
+        self.assert_controller();
+        let AddKeyArgs { public_key: key } = args(method::ADD_KEY);
+        Promise::new(env::current_account_id()).add_full_access_key(public_key(&key));
+    }
+
+    pub fn delete_key(self) {
+        self.assert_controller();
+        let DeleteKeyArgs { public_key: key } = args(method::DELETE_KEY);
+        Promise::new(env::current_account_id()).delete_key(public_key(&key));
+    }
+
+    pub fn transfer(self) {
+        self.assert_controller();
+        let TransferArgs { to, amount } = args(method::TRANSFER);
+        Promise::new(account_id(&to)).transfer(amount);
     }
+}
 
-    pub fn add_key(self, public_key: PublicKey) {
-        assert!(env::predecessor_account_id() == self.controller_id);
-        Promise::new(env::current_account_id()).add_full_access_key(public_key);
+impl ControlDelegator {
+    fn assert_controller(&self) {
+        require!(
+            env::predecessor_account_id() == self.controller_id,
+            "only the controller can call this method"
+        );
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use email_auth_types::ONE_NEAR;
+    use near_sdk::serde::Serialize;
+    use near_sdk::test_utils::{get_created_receipts, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    const CONTROLLER: &str = "controller.near";
+    const KEY: &str = "ed25519:3tXAA9zf5YSLxYELSbxwhEvMd7h9itTfCcUfEc3QfPgD";
 
-    pub fn delete_key(self, public_key: PublicKey) {
-        assert!(env::predecessor_account_id() == self.controller_id);
-        Promise::new(env::current_account_id()).delete_key(public_key);
+    /// Calls the method `$method` of `$delegator` with the JSON of `$args` as
+    /// input, after checking that it is exposed under the shared name `$name`
+    macro_rules! call {
+        ($delegator:expr, $method:ident, $name:expr, $args:expr) => {{
+            let delegator = $delegator;
+            assert_eq!(stringify!($method), $name);
+            set_input(&$args);
+            delegator.$method()
+        }};
     }
 
-    pub fn transfer(self, to: AccountId, amount: Balance) {
-        assert!(env::predecessor_account_id() == self.controller_id);
-        Promise::new(to).transfer(amount);
+    fn set_input(args: &impl Serialize) {
+        let mut builder = VMContextBuilder::new();
+        builder.predecessor_account_id(CONTROLLER.parse().unwrap());
+        builder.context.input = serde_json::to_vec(args).unwrap();
+        testing_env!(builder.build());
+    }
+
+    fn delegator() -> ControlDelegator {
+        assert_eq!(stringify!(set_controller), method::SET_CONTROLLER);
+        set_input(&SetControllerArgs {
+            controller_id: CONTROLLER.to_owned(),
+        });
+        ControlDelegator::set_controller()
+    }
+
+    #[test]
+    fn test_shared_methods() {
+        assert_eq!(delegator().controller_id.as_str(), CONTROLLER);
+
+        call!(
+            delegator(),
+            add_key,
+            method::ADD_KEY,
+            AddKeyArgs {
+                public_key: KEY.to_owned(),
+            }
+        );
+        assert_eq!(get_created_receipts().len(), 1);
+        call!(
+            delegator(),
+            delete_key,
+            method::DELETE_KEY,
+            DeleteKeyArgs {
+                public_key: KEY.to_owned(),
+            }
+        );
+        assert_eq!(get_created_receipts().len(), 1);
+        call!(
+            delegator(),
+            transfer,
+            method::TRANSFER,
+            TransferArgs {
+                to: "bob.near".to_owned(),
+                amount: ONE_NEAR,
+            }
+        );
+        assert_eq!(get_created_receipts().len(), 1);
     }
 }
@@ -1,21 +1,18 @@
 use mailparse::{addrparse_header, parse_mail, MailHeaderMap};
 use near_dkim::dns::Lookup;
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::serde::Serialize;
 use near_sdk::store::LookupMap;
 use near_sdk::{
     env, near_bindgen, require, AccountId, Balance, Gas, GasWeight, PanicOnDefault, Promise,
-    PublicKey, ONE_NEAR,
 };
 
-use near_dkim::verify_email_with_resolver;
-
-pub fn always_fail(_: &mut [u8]) -> Result<(), getrandom::Error> {
-    unimplemented!()
-}
-
-use getrandom::register_custom_getrandom;
-register_custom_getrandom!(always_fail);
+use chrono::TimeZone;
+use email_auth_types::{
+    method, AddKeyArgs, ControllerEvent, DeleteKeyArgs, SetControllerArgs, TransferArgs,
+};
+pub use email_command::CommandEnum;
+use email_command::SubjectNormalizer;
+use near_dkim::{verify_raw_email_with_resolver, AuthenticationResult, DKIMResult};
 
 // Define the contract structure
 #[near_bindgen]
@@ -43,33 +40,6 @@ impl Lookup for DkimResolver {
 const MIN_STORAGE: Balance = 4_200_000_000_000_000_000_000_000; //11.1Ⓝ
 const ACCESS_DELEGATOR_CODE: &[u8] = include_bytes!("access_delegator.wasm");
 
-#[derive(Debug, PartialEq)]
-pub enum CommandEnum {
-    Init,
-    AddKey(PublicKey),
-    DeleteKey,
-    Transfer(AccountId, Balance),
-}
-
-#[derive(Serialize)]
-#[serde(crate = "near_sdk::serde")]
-struct AddKeyArgs {
-    public_key: PublicKey,
-}
-
-#[derive(Serialize)]
-#[serde(crate = "near_sdk::serde")]
-struct TransferArgs {
-    to: AccountId,
-    amount: Balance,
-}
-
-#[derive(Serialize)]
-#[serde(crate = "near_sdk::serde")]
-struct NewContractArgs {
-    controller_id: AccountId,
-}
-
 // Implement the contract structure
 #[near_bindgen]
 impl DkimController {
@@ -88,8 +58,8 @@ impl DkimController {
     }
 
     fn create_new_subaccount(account_id: AccountId) {
-        let create_args = near_sdk::serde_json::to_vec(&NewContractArgs {
-            controller_id: env::current_account_id(),
+        let create_args = near_sdk::serde_json::to_vec(&SetControllerArgs {
+            controller_id: env::current_account_id().to_string(),
         })
         .unwrap();
 
@@ -98,7 +68,7 @@ impl DkimController {
             .transfer(MIN_STORAGE)
             .deploy_contract(ACCESS_DELEGATOR_CODE.to_vec())
             .function_call_weight(
-                "set_controller".to_owned(),
+                method::SET_CONTROLLER.to_owned(),
                 create_args,
                 0,
                 Gas(0),
@@ -106,11 +76,11 @@ impl DkimController {
             );
     }
 
-    fn add_key(account_id: AccountId, public_key: PublicKey) {
+    fn add_key(account_id: AccountId, public_key: String) {
         let add_key_args = near_sdk::serde_json::to_vec(&AddKeyArgs { public_key }).unwrap();
 
         Promise::new(account_id).function_call_weight(
-            "add_key".to_owned(),
+            method::ADD_KEY.to_owned(),
             add_key_args,
             0,
             Gas(0),
@@ -118,11 +88,23 @@ impl DkimController {
         );
     }
 
-    fn transfer(account_id: AccountId, to: AccountId, amount: Balance) {
+    fn delete_key(account_id: AccountId, public_key: String) {
+        let delete_key_args = near_sdk::serde_json::to_vec(&DeleteKeyArgs { public_key }).unwrap();
+
+        Promise::new(account_id).function_call_weight(
+            method::DELETE_KEY.to_owned(),
+            delete_key_args,
+            0,
+            Gas(0),
+            GasWeight(1),
+        );
+    }
+
+    fn transfer(account_id: AccountId, to: String, amount: Balance) {
         let transfer_args = near_sdk::serde_json::to_vec(&TransferArgs { to, amount }).unwrap();
 
         Promise::new(account_id).function_call_weight(
-            "transfer".to_owned(),
+            method::TRANSFER.to_owned(),
             transfer_args,
             0,
             Gas(0),
@@ -130,18 +112,47 @@ impl DkimController {
         );
     }
 
+    fn verify_dkim(&self, full_email: &[u8]) -> DKIMResult {
+        // Signature expiration is checked against the block time
+        let now = chrono::Utc.timestamp_nanos(env::block_timestamp() as i64);
+        verify_raw_email_with_resolver(full_email, &self.resolver, now)
+            .unwrap_or_else(|err| env::panic_str(&format!("Email verification failed: {}", err)))
+    }
+
+    /// Returns the DKIM verification report of an email, without executing
+    /// its command
+    pub fn check_email(&self, full_email: Vec<u8>) -> DKIMResult {
+        self.verify_dkim(&full_email)
+    }
+
     fn verify_email(&self, full_email: Vec<u8>) -> (String, String) {
         let email = parse_mail(full_email.as_slice())
             .unwrap_or_else(|err| env::panic_str(&format!("The email is malformed: {}", err)));
 
-        let result = verify_email_with_resolver(&email, &self.resolver)
-            .unwrap_or_else(|err| env::panic_str(&format!("Email verification failed: {}", err)));
+        let result = self.verify_dkim(&full_email);
+        let code = result
+            .code()
+            .map(|code| format!(", code={}", code))
+            .unwrap_or_default();
         require!(
-            result.summary() == "pass",
-            "Email signature does not match its contents"
+            result.result() == AuthenticationResult::Pass,
+            format!(
+                "Email signature does not match its contents (dkim={}{})",
+                result.summary(),
+                code
+            )
         );
 
+        // DKIM signs the last instances of the headers (RFC 6376 §5.4.2) so
+        // that a From or a Subject added on top of the email passes the
+        // verification. RFC 5322 allows only one of each.
         let headers = email.get_headers();
+        for name in ["From", "Subject"] {
+            require!(
+                headers.get_all_headers(name).len() <= 1,
+                format!("The email has more than one \"{}\" header", name)
+            );
+        }
         let from_header = headers
             .get_first_header("From")
             .unwrap_or_else(|| env::panic_str("The email lacks \"From\" header"));
@@ -159,83 +170,35 @@ impl DkimController {
             _ => env::panic_str("The email \"From\" header contains a group of addresses"),
         };
 
-        let subject = match email.get_headers().get_first_header("Subject") {
-            Some(subject_header) => subject_header.get_value(),
-            None => env::panic_str("The email lacks \"From\" header"),
+        let subject = match headers.get_first_header("Subject") {
+            Some(subject_header) => {
+                SubjectNormalizer::default().normalize(&subject_header.get_value())
+            }
+            None => env::panic_str("The email lacks \"Subject\" header"),
         };
 
         (addr, subject)
     }
 
-    fn validate_key(key: &str) -> PublicKey {
-        require!(key.len() == 52, "The key length is not 52");
-        let key_suffix = key
-            .strip_prefix("ed25519:")
-            .unwrap_or_else(|| env::panic_str("The key prefix does not match \"ed25519:\""));
-
-        key_suffix.chars().for_each(|x| match x {
-            'A'..='Z' | 'a'..='z' | '0'..='9' => (),
-            _ => env::panic_str(&format!("The key contains an invalid character '{}'", x)),
-        });
-
-        key.parse()
-            .unwrap_or_else(|err| env::panic_str(&format!("The key is malformed: {}", err)))
-    }
-
     fn parse_command(subject: String) -> CommandEnum {
-        let cmds: Vec<&str> = subject.split_whitespace().collect();
-        match cmds.as_slice() {
-            ["init"] => CommandEnum::Init,
-            ["add_key", key] => CommandEnum::AddKey(DkimController::validate_key(key)),
-            // TODO: Unsupported for now, this command should accept a public key and delete it
-            ["delete_key"] => CommandEnum::DeleteKey,
-            ["transfer", account, amount] => {
-                let amount: f64 = amount.parse().unwrap_or_else(|err| {
-                    env::panic_str(&format!(
-                        "Failed to transfer due to malformed amount: {}",
-                        err
-                    ))
-                });
-                require!(amount > 0.0, "Transfer amount must be positive");
-                let amount = ((amount * 100.0) as u128) * (ONE_NEAR / 100);
-
-                let account: AccountId = account.parse().unwrap_or_else(|err| {
-                    env::panic_str(&format!(
-                        "Failed to transfer due to malformed account: {}",
-                        err
-                    ))
-                });
-
-                CommandEnum::Transfer(account, amount)
-            }
-            _ => env::panic_str("Unrecognized subject"),
-        }
+        email_command::parse_command(&subject)
+            .unwrap_or_else(|err| env::panic_str(&format!("Unrecognized subject: {}", err)))
     }
 
     fn sender_to_account(sender: String) -> String {
-        sender
-            .chars()
-            .map(|x| match x {
-                'a'..='z' => x,
-                'A'..='Z' => x,
-                '0'..='9' => x,
-                '@' => '_',
-                '.' => '_',
-                '_' => x,
-                '-' => x,
-                _ => env::panic_str(&format!(
-                    "The sender email contains an unsupported character '{}'",
-                    x
-                )),
-            })
-            .collect()
+        email_auth_types::sender_to_account(&sender).unwrap_or_else(|x| {
+            env::panic_str(&format!(
+                "The sender email contains an unsupported character '{}'",
+                x
+            ))
+        })
     }
 
     pub fn receive_email(&self, full_email: Vec<u8>) {
         // verify email
         let (sender, header) = self.verify_email(full_email);
         env::log_str(format!("Email verified: {}", sender).as_str());
-        let prefix = DkimController::sender_to_account(sender);
+        let prefix = DkimController::sender_to_account(sender.clone());
         env::log_str(format!("Account prefix is: {}", prefix).as_str());
         let account_id: AccountId = (prefix + "." + env::current_account_id().as_ref())
             .parse()
@@ -243,18 +206,38 @@ impl DkimController {
                 env::panic_str("Unexpected error: failed to derive a valid account id")
             });
         let cmd = DkimController::parse_command(header);
+        let event = ControllerEvent::Command {
+            sender,
+            account_id: account_id.to_string(),
+            command: cmd.to_string(),
+        };
         match cmd {
             CommandEnum::Init => DkimController::create_new_subaccount(account_id),
             CommandEnum::AddKey(key) => DkimController::add_key(account_id, key),
+            CommandEnum::DeleteKey(key) => DkimController::delete_key(account_id, key),
             CommandEnum::Transfer(to, amount) => DkimController::transfer(account_id, to, amount),
-            _ => todo!(),
         }
+        env::log_str(&event.to_log());
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use near_dkim::testing::{EmailBuilder, TestKey};
+    use near_sdk::mock::VmAction;
+    use near_sdk::test_utils::{get_created_receipts, get_logs};
+    use near_sdk::ONE_NEAR;
+
+    /// Controller that also trusts the key of the email fixtures
+    fn controller_with_key(key: &TestKey) -> DkimController {
+        let mut controller = DkimController::new();
+        controller
+            .resolver
+            .map
+            .insert(key.dns_name(), key.dns_record());
+        controller
+    }
 
     #[test]
     pub fn test_parse_command() {
@@ -310,7 +293,23 @@ mod tests {
         );
     }
     #[test]
-    #[should_panic]
+    pub fn check_email() {
+        let auth_manager = DkimController::new();
+        assert_eq!(
+            auth_manager
+                .check_email(include_bytes!("message.eml").to_vec())
+                .summary(),
+            "pass"
+        );
+
+        let report = auth_manager.check_email(include_bytes!("invalid_message.eml").to_vec());
+        let report = near_sdk::serde_json::to_value(report).unwrap();
+        assert_eq!(report["result"], "fail");
+        assert_eq!(report["error"]["code"], 301);
+    }
+
+    #[test]
+    #[should_panic(expected = "(dkim=fail, code=301)")]
     pub fn verify_invalid_email() {
         let auth_manager = DkimController::new();
         assert_eq!(
@@ -321,4 +320,192 @@ mod tests {
             )
         );
     }
+
+    #[test]
+    pub fn receive_email_commands() {
+        let key = TestKey::rsa("example.com", "test");
+        let controller = controller_with_key(&key);
+        for subject in [
+            "init",
+            "add_key ed25519:3tXAA9zf5YSLxYELSbxwhEvMd7h9itTfCcUfEc3QfPgD",
+            "delete_key ed25519:3tXAA9zf5YSLxYELSbxwhEvMd7h9itTfCcUfEc3QfPgD",
+            "transfer bob.near 1.5",
+        ] {
+            let email = EmailBuilder::new(&key)
+                .from("Alice <alice@example.com>")
+                .subject(subject)
+                .build();
+            controller.receive_email(email);
+        }
+        let logs = get_logs();
+        assert_eq!(logs.len(), 12);
+        for (logs, command) in logs.chunks(3).zip([
+            "init",
+            "add_key ed25519:3tXAA9zf5YSLxYELSbxwhEvMd7h9itTfCcUfEc3QfPgD",
+            "delete_key ed25519:3tXAA9zf5YSLxYELSbxwhEvMd7h9itTfCcUfEc3QfPgD",
+            "transfer bob.near 1.5",
+        ]) {
+            assert_eq!(
+                logs[..2],
+                [
+                    "Email verified: alice@example.com",
+                    "Account prefix is: alice_example_com",
+                ]
+            );
+            assert_eq!(
+                ControllerEvent::from_log(&logs[2]),
+                Some(ControllerEvent::Command {
+                    sender: "alice@example.com".to_owned(),
+                    account_id: format!("alice_example_com.{}", env::current_account_id()),
+                    command: command.to_owned(),
+                })
+            );
+        }
+
+        // delete_key calls the control-delegator of the account
+        let receipts = get_created_receipts();
+        assert_eq!(
+            receipts[2].receiver_id.as_str(),
+            format!("alice_example_com.{}", env::current_account_id())
+        );
+        match &receipts[2].actions[..] {
+            [VmAction::FunctionCall {
+                function_name,
+                args,
+                ..
+            }] => {
+                assert_eq!(function_name, method::DELETE_KEY);
+                assert_eq!(
+                    near_sdk::serde_json::from_slice::<DeleteKeyArgs>(args).unwrap(),
+                    DeleteKeyArgs {
+                        public_key: "ed25519:3tXAA9zf5YSLxYELSbxwhEvMd7h9itTfCcUfEc3QfPgD"
+                            .to_owned()
+                    }
+                );
+            }
+            actions => panic!("unexpected actions {:?}", actions),
+        }
+    }
+
+    #[test]
+    pub fn verify_email_signed_with_ed25519() {
+        let key = TestKey::ed25519("example.com", "ed");
+        let email = EmailBuilder::new(&key)
+            .from("alice@example.com")
+            .subject("init")
+            .build();
+        assert_eq!(
+            controller_with_key(&key).verify_email(email),
+            ("alice@example.com".to_owned(), "init".to_owned())
+        );
+    }
+
+    #[test]
+    pub fn verify_email_subject_from_clients() {
+        let key = TestKey::rsa("example.com", "test");
+        let controller = controller_with_key(&key);
+        for (client, email, expected) in [
+            (
+                "Thunderbird",
+                &include_bytes!("clients/thunderbird_forward.eml")[..],
+                "transfer bob.near 1.5",
+            ),
+            (
+                "Outlook (German)",
+                include_bytes!("clients/outlook_forward.eml"),
+                "init",
+            ),
+            (
+                "Apple Mail",
+                include_bytes!("clients/apple_mail_forward.eml"),
+                "transfer bob.near 1.5",
+            ),
+            (
+                "Foxmail",
+                include_bytes!("clients/foxmail_forward.eml"),
+                "init",
+            ),
+        ] {
+            let (sender, normalized) = controller.verify_email(key.sign(email));
+            assert_eq!(sender, "alice@example.com", "{}", client);
+            assert_eq!(normalized, expected, "{}", client);
+            DkimController::parse_command(normalized);
+        }
+    }
+
+    #[test]
+    pub fn verify_email_reply() {
+        // The reply to a command sent by someone else is not a command
+        let key = TestKey::rsa("example.com", "test");
+        let email = key.sign(include_bytes!("clients/gmail_reply.eml"));
+        let (_, subject) = controller_with_key(&key).verify_email(email);
+        assert_eq!(subject, "Re: transfer mallory.near 100");
+        assert!(email_command::parse_command(&subject).is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "(dkim=fail, code=301)")]
+    pub fn receive_tampered_email() {
+        let key = TestKey::rsa("example.com", "test");
+        let email = EmailBuilder::new(&key)
+            .subject("transfer bob.near 1")
+            .tamper_header("Subject", "transfer mallory.near 100")
+            .build();
+        controller_with_key(&key).receive_email(email);
+    }
+
+    #[test]
+    #[should_panic(expected = "The email has more than one \"From\" header")]
+    pub fn receive_email_with_injected_from() {
+        let key = TestKey::rsa("example.com", "test");
+        let email = EmailBuilder::new(&key)
+            .from("alice@example.com")
+            .subject("transfer bob.near 1")
+            .inject_header("From", "mallory@example.com")
+            .build();
+        controller_with_key(&key).receive_email(email);
+    }
+
+    #[test]
+    #[should_panic(expected = "The email has more than one \"Subject\" header")]
+    pub fn receive_email_with_injected_subject() {
+        let key = TestKey::rsa("example.com", "test");
+        let email = EmailBuilder::new(&key)
+            .from("alice@example.com")
+            .subject("init")
+            .inject_header("Subject", "transfer mallory.near 100")
+            .build();
+        controller_with_key(&key).receive_email(email);
+    }
+
+    #[test]
+    #[should_panic(expected = "The email has more than one \"Subject\" header")]
+    pub fn receive_email_with_signed_duplicate_subject() {
+        let key = TestKey::rsa("example.com", "test");
+        let email = EmailBuilder::new(&key)
+            .from("alice@example.com")
+            .subject("init")
+            .header("Subject", "transfer mallory.near 100")
+            .build();
+        controller_with_key(&key).receive_email(email);
+    }
+
+    #[test]
+    #[should_panic(expected = "(dkim=temperror, code=201)")]
+    pub fn receive_email_from_unknown_key() {
+        let key = TestKey::rsa("example.com", "test");
+        let email = EmailBuilder::new(&key).subject("init").build();
+        DkimController::new().receive_email(email);
+    }
+
+    #[test]
+    #[should_panic(expected = "The email \"From\" header contains more than one author")]
+    pub fn receive_email_from_several_authors() {
+        let key = TestKey::rsa("example.com", "test");
+        let email = EmailBuilder::new(&key)
+            .from("alice@example.com, bob@example.com")
+            .subject("init")
+            .build();
+        controller_with_key(&key).receive_email(email);
+    }
 }
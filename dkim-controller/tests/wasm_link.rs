@@ -0,0 +1,35 @@
+//! The contract must link for wasm32-unknown-unknown as is: no custom
+//! `getrandom` backend and no wasm-bindgen imports, which the NEAR runtime
+//! doesn't provide.
+use std::path::Path;
+use std::process::Command;
+
+#[test]
+fn contract_links_for_wasm() {
+    let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+    // A separate target directory, the one of `cargo test` is locked
+    let target_dir = manifest_dir.join("target").join("wasm-link");
+
+    let output = Command::new(env!("CARGO"))
+        .current_dir(manifest_dir)
+        .args([
+            "build",
+            "--target",
+            "wasm32-unknown-unknown",
+            "--target-dir",
+        ])
+        .arg(&target_dir)
+        .output()
+        .expect("failed to run cargo");
+    assert!(
+        output.status.success(),
+        "wasm build failed:\n{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let wasm = std::fs::read(target_dir.join("wasm32-unknown-unknown/debug/dkim_controller.wasm"))
+        .unwrap();
+    let contains = |needle: &[u8]| wasm.windows(needle.len()).any(|w| w == needle);
+    assert!(!contains(b"__wbindgen_placeholder__"));
+    assert!(!contains(b"__getrandom_custom"));
+}
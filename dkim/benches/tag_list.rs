@@ -0,0 +1,166 @@
+//! Allocations and time of parsing a DKIM-Signature with `parse_tag_list`,
+//! compared to copying every tag into owned strings as the parser used to,
+//! and of the checks of the signature before its key is looked up
+//! (`validate_header`), compared to the checks on owned tags they replaced.
+//!
+//! `cargo bench --bench tag_list`
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::BTreeSet;
+use std::hint::black_box;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use near_dkim::{bench_validate_header, parse_tag_list, DKIMError};
+
+/// Counts the allocations of the process
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// A DKIM-Signature of Gmail, with its folded b=
+const SIGNATURE: &str = "v=1; a=rsa-sha256; c=relaxed/relaxed;\r\n        d=gmail.com; s=20230601; t=1697035652; x=1697640452; darn=near.org;\r\n        h=to:subject:message-id:date:from:mime-version:from:to:cc:subject\r\n         :date:message-id:reply-to;\r\n        bh=frcCV1k9oG9oKj3dpUqdJg1PxRT2RSN/XKdLCPjaYaY=;\r\n        b=gOczoYbWDFKWXbVoOJVUNAD6LFZkU/UbUhtLi9aSGFwDt1rtnVCA/r+kGNRRNCpEWe\r\n         /n3ju7ELPtM9K6N20yAmUpvjRIxHj5+UKsFyBIa1nE9/hHh7kgOJDt6lq0RtPo3gXBZ8\r\n         W/5Hv1BaE2KRmswSBm63DQ6qvXVJrkWOUrFgS8IsZ/+r7lsBWfG/ATm4/UkLvwmwsO+z\r\n         6Y2ykzfpVXPhxNmJINnMMW56L7/R4CnZTyMzgaHtjKxSKjWnLqSe5Wql0cJnbzq4aw0q\r\n         VPoMiNhIRGyimGxd6YeuJF9fT2qqgQVlo4bThSmS9uM3LR3W8VZeZGvtdvIzWn5bk4UN\r\n         cHPg==";
+
+const ITERATIONS: u32 = 10_000;
+
+fn measure<F: FnMut()>(name: &str, mut f: F) {
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        f();
+    }
+    let elapsed = start.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+    println!(
+        "{:<16} {:>6.1} allocations {:>10.2?} per signature",
+        name,
+        allocations as f64 / ITERATIONS as f64,
+        elapsed / ITERATIONS
+    );
+}
+
+/// A tag as the parser used to return it
+struct OwnedTag {
+    name: String,
+    value: String,
+    /// Copied for the signature itself, not read by the checks
+    #[allow(dead_code)]
+    raw_value: String,
+}
+
+/// `validate_header` before the tags were borrowed: owned tags, a set of
+/// their names to check the required ones, then a list of the tags where a
+/// repeated one replaced the first
+fn validate_header_owned(
+    value: &str,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Result<Vec<OwnedTag>, DKIMError> {
+    let (_, tags) =
+        parse_tag_list(value).map_err(|err| DKIMError::SignatureSyntaxError(err.to_string()))?;
+    let tags: Vec<OwnedTag> = tags
+        .iter()
+        .map(|tag| OwnedTag {
+            name: tag.name.to_owned(),
+            value: tag.value().into_owned(),
+            raw_value: tag.raw_value.to_owned(),
+        })
+        .collect();
+
+    let mut tag_names: BTreeSet<String> = BTreeSet::new();
+    for tag in &tags {
+        tag_names.insert(tag.name.clone());
+    }
+    for required in ["v", "a", "b", "bh", "d", "h", "s"] {
+        if !tag_names.contains(required) {
+            return Err(DKIMError::SignatureMissingRequiredTag(required));
+        }
+    }
+
+    let mut header: Vec<OwnedTag> = vec![];
+    for tag in tags {
+        match header.iter_mut().find(|t| t.name == tag.name) {
+            Some(existing) => *existing = tag,
+            None => header.push(tag),
+        }
+    }
+    let get_tag = |name: &str| {
+        header
+            .iter()
+            .find(|tag| tag.name == name)
+            .map(|tag| tag.value.clone())
+    };
+
+    if get_tag("v").unwrap() != "1" {
+        return Err(DKIMError::IncompatibleVersion);
+    }
+    if let Some(user) = get_tag("i") {
+        if !user.ends_with(&get_tag("d").unwrap()) {
+            return Err(DKIMError::DomainMismatch);
+        }
+    }
+    let headers: Vec<String> = get_tag("h")
+        .unwrap()
+        .split(':')
+        .map(|h| h.to_lowercase())
+        .collect();
+    if !headers.contains(&"from".to_string()) {
+        return Err(DKIMError::FromFieldNotSigned);
+    }
+    if let Some(query_method) = get_tag("q") {
+        if query_method != "dns/txt" {
+            return Err(DKIMError::UnsupportedQueryMethod);
+        }
+    }
+    if let Some(expiration) = get_tag("x") {
+        let expiration = expiration.parse::<i64>().unwrap_or_default();
+        if now.timestamp() > expiration + 15 * 60 {
+            return Err(DKIMError::SignatureExpired);
+        }
+    }
+    Ok(header)
+}
+
+fn main() {
+    measure("borrowed", || {
+        let (_, tags) = parse_tag_list(black_box(SIGNATURE)).unwrap();
+        // What verification reads: every value, b= is the only one folded
+        let length: usize = tags.iter().map(|tag| tag.value().len()).sum();
+        black_box(length);
+    });
+    measure("owned", || {
+        let (_, tags) = parse_tag_list(black_box(SIGNATURE)).unwrap();
+        let tags: Vec<(String, String, String)> = tags
+            .iter()
+            .map(|tag| {
+                (
+                    tag.name.to_owned(),
+                    tag.value().into_owned(),
+                    tag.raw_value.to_owned(),
+                )
+            })
+            .collect();
+        black_box(tags);
+    });
+
+    // Before the expiry (x=) of the signature
+    let now = chrono::TimeZone::timestamp_opt(&chrono::Utc, 1697035652, 0).unwrap();
+    measure("validate", || {
+        bench_validate_header(black_box(SIGNATURE), now).unwrap();
+    });
+    measure("validate (owned)", || {
+        black_box(validate_header_owned(black_box(SIGNATURE), now).unwrap());
+    });
+}
@@ -0,0 +1,430 @@
+//! Signing algorithms (the `a=` tag) and the registry resolving them by name.
+//!
+//! The verifier and the signer only go through [SignatureAlgorithm], so an
+//! experimental algorithm is supported by implementing it and registering it
+//! in an [AlgorithmRegistry].
+use alloc::sync::Arc;
+use core::fmt;
+
+use crate::prelude::*;
+#[cfg(feature = "sign")]
+use crate::DkimPrivateKey;
+use crate::{DKIMError, HashAlgo};
+
+/// A signing algorithm: its digest, its key type and how signatures are
+/// verified and produced. Registries and signers share algorithms across
+/// threads, hence `Send + Sync`.
+pub trait SignatureAlgorithm: Send + Sync {
+    /// Name in the `a=` tag
+    fn name(&self) -> &str;
+
+    /// Key type (the `k=` tag of the key record)
+    fn key_type(&self) -> &str;
+
+    /// Hashes the canonicalized body or headers
+    fn digest(&self, data: &[u8]) -> Vec<u8>;
+
+    /// Verifies the signature of the digest of the canonicalized headers.
+    /// `public_key` is the decoded `p=` tag of the key record.
+    fn verify(&self, public_key: &[u8], digest: &[u8], signature: &[u8])
+        -> Result<bool, DKIMError>;
+
+    /// Decodes the `p=` tag of a key record once, to verify several
+    /// signatures with it. By default the key is kept as is.
+    fn parse_public_key(&self, public_key: &[u8]) -> Result<PublicKey, DKIMError> {
+        Ok(PublicKey::Der(public_key.to_vec()))
+    }
+
+    /// Same as [SignatureAlgorithm::verify] with a key from
+    /// [SignatureAlgorithm::parse_public_key]
+    fn verify_parsed(
+        &self,
+        public_key: &PublicKey,
+        digest: &[u8],
+        signature: &[u8],
+    ) -> Result<bool, DKIMError> {
+        match public_key {
+            PublicKey::Der(public_key) => self.verify(public_key, digest, signature),
+            #[allow(unreachable_patterns)]
+            _ => Err(DKIMError::InappropriateKeyAlgorithm),
+        }
+    }
+
+    /// Signs the digest of the canonicalized headers with an in-memory key.
+    /// Algorithms whose keys don't fit in a [DkimPrivateKey] sign through a
+    /// [crate::SigningBackend] instead.
+    #[cfg(feature = "sign")]
+    fn sign(&self, private_key: &DkimPrivateKey, digest: &[u8]) -> Result<Vec<u8>, DKIMError> {
+        let _ = (private_key, digest);
+        Err(DKIMError::FailedToSign(format!(
+            "{} has no in-memory keys",
+            self.name()
+        )))
+    }
+}
+
+/// A public key decoded by [SignatureAlgorithm::parse_public_key]
+#[derive(Debug, Clone, PartialEq)]
+pub enum PublicKey {
+    /// The `p=` tag, for the algorithms that don't decode their keys ahead
+    Der(Vec<u8>),
+    #[cfg(feature = "rsa")]
+    Rsa(rsa::RsaPublicKey),
+}
+
+#[cfg(feature = "sha1")]
+fn sha1(data: &[u8]) -> Vec<u8> {
+    use sha1::{Digest, Sha1};
+    Sha1::digest(data).to_vec()
+}
+
+fn sha256(data: &[u8]) -> Vec<u8> {
+    use sha2::{Digest, Sha256};
+    Sha256::digest(data).to_vec()
+}
+
+fn invalid_public_key<E: fmt::Display>(err: E) -> DKIMError {
+    DKIMError::KeyUnavailable(format!("failed to parse public key: {}", err))
+}
+
+/// Decodes an RSA key: a SubjectPublicKeyInfo or, as some records have it, a
+/// PKCS#1 RSAPublicKey
+#[cfg(feature = "rsa")]
+fn rsa_parse_public_key(public_key: &[u8]) -> Result<PublicKey, DKIMError> {
+    use rsa::{pkcs1, pkcs8};
+    pkcs8::DecodePublicKey::from_public_key_der(public_key)
+        .or_else(|_| pkcs1::DecodeRsaPublicKey::from_pkcs1_der(public_key))
+        .map(PublicKey::Rsa)
+        .map_err(invalid_public_key)
+}
+
+/// Verifies a PKCS#1 v1.5 signature
+#[cfg(feature = "rsa")]
+fn rsa_verify(
+    hash: rsa::hash::Hash,
+    public_key: &PublicKey,
+    digest: &[u8],
+    signature: &[u8],
+) -> Result<bool, DKIMError> {
+    use rsa::PublicKey as _;
+    let public_key = match public_key {
+        PublicKey::Rsa(public_key) => public_key,
+        PublicKey::Der(public_key) => {
+            return rsa_verify(hash, &rsa_parse_public_key(public_key)?, digest, signature)
+        }
+    };
+    Ok(public_key
+        .verify(
+            rsa::PaddingScheme::PKCS1v15Sign { hash: Some(hash) },
+            digest,
+            signature,
+        )
+        .is_ok())
+}
+
+#[cfg(all(feature = "rsa", feature = "sign"))]
+fn rsa_sign(
+    hash: rsa::hash::Hash,
+    private_key: &DkimPrivateKey,
+    digest: &[u8],
+) -> Result<Vec<u8>, DKIMError> {
+    #[allow(irrefutable_let_patterns)]
+    let DkimPrivateKey::Rsa(private_key) = private_key
+    else {
+        return Err(DKIMError::FailedToSign("expected an RSA key".to_owned()));
+    };
+    private_key
+        .sign(
+            rsa::PaddingScheme::PKCS1v15Sign { hash: Some(hash) },
+            digest,
+        )
+        .map_err(|err| DKIMError::FailedToSign(err.to_string()))
+}
+
+/// `rsa-sha1`, historic <https://datatracker.ietf.org/doc/html/rfc8301>
+#[cfg(all(feature = "rsa", feature = "sha1"))]
+#[derive(Debug, Clone, Copy)]
+pub struct RsaSha1;
+
+#[cfg(all(feature = "rsa", feature = "sha1"))]
+impl SignatureAlgorithm for RsaSha1 {
+    fn name(&self) -> &str {
+        "rsa-sha1"
+    }
+
+    fn key_type(&self) -> &str {
+        "rsa"
+    }
+
+    fn digest(&self, data: &[u8]) -> Vec<u8> {
+        sha1(data)
+    }
+
+    fn verify(
+        &self,
+        public_key: &[u8],
+        digest: &[u8],
+        signature: &[u8],
+    ) -> Result<bool, DKIMError> {
+        rsa_verify(
+            rsa::hash::Hash::SHA1,
+            &rsa_parse_public_key(public_key)?,
+            digest,
+            signature,
+        )
+    }
+
+    fn parse_public_key(&self, public_key: &[u8]) -> Result<PublicKey, DKIMError> {
+        rsa_parse_public_key(public_key)
+    }
+
+    fn verify_parsed(
+        &self,
+        public_key: &PublicKey,
+        digest: &[u8],
+        signature: &[u8],
+    ) -> Result<bool, DKIMError> {
+        rsa_verify(rsa::hash::Hash::SHA1, public_key, digest, signature)
+    }
+
+    #[cfg(feature = "sign")]
+    fn sign(&self, private_key: &DkimPrivateKey, digest: &[u8]) -> Result<Vec<u8>, DKIMError> {
+        rsa_sign(rsa::hash::Hash::SHA1, private_key, digest)
+    }
+}
+
+/// `rsa-sha256` <https://datatracker.ietf.org/doc/html/rfc6376#section-3.3.2>
+#[cfg(feature = "rsa")]
+#[derive(Debug, Clone, Copy)]
+pub struct RsaSha256;
+
+#[cfg(feature = "rsa")]
+impl SignatureAlgorithm for RsaSha256 {
+    fn name(&self) -> &str {
+        "rsa-sha256"
+    }
+
+    fn key_type(&self) -> &str {
+        "rsa"
+    }
+
+    fn digest(&self, data: &[u8]) -> Vec<u8> {
+        sha256(data)
+    }
+
+    fn verify(
+        &self,
+        public_key: &[u8],
+        digest: &[u8],
+        signature: &[u8],
+    ) -> Result<bool, DKIMError> {
+        rsa_verify(
+            rsa::hash::Hash::SHA2_256,
+            &rsa_parse_public_key(public_key)?,
+            digest,
+            signature,
+        )
+    }
+
+    fn parse_public_key(&self, public_key: &[u8]) -> Result<PublicKey, DKIMError> {
+        rsa_parse_public_key(public_key)
+    }
+
+    fn verify_parsed(
+        &self,
+        public_key: &PublicKey,
+        digest: &[u8],
+        signature: &[u8],
+    ) -> Result<bool, DKIMError> {
+        rsa_verify(rsa::hash::Hash::SHA2_256, public_key, digest, signature)
+    }
+
+    #[cfg(feature = "sign")]
+    fn sign(&self, private_key: &DkimPrivateKey, digest: &[u8]) -> Result<Vec<u8>, DKIMError> {
+        rsa_sign(rsa::hash::Hash::SHA2_256, private_key, digest)
+    }
+}
+
+/// `ed25519-sha256` <https://datatracker.ietf.org/doc/html/rfc8463>. The
+/// SHA-256 digest is signed as the Ed25519 message.
+#[cfg(feature = "ed25519")]
+#[derive(Debug, Clone, Copy)]
+pub struct Ed25519Sha256;
+
+#[cfg(feature = "ed25519")]
+impl SignatureAlgorithm for Ed25519Sha256 {
+    fn name(&self) -> &str {
+        "ed25519-sha256"
+    }
+
+    fn key_type(&self) -> &str {
+        "ed25519"
+    }
+
+    fn digest(&self, data: &[u8]) -> Vec<u8> {
+        sha256(data)
+    }
+
+    fn verify(
+        &self,
+        public_key: &[u8],
+        digest: &[u8],
+        signature: &[u8],
+    ) -> Result<bool, DKIMError> {
+        let public_key =
+            ed25519_dalek::PublicKey::from_bytes(public_key).map_err(invalid_public_key)?;
+        let signature = ed25519_dalek::Signature::from_bytes(signature)
+            .map_err(|err| DKIMError::SignatureSyntaxError(err.to_string()))?;
+        Ok(public_key.verify_strict(digest, &signature).is_ok())
+    }
+
+    #[cfg(feature = "sign")]
+    fn sign(&self, private_key: &DkimPrivateKey, digest: &[u8]) -> Result<Vec<u8>, DKIMError> {
+        #[allow(irrefutable_let_patterns)]
+        let DkimPrivateKey::Ed25519(keypair) = private_key
+        else {
+            return Err(DKIMError::FailedToSign(
+                "expected an Ed25519 key".to_owned(),
+            ));
+        };
+        let expanded: ed25519_dalek::ExpandedSecretKey = (&keypair.secret).into();
+        Ok(expanded.sign(digest, &keypair.public).to_bytes().to_vec())
+    }
+}
+
+/// The algorithms known to a verifier or a signer, by name. The default
+/// registry holds the algorithms enabled by the features of the crate.
+#[derive(Clone)]
+pub struct AlgorithmRegistry {
+    algorithms: Vec<Arc<dyn SignatureAlgorithm>>,
+}
+
+impl AlgorithmRegistry {
+    /// A registry without any algorithm
+    pub fn empty() -> Self {
+        AlgorithmRegistry { algorithms: vec![] }
+    }
+
+    /// Registers an algorithm, replacing the algorithm with the same name
+    pub fn register<A: SignatureAlgorithm + 'static>(&mut self, algorithm: A) {
+        self.algorithms.retain(|a| a.name() != algorithm.name());
+        self.algorithms.push(Arc::new(algorithm));
+    }
+
+    /// The algorithm of an `a=` value
+    pub fn get(&self, name: &str) -> Result<Arc<dyn SignatureAlgorithm>, DKIMError> {
+        self.algorithms
+            .iter()
+            .find(|algorithm| algorithm.name() == name)
+            .cloned()
+            .ok_or_else(|| DKIMError::UnsupportedHashAlgorithm(name.to_owned()))
+    }
+
+    /// Parses an `a=` value, which must be registered
+    pub fn parse(&self, name: &str) -> Result<HashAlgo, DKIMError> {
+        self.get(name)?;
+        Ok(HashAlgo::from_name(name))
+    }
+
+    /// Names of the registered algorithms, in registration order
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.algorithms.iter().map(|algorithm| algorithm.name())
+    }
+}
+
+impl Default for AlgorithmRegistry {
+    fn default() -> Self {
+        #[allow(unused_mut)]
+        let mut registry = Self::empty();
+        #[cfg(all(feature = "rsa", feature = "sha1"))]
+        registry.register(RsaSha1);
+        #[cfg(feature = "rsa")]
+        registry.register(RsaSha256);
+        #[cfg(feature = "ed25519")]
+        registry.register(Ed25519Sha256);
+        registry
+    }
+}
+
+impl fmt::Debug for AlgorithmRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.names()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An algorithm signing with the digest itself, to test the registry
+    struct Identity;
+
+    impl SignatureAlgorithm for Identity {
+        fn name(&self) -> &str {
+            "x-identity"
+        }
+
+        fn key_type(&self) -> &str {
+            "x-identity"
+        }
+
+        fn digest(&self, data: &[u8]) -> Vec<u8> {
+            data.to_vec()
+        }
+
+        fn verify(&self, _: &[u8], digest: &[u8], signature: &[u8]) -> Result<bool, DKIMError> {
+            Ok(digest == signature)
+        }
+    }
+
+    #[test]
+    fn test_registry() {
+        let mut registry = AlgorithmRegistry::default();
+        assert_eq!(
+            registry.names().collect::<Vec<_>>(),
+            ["rsa-sha1", "rsa-sha256", "ed25519-sha256"]
+        );
+        assert_eq!(registry.parse("rsa-sha256").unwrap(), HashAlgo::RsaSha256);
+        assert_eq!(
+            registry.parse("x-identity").unwrap_err(),
+            DKIMError::UnsupportedHashAlgorithm("x-identity".to_owned())
+        );
+
+        registry.register(Identity);
+        assert_eq!(
+            registry.parse("x-identity").unwrap(),
+            HashAlgo::Other("x-identity".to_owned())
+        );
+        let algorithm = registry.get("x-identity").unwrap();
+        assert!(algorithm.verify(b"", b"abc", b"abc").unwrap());
+        assert_eq!(
+            format!("{:?}", registry),
+            r#"["rsa-sha1", "rsa-sha256", "ed25519-sha256", "x-identity"]"#
+        );
+
+        let registry = AlgorithmRegistry::empty();
+        assert!(registry.get("rsa-sha256").is_err());
+    }
+
+    #[test]
+    fn test_sign_verify() {
+        let pem = std::fs::read_to_string("./test/keys/2022.private").unwrap();
+        let private_key = crate::keys::private_key_from_pem(&pem).unwrap();
+        let public_key = crate::SigningBackend::public_key(&private_key).unwrap();
+
+        for algorithm in [&RsaSha1 as &dyn SignatureAlgorithm, &RsaSha256] {
+            let digest = algorithm.digest(b"headers");
+            let signature = algorithm.sign(&private_key, &digest).unwrap();
+            assert!(algorithm.verify(&public_key, &digest, &signature).unwrap());
+            assert!(!algorithm.verify(&public_key, &digest, b"other").unwrap());
+        }
+        assert_eq!(
+            Ed25519Sha256.sign(&private_key, b"digest").unwrap_err(),
+            DKIMError::FailedToSign("expected an Ed25519 key".to_owned())
+        );
+        assert_eq!(
+            Identity.sign(&private_key, b"digest").unwrap_err(),
+            DKIMError::FailedToSign("x-identity has no in-memory keys".to_owned())
+        );
+    }
+}
@@ -0,0 +1,173 @@
+//! Signing backends: where the private key lives and how the signature is
+//! produced. The key can stay in an HSM or a KMS by implementing
+//! [SigningBackend]; [DkimPrivateKey] holds it in memory.
+#[cfg(any(test, feature = "testing"))]
+use alloc::collections::VecDeque;
+#[cfg(any(test, feature = "testing"))]
+use std::sync::Mutex;
+
+#[cfg(feature = "rsa")]
+use rsa::pkcs8::EncodePublicKey;
+
+use crate::algorithm::{self, SignatureAlgorithm};
+use crate::prelude::*;
+use crate::{DKIMError, DkimPrivateKey, HashAlgo};
+
+/// Produces the signature of a DKIM-Signature header. A [crate::Signer] owns
+/// its backend and can be shared across threads, hence `Send + Sync`.
+pub trait SigningBackend: Send + Sync {
+    /// Signing algorithm (the `a=` tag)
+    fn algorithm(&self) -> HashAlgo;
+
+    /// Public key, as published in the `p=` tag of the DNS record: the DER
+    /// SubjectPublicKeyInfo for RSA or the raw 32 bytes for Ed25519
+    fn public_key(&self) -> Result<Vec<u8>, DKIMError>;
+
+    /// Signs the digest of the canonicalized headers. RSA signs it with
+    /// PKCS#1 v1.5, Ed25519 signs the digest itself as the message.
+    fn sign(&self, digest: &[u8]) -> Result<Vec<u8>, DKIMError>;
+}
+
+impl<T: SigningBackend + ?Sized> SigningBackend for &T {
+    fn algorithm(&self) -> HashAlgo {
+        (**self).algorithm()
+    }
+
+    fn public_key(&self) -> Result<Vec<u8>, DKIMError> {
+        (**self).public_key()
+    }
+
+    fn sign(&self, digest: &[u8]) -> Result<Vec<u8>, DKIMError> {
+        (**self).sign(digest)
+    }
+}
+
+impl SigningBackend for DkimPrivateKey {
+    fn algorithm(&self) -> HashAlgo {
+        match self {
+            #[cfg(feature = "rsa")]
+            DkimPrivateKey::Rsa(_) => HashAlgo::RsaSha256,
+            #[cfg(feature = "ed25519")]
+            DkimPrivateKey::Ed25519(_) => HashAlgo::Ed25519Sha256,
+        }
+    }
+
+    fn public_key(&self) -> Result<Vec<u8>, DKIMError> {
+        match self {
+            #[cfg(feature = "rsa")]
+            DkimPrivateKey::Rsa(private_key) => Ok(private_key
+                .to_public_key()
+                .to_public_key_der()
+                .map_err(|err| DKIMError::FailedToSign(err.to_string()))?
+                .as_ref()
+                .to_vec()),
+            #[cfg(feature = "ed25519")]
+            DkimPrivateKey::Ed25519(keypair) => Ok(keypair.public.to_bytes().to_vec()),
+        }
+    }
+
+    fn sign(&self, digest: &[u8]) -> Result<Vec<u8>, DKIMError> {
+        match self {
+            #[cfg(feature = "rsa")]
+            DkimPrivateKey::Rsa(_) => algorithm::RsaSha256.sign(self, digest),
+            #[cfg(feature = "ed25519")]
+            DkimPrivateKey::Ed25519(_) => algorithm::Ed25519Sha256.sign(self, digest),
+        }
+    }
+}
+
+/// Backend returning scripted signatures, for tests. Each call to `sign`
+/// returns the next scripted response and records the digest it was given.
+#[cfg(any(test, feature = "testing"))]
+#[derive(Debug)]
+pub struct MockSigningBackend {
+    algorithm: HashAlgo,
+    public_key: Vec<u8>,
+    responses: Mutex<VecDeque<Result<Vec<u8>, DKIMError>>>,
+    digests: Mutex<Vec<Vec<u8>>>,
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl MockSigningBackend {
+    /// New backend without any scripted response
+    pub fn new(algorithm: HashAlgo, public_key: Vec<u8>) -> Self {
+        MockSigningBackend {
+            algorithm,
+            public_key,
+            responses: Mutex::new(VecDeque::new()),
+            digests: Mutex::new(vec![]),
+        }
+    }
+
+    /// Script the result of the next call to `sign`
+    pub fn push_response(&self, response: Result<Vec<u8>, DKIMError>) {
+        self.responses.lock().unwrap().push_back(response);
+    }
+
+    /// Digests passed to `sign`, in order
+    pub fn digests(&self) -> Vec<Vec<u8>> {
+        self.digests.lock().unwrap().clone()
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl SigningBackend for MockSigningBackend {
+    fn algorithm(&self) -> HashAlgo {
+        self.algorithm.clone()
+    }
+
+    fn public_key(&self) -> Result<Vec<u8>, DKIMError> {
+        Ok(self.public_key.clone())
+    }
+
+    fn sign(&self, digest: &[u8]) -> Result<Vec<u8>, DKIMError> {
+        self.digests.lock().unwrap().push(digest.to_vec());
+        self.responses
+            .lock()
+            .unwrap()
+            .pop_front()
+            .unwrap_or_else(|| Err(DKIMError::FailedToSign("no scripted response".to_owned())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rsa::pkcs1::DecodeRsaPrivateKey;
+    use std::path::Path;
+
+    #[test]
+    fn test_private_key_public_key() {
+        let private_key =
+            rsa::RsaPrivateKey::read_pkcs1_pem_file(Path::new("./test/keys/2022.private")).unwrap();
+        let backend = DkimPrivateKey::Rsa(private_key);
+        assert_eq!(backend.algorithm(), HashAlgo::RsaSha256);
+
+        // the `p=` of test/keys/2022.txt
+        let record = std::fs::read_to_string("./test/keys/2022.txt").unwrap();
+        let record: String = record.split('"').skip(1).step_by(2).collect();
+        let public_key = base64::encode(backend.public_key().unwrap());
+        assert!(record.ends_with(&format!("p={}", public_key)));
+    }
+
+    #[test]
+    fn test_mock_signing_backend() {
+        let backend = MockSigningBackend::new(HashAlgo::Ed25519Sha256, vec![1; 32]);
+        backend.push_response(Ok(vec![1, 2, 3]));
+        backend.push_response(Err(DKIMError::FailedToSign("kms unavailable".to_owned())));
+
+        assert_eq!(backend.sign(b"first").unwrap(), vec![1, 2, 3]);
+        assert_eq!(
+            backend.sign(b"second").unwrap_err(),
+            DKIMError::FailedToSign("kms unavailable".to_owned())
+        );
+        assert_eq!(
+            backend.sign(b"third").unwrap_err(),
+            DKIMError::FailedToSign("no scripted response".to_owned())
+        );
+        assert_eq!(
+            backend.digests(),
+            vec![b"first".to_vec(), b"second".to_vec(), b"third".to_vec()]
+        );
+    }
+}
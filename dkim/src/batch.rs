@@ -0,0 +1,243 @@
+//! Verification of many emails at once, for example a relayer submitting what
+//! it fetched from a mailbox.
+//!
+//! The key of a (domain, selector) is looked up and decoded once for the whole
+//! batch. If the resolver answers depending on the timestamp (`t=`) of the
+//! signature, like a [dns::HistoricalLookup], it is looked up once per
+//! timestamp instead. With the `rayon` feature the emails are verified in
+//! parallel.
+//!
+//! Failed lookups are shared too, temporary errors included: the batch is
+//! verified against one view of DNS, and the emails whose key lookup failed
+//! temporarily are reported as `temperror` to be submitted again in a later
+//! batch.
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use crate::message::Message;
+use crate::{
+    dns, verify_signatures_inner, AlgorithmRegistry, AuthenticationResult, DKIMError, DKIMResult,
+    KeySource, PublicKeys, SignatureAlgorithm, SignatureResult,
+};
+
+type CachedKey = Result<PublicKeys, DKIMError>;
+
+/// (domain, selector, key type, timestamp). The timestamp is `None` if the
+/// resolver ignores it.
+type KeyId = (
+    String,
+    String,
+    String,
+    Option<chrono::DateTime<chrono::Utc>>,
+);
+
+/// The keys of a batch, including the failed lookups
+#[derive(Default)]
+struct KeyCache {
+    keys: Mutex<BTreeMap<KeyId, CachedKey>>,
+}
+
+struct CachedKeys<'a, T: ?Sized> {
+    resolver: &'a T,
+    cache: &'a KeyCache,
+}
+
+impl<T: dns::TimedLookup + ?Sized> KeySource for CachedKeys<'_, T> {
+    fn public_keys(
+        &self,
+        algorithm: &dyn SignatureAlgorithm,
+        domain: String,
+        selector: String,
+        timestamp: Option<chrono::DateTime<chrono::Utc>>,
+    ) -> CachedKey {
+        let id = (
+            domain.to_lowercase(),
+            selector.to_lowercase(),
+            algorithm.key_type().to_owned(),
+            timestamp.filter(|_| self.resolver.depends_on_timestamp()),
+        );
+        if let Some(key) = self.cache.lock().get(&id) {
+            return key.clone();
+        }
+        // Not locked during the lookup: two emails may look up the same key
+        // concurrently, the first answer is kept
+        let key = self
+            .resolver
+            .public_keys(algorithm, domain, selector, timestamp);
+        self.cache.lock().entry(id).or_insert(key).clone()
+    }
+}
+
+impl KeyCache {
+    fn lock(&self) -> std::sync::MutexGuard<'_, BTreeMap<KeyId, CachedKey>> {
+        self.keys.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+/// The verification of an email of a batch
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BatchReport {
+    /// `pass` if one of the signatures passed, like
+    /// [crate::verify_raw_email_with_resolver]
+    pub result: DKIMResult,
+    /// Result of each DKIM-Signature, in the order of the headers
+    pub signatures: Vec<SignatureResult>,
+}
+
+fn verify_one<K: KeySource + ?Sized>(
+    raw_email: &[u8],
+    keys: &K,
+    now: chrono::DateTime<chrono::Utc>,
+) -> BatchReport {
+    let email = match Message::parse(raw_email) {
+        Ok(email) => email,
+        Err(err) => {
+            return BatchReport {
+                result: DKIMResult::fail(err),
+                signatures: vec![],
+            }
+        }
+    };
+    let signatures =
+        verify_signatures_inner(&email, keys, &AlgorithmRegistry::default(), now, None);
+    let result = signatures
+        .iter()
+        .find(|signature| signature.result.result() == AuthenticationResult::Pass)
+        .or_else(|| signatures.last())
+        .map_or_else(DKIMResult::neutral, |signature| signature.result.clone());
+    BatchReport { result, signatures }
+}
+
+/// Verifies the raw bytes of many emails, sharing the keys between them. The
+/// reports are in the order of `emails`.
+pub fn verify_batch<T, E>(
+    emails: &[E],
+    resolver: &T,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Vec<BatchReport>
+where
+    T: dns::TimedLookup + Sync,
+    E: AsRef<[u8]> + Sync,
+{
+    let cache = KeyCache::default();
+    let keys = CachedKeys {
+        resolver,
+        cache: &cache,
+    };
+
+    #[cfg(feature = "rayon")]
+    {
+        use rayon::prelude::*;
+        emails
+            .par_iter()
+            .map(|email| verify_one(email.as_ref(), &keys, now))
+            .collect()
+    }
+    #[cfg(not(feature = "rayon"))]
+    {
+        emails
+            .iter()
+            .map(|email| verify_one(email.as_ref(), &keys, now))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::dns::Lookup;
+    use crate::testing::{EmailBuilder, TestKey, TestResolver};
+
+    /// Counts the lookups of the inner resolver
+    struct CountingResolver {
+        inner: TestResolver,
+        lookups: AtomicUsize,
+    }
+
+    impl Lookup for CountingResolver {
+        fn lookup_txt(&self, name: &str) -> Result<Vec<String>, DKIMError> {
+            self.lookups.fetch_add(1, Ordering::Relaxed);
+            self.inner.lookup_txt(name)
+        }
+    }
+
+    #[test]
+    fn test_verify_batch() {
+        let key = TestKey::rsa("example.com", "2022");
+        let other = TestKey::rsa("example.org", "2022");
+        let resolver = CountingResolver {
+            inner: key.resolver(),
+            lookups: AtomicUsize::new(0),
+        };
+
+        let emails = vec![
+            EmailBuilder::new(&key).body("first").build(),
+            EmailBuilder::new(&key).body("second").build(),
+            EmailBuilder::new(&key).tamper_body("tampered").build(),
+            EmailBuilder::new(&other).build(),
+            EmailBuilder::new(&other).build(),
+            b" From: malformed\r\n\r\n".to_vec(),
+            EmailBuilder::new(&key).build_unsigned(),
+        ];
+        let reports = verify_batch(&emails, &resolver, chrono::Utc::now());
+
+        let results: Vec<String> = reports
+            .iter()
+            .map(|report| report.result.with_detail())
+            .collect();
+        assert_eq!(
+            results,
+            [
+                "pass",
+                "pass",
+                "fail (body hash did not verify)",
+                "permerror (no key for signature)",
+                "permerror (no key for signature)",
+                "permerror (malformed email body)",
+                "neutral",
+            ]
+        );
+        assert_eq!(reports[0].signatures.len(), 1);
+        assert!(reports[5].signatures.is_empty());
+        // One lookup per (domain, selector), even the failed one
+        assert_eq!(resolver.lookups.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn test_verify_batch_rotated_key() {
+        use crate::dns::{HistoricalLookup, HistoricalRecord};
+        use chrono::TimeZone;
+
+        let key = TestKey::rsa("example.com", "2022");
+        let rotation = chrono::Utc.ymd(2022, 1, 1).and_hms(0, 0, 0);
+        let mut resolver = HistoricalLookup::new(
+            TestResolver::default().with_record(&key.dns_name(), "v=DKIM1; k=rsa; p="),
+        );
+        resolver.add_record(
+            &key.dns_name(),
+            HistoricalRecord {
+                record: key.dns_record(),
+                not_before: None,
+                not_after: Some(rotation),
+            },
+        );
+
+        // The key of the first email is revoked, it must not be reused for the
+        // second one, signed before the rotation
+        let emails = vec![
+            EmailBuilder::new(&key).build(),
+            EmailBuilder::new(&key)
+                .time(rotation - chrono::Duration::days(1))
+                .build(),
+        ];
+        let reports = verify_batch(&emails, &resolver, chrono::Utc::now());
+        assert!(matches!(
+            reports[0].result.error(),
+            Some(DKIMError::KeyUnavailable(_))
+        ));
+        assert_eq!(reports[1].result.with_detail(), "pass");
+    }
+}
@@ -0,0 +1,437 @@
+//! Command-line tool to debug DKIM signatures: verify, sign, generate keys,
+//! inspect signatures and dump the canonicalized data that is hashed.
+use std::error::Error;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use mailparse::MailHeaderMap;
+use near_dkim::keys::{self, DkimRecord};
+use near_dkim::{canonicalization, dns::Lookup, DkimSignature, SignerBuilder};
+
+mod zone;
+
+use zone::StubResolver;
+
+#[derive(Parser)]
+#[command(name = "dkim", about = "Verify, sign and debug DKIM signatures")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Verify the DKIM signatures of an email
+    Verify {
+        /// Email file, or `-` for stdin
+        email: PathBuf,
+        #[command(flatten)]
+        resolver: ResolverArgs,
+        /// Print the canonicalized inputs of each signature
+        #[arg(long)]
+        trace: bool,
+    },
+    /// Sign an email and print it with the DKIM-Signature header
+    Sign {
+        /// Email file, or `-` for stdin
+        email: PathBuf,
+        /// Private key, PKCS#1 or PKCS#8 PEM
+        #[arg(long)]
+        key: PathBuf,
+        /// Signing domain (d=)
+        #[arg(long)]
+        domain: String,
+        /// Selector (s=)
+        #[arg(long)]
+        selector: String,
+        /// Headers to sign (h=)
+        #[arg(
+            long,
+            value_delimiter = ',',
+            default_value = "From,To,Subject,Date,Message-ID"
+        )]
+        headers: Vec<String>,
+        /// Header and body canonicalization (c=)
+        #[arg(long, default_value = "relaxed/relaxed")]
+        canonicalization: String,
+        /// Validity of the signature in seconds (x=)
+        #[arg(long)]
+        expiry: Option<i64>,
+    },
+    /// Generate a key pair and its DNS record
+    Keygen {
+        #[arg(long, value_enum, default_value_t = Algorithm::Rsa)]
+        algorithm: Algorithm,
+        /// Size of RSA keys
+        #[arg(long, default_value_t = 2048)]
+        bits: usize,
+        /// Selector of the DNS record
+        #[arg(long)]
+        selector: String,
+        /// Serialize RSA keys as PKCS#8 instead of PKCS#1
+        #[arg(long)]
+        pkcs8: bool,
+        /// Write `<selector>.private` and `<selector>.txt` in this directory
+        /// instead of printing them
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+    /// Print the parsed DKIM signatures of an email and their key records
+    Inspect {
+        /// Email file, or `-` for stdin
+        email: PathBuf,
+        #[command(flatten)]
+        resolver: ResolverArgs,
+    },
+    /// Dump the canonicalized headers and body hashed for each signature
+    Canonicalize {
+        /// Email file, or `-` for stdin
+        email: PathBuf,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Algorithm {
+    Rsa,
+    Ed25519,
+}
+
+#[derive(Args)]
+struct ResolverArgs {
+    /// BIND zone file with the key records
+    #[arg(long)]
+    zone: Option<PathBuf>,
+    /// Origin of the relative names of the zone file
+    #[arg(long)]
+    origin: Option<String>,
+    /// Key record given as `<name>=<txt>`, for example
+    /// `2022._domainkey.example.com=v=DKIM1; p=...`
+    #[arg(long = "record", value_name = "NAME=TXT")]
+    records: Vec<String>,
+}
+
+impl ResolverArgs {
+    fn is_empty(&self) -> bool {
+        self.zone.is_none() && self.records.is_empty()
+    }
+
+    fn resolver(&self) -> Result<StubResolver, Box<dyn Error>> {
+        let mut resolver = StubResolver::default();
+        if let Some(path) = &self.zone {
+            let zone = std::fs::read_to_string(path)?;
+            resolver.load_zone(&zone, self.origin.as_deref())?;
+        }
+        for record in &self.records {
+            let (name, value) = record
+                .split_once('=')
+                .ok_or_else(|| format!("invalid record: {}", record))?;
+            resolver.insert(name, value.to_owned());
+        }
+        Ok(resolver)
+    }
+}
+
+fn read_email(path: &Path) -> Result<Vec<u8>, Box<dyn Error>> {
+    if path == Path::new("-") {
+        let mut email = vec![];
+        std::io::stdin().read_to_end(&mut email)?;
+        Ok(email)
+    } else {
+        Ok(std::fs::read(path)?)
+    }
+}
+
+fn parse_canonicalization(
+    value: &str,
+) -> Result<(canonicalization::Type, canonicalization::Type), Box<dyn Error>> {
+    let parse = |value: &str| match value {
+        "simple" => Ok(canonicalization::Type::Simple),
+        "relaxed" => Ok(canonicalization::Type::Relaxed),
+        _ => Err(format!("unsupported canonicalization: {}", value)),
+    };
+    let (header, body) = value.split_once('/').unwrap_or((value, "simple"));
+    Ok((parse(header)?, parse(body)?))
+}
+
+/// One line summary of a signature
+fn describe(signature: &Option<DkimSignature>) -> String {
+    match signature {
+        Some(signature) => format!(
+            "d={} s={} a={}",
+            signature.signing_domain, signature.selector, signature.algorithm
+        ),
+        None => "unparsable signature".to_owned(),
+    }
+}
+
+fn verify(email: &[u8], resolver: &ResolverArgs, trace: bool) -> Result<ExitCode, Box<dyn Error>> {
+    let email = mailparse::parse_mail(email)?;
+    let resolver = resolver.resolver()?;
+
+    let (results, traces) = if trace {
+        near_dkim::verify_email_signatures_with_trace(&email, &resolver)?
+    } else {
+        (
+            near_dkim::verify_email_signatures_with_resolver(&email, &resolver)?,
+            vec![],
+        )
+    };
+    if results.is_empty() {
+        println!("neutral (no signature)");
+    }
+    for (i, result) in results.iter().enumerate() {
+        println!(
+            "#{} {}: {}",
+            i,
+            describe(&result.signature),
+            result.result.with_detail()
+        );
+    }
+    if email
+        .headers
+        .get_first_header(near_dkim::domainkeys::HEADER)
+        .is_some()
+    {
+        let result = near_dkim::domainkeys::verify_email_with_resolver(&email, &resolver);
+        println!("domainkeys: {}", result.with_detail());
+    }
+
+    for trace in traces {
+        println!();
+        print!("{}", trace);
+    }
+
+    let passed = results
+        .iter()
+        .any(|result| result.result.result() == near_dkim::AuthenticationResult::Pass);
+    Ok(if passed {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
+}
+
+fn sign(
+    email: &[u8],
+    key: &Path,
+    domain: &str,
+    selector: &str,
+    headers: &[String],
+    canonicalization: &str,
+    expiry: Option<i64>,
+) -> Result<(), Box<dyn Error>> {
+    let email = mailparse::parse_mail(email)?;
+    let private_key = keys::private_key_from_pem(&std::fs::read_to_string(key)?)?;
+    let headers: Vec<&str> = headers.iter().map(|h| h.as_str()).collect();
+    let (header_canonicalization, body_canonicalization) =
+        parse_canonicalization(canonicalization)?;
+
+    let mut builder = SignerBuilder::new()
+        .with_signed_headers(&headers)?
+        .with_private_key(private_key)
+        .with_selector(selector)
+        .with_signing_domain(domain)
+        .with_header_canonicalization(header_canonicalization)
+        .with_body_canonicalization(body_canonicalization);
+    if let Some(expiry) = expiry {
+        builder = builder.with_expiry(chrono::Duration::seconds(expiry));
+    }
+    let signed_email = builder.build()?.sign_message(&email)?;
+
+    std::io::stdout().write_all(&signed_email)?;
+    Ok(())
+}
+
+fn keygen(
+    algorithm: Algorithm,
+    bits: usize,
+    selector: &str,
+    pkcs8: bool,
+    out: Option<&Path>,
+) -> Result<(), Box<dyn Error>> {
+    let key = match algorithm {
+        Algorithm::Rsa => keys::generate_rsa(bits)?,
+        Algorithm::Ed25519 => keys::generate_ed25519(),
+    };
+    let pem = match algorithm {
+        Algorithm::Rsa if !pkcs8 => keys::to_pkcs1_pem(&key)?,
+        _ => keys::to_pkcs8_pem(&key)?,
+    };
+    let record = DkimRecord::from_backend(&key)?.to_bind_line(selector);
+
+    match out {
+        Some(dir) => {
+            let private_path = dir.join(format!("{}.private", selector));
+            let record_path = dir.join(format!("{}.txt", selector));
+            std::fs::write(&private_path, pem)?;
+            std::fs::write(&record_path, format!("{}\n", record))?;
+            println!("{}", private_path.display());
+            println!("{}", record_path.display());
+        }
+        None => {
+            print!("{}", pem);
+            println!("{}", record);
+        }
+    }
+    Ok(())
+}
+
+fn inspect(email: &[u8], resolver_args: &ResolverArgs) -> Result<(), Box<dyn Error>> {
+    let email = mailparse::parse_mail(email)?;
+    let resolver = resolver_args.resolver()?;
+
+    let mut signatures = 0;
+    for header in email.headers.get_all_headers("DKIM-Signature") {
+        let value = String::from_utf8_lossy(header.get_value_raw());
+        println!("signature #{}", signatures);
+        signatures += 1;
+
+        let signature = match DkimSignature::parse(&value) {
+            Ok(signature) => signature,
+            Err(err) => {
+                println!("  error: {}", err);
+                continue;
+            }
+        };
+        println!("  algorithm (a=): {}", signature.algorithm);
+        println!("  domain (d=): {}", signature.signing_domain);
+        println!("  selector (s=): {}", signature.selector);
+        println!(
+            "  canonicalization (c=): {}/{}",
+            signature.header_canonicalization, signature.body_canonicalization
+        );
+        println!("  headers (h=): {}", signature.signed_headers.join(":"));
+        println!(
+            "  body hash (bh=): {}",
+            base64::encode(&signature.body_hash)
+        );
+        if let Some(auid) = &signature.auid {
+            println!("  identifier (i=): {}", auid);
+        }
+        if let Some(body_length) = signature.body_length {
+            println!("  body length (l=): {}", body_length);
+        }
+        if let Some(timestamp) = signature.timestamp {
+            println!("  timestamp (t=): {}", timestamp);
+        }
+        if let Some(expiration) = signature.expiration {
+            println!("  expiration (x=): {}", expiration);
+        }
+        for (name, value) in &signature.copied_headers {
+            println!("  copied header (z=): {}: {}", name, value);
+        }
+        for (name, value) in signature.extensions() {
+            println!("  extension: {}={}", name, value);
+        }
+
+        if resolver_args.is_empty() {
+            continue;
+        }
+        let name = format!(
+            "{}._domainkey.{}",
+            signature.selector, signature.signing_domain
+        );
+        match resolver.lookup_txt(&name) {
+            Ok(records) => {
+                for record in records {
+                    println!("  key record {}:", name);
+                    match near_dkim::parse_tag_list(&record) {
+                        Ok((_, tags)) => {
+                            for tag in tags {
+                                println!("    {}={}", tag.name, tag.value());
+                            }
+                        }
+                        Err(err) => println!("    unparsable record: {}", err),
+                    }
+                }
+            }
+            Err(err) => println!("  key record {}: {}", name, err),
+        }
+    }
+    if signatures == 0 {
+        println!("no DKIM-Signature header");
+    }
+    Ok(())
+}
+
+fn canonicalize(email: &[u8]) -> Result<(), Box<dyn Error>> {
+    let email = mailparse::parse_mail(email)?;
+    // The canonicalized data is collected before the key lookup
+    let (_, traces) = near_dkim::verify_email_with_trace(&email, &StubResolver::default())?;
+
+    let mut stdout = std::io::stdout();
+    for (i, trace) in traces.iter().enumerate() {
+        writeln!(stdout, "==> signature #{}: {}", i, trace.header.trim())?;
+        if trace.header_hash_input.is_empty() {
+            if let Some(err) = &trace.error {
+                writeln!(stdout, "error: {}", err)?;
+            }
+            continue;
+        }
+        writeln!(stdout, "--- headers ---")?;
+        stdout.write_all(&trace.header_hash_input)?;
+        writeln!(stdout, "\n--- body ---")?;
+        stdout.write_all(&trace.canonicalized_body)?;
+    }
+    Ok(())
+}
+
+fn run(cli: Cli) -> Result<ExitCode, Box<dyn Error>> {
+    match cli.command {
+        Command::Verify {
+            email,
+            resolver,
+            trace,
+        } => verify(&read_email(&email)?, &resolver, trace),
+        Command::Sign {
+            email,
+            key,
+            domain,
+            selector,
+            headers,
+            canonicalization,
+            expiry,
+        } => {
+            sign(
+                &read_email(&email)?,
+                &key,
+                &domain,
+                &selector,
+                &headers,
+                &canonicalization,
+                expiry,
+            )?;
+            Ok(ExitCode::SUCCESS)
+        }
+        Command::Keygen {
+            algorithm,
+            bits,
+            selector,
+            pkcs8,
+            out,
+        } => {
+            keygen(algorithm, bits, &selector, pkcs8, out.as_deref())?;
+            Ok(ExitCode::SUCCESS)
+        }
+        Command::Inspect { email, resolver } => {
+            inspect(&read_email(&email)?, &resolver)?;
+            Ok(ExitCode::SUCCESS)
+        }
+        Command::Canonicalize { email } => {
+            canonicalize(&read_email(&email)?)?;
+            Ok(ExitCode::SUCCESS)
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    match run(Cli::parse()) {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            ExitCode::from(2)
+        }
+    }
+}
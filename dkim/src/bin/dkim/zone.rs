@@ -0,0 +1,241 @@
+//! Stub resolver answering TXT queries from a BIND zone file and from records
+//! given on the command line, so that verification doesn't need the network.
+use std::collections::HashMap;
+
+use near_dkim::{dns, DKIMError};
+
+#[derive(Debug, Default)]
+pub struct StubResolver {
+    records: HashMap<String, Vec<String>>,
+}
+
+impl StubResolver {
+    /// Adds a TXT record. The name is fully qualified, without the trailing dot.
+    pub fn insert(&mut self, name: &str, value: String) {
+        self.records
+            .entry(normalize_name(name))
+            .or_default()
+            .push(value);
+    }
+
+    /// Adds the TXT records of a zone file. Relative names are completed with
+    /// `origin`, or with the `$ORIGIN` of the file. An entry starting with
+    /// whitespace has the name of the previous entry.
+    pub fn load_zone(&mut self, zone: &str, origin: Option<&str>) -> Result<(), String> {
+        let mut origin = origin.map(normalize_name);
+        let mut previous_name: Option<String> = None;
+        for entry in zone_entries(zone)? {
+            let tokens = tokenize(&entry)?;
+            let Some(Token::Word(first)) = tokens.first() else {
+                continue;
+            };
+            if first.eq_ignore_ascii_case("$ORIGIN") {
+                if let Some(Token::Word(value)) = tokens.get(1) {
+                    origin = Some(normalize_name(value));
+                }
+                continue;
+            }
+            if first.starts_with('$') {
+                continue;
+            }
+
+            // [name] [ttl] [class] type data
+            let name = if entry.starts_with(char::is_whitespace) {
+                previous_name
+                    .clone()
+                    .ok_or("record without a name and no previous name")?
+            } else if first.ends_with('.') {
+                first.clone()
+            } else if first == "@" {
+                origin.clone().ok_or("relative name without an origin")?
+            } else {
+                match &origin {
+                    Some(origin) => format!("{}.{}", first, origin),
+                    None => first.clone(),
+                }
+            };
+            previous_name = Some(name.clone());
+
+            let Some(type_index) = tokens
+                .iter()
+                .position(|t| matches!(t, Token::Word(w) if w.eq_ignore_ascii_case("TXT")))
+            else {
+                continue;
+            };
+            let value: String = tokens[type_index + 1..]
+                .iter()
+                .map(|t| match t {
+                    Token::Word(w) | Token::Quoted(w) => w.as_str(),
+                })
+                .collect();
+            self.insert(&name, value);
+        }
+        Ok(())
+    }
+}
+
+impl dns::Lookup for StubResolver {
+    fn lookup_txt(&self, name: &str) -> Result<Vec<String>, DKIMError> {
+        self.records
+            .get(&normalize_name(name))
+            .cloned()
+            .ok_or(DKIMError::NoKeyForSignature)
+    }
+}
+
+fn normalize_name(name: &str) -> String {
+    name.trim_end_matches('.').to_lowercase()
+}
+
+/// Splits a zone file in entries, joining the lines inside parentheses and
+/// removing the comments
+fn zone_entries(zone: &str) -> Result<Vec<String>, String> {
+    let mut entries = vec![];
+    let mut entry = String::new();
+    let mut depth = 0;
+    for line in zone.lines() {
+        let mut in_quotes = false;
+        let mut escaped = false;
+        for c in line.chars() {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' if in_quotes => escaped = true,
+                '"' => in_quotes = !in_quotes,
+                ';' if !in_quotes => break,
+                '(' if !in_quotes => {
+                    depth += 1;
+                    entry.push(' ');
+                    continue;
+                }
+                ')' if !in_quotes => {
+                    depth -= 1;
+                    entry.push(' ');
+                    continue;
+                }
+                _ => {}
+            }
+            entry.push(c);
+        }
+        if in_quotes {
+            return Err(format!("unterminated string: {}", line));
+        }
+        if depth == 0 {
+            if !entry.trim().is_empty() {
+                entries.push(std::mem::take(&mut entry));
+            }
+            entry.clear();
+        } else {
+            entry.push(' ');
+        }
+    }
+    if depth != 0 {
+        return Err("unbalanced parentheses".to_owned());
+    }
+    Ok(entries)
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Quoted(String),
+}
+
+fn tokenize(entry: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let mut chars = entry.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '"' {
+            chars.next();
+            let mut value = String::new();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => value.extend(chars.next()),
+                    Some(c) => value.push(c),
+                    None => return Err(format!("unterminated string: {}", entry)),
+                }
+            }
+            tokens.push(Token::Quoted(value));
+        } else {
+            let mut value = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() || c == '"' {
+                    break;
+                }
+                value.push(c);
+                chars.next();
+            }
+            tokens.push(Token::Word(value));
+        }
+    }
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_dkim::dns::Lookup;
+
+    #[test]
+    fn test_load_zone() {
+        let mut resolver = StubResolver::default();
+        let zone = std::fs::read_to_string("./test/keys/2022.txt").unwrap();
+        resolver.load_zone(&zone, Some("example.com.")).unwrap();
+        resolver
+            .load_zone(
+                "$ORIGIN football.example.com.\n\
+                 brisbane._domainkey 3600 IN TXT \"v=DKIM1; k=ed25519; \" ; comment\n\
+                 @ IN A 127.0.0.1\n\
+                 other.example.org. IN TXT (\"a\\\"b\"\n \"c\")",
+                None,
+            )
+            .unwrap();
+
+        let record = &resolver.lookup_txt("2022._domainkey.example.com").unwrap()[0];
+        assert!(record.starts_with("v=DKIM1; h=sha256; k=rsa; t=y:s; p=MIIBIjANBg"));
+        assert!(record.ends_with("XIApMuK2QIDAQAB"));
+        assert_eq!(
+            resolver
+                .lookup_txt("Brisbane._domainkey.football.example.com.")
+                .unwrap(),
+            vec!["v=DKIM1; k=ed25519; ".to_owned()]
+        );
+        assert_eq!(
+            resolver.lookup_txt("other.example.org").unwrap(),
+            vec!["a\"bc".to_owned()]
+        );
+        assert_eq!(
+            resolver.lookup_txt("football.example.com").unwrap_err(),
+            DKIMError::NoKeyForSignature
+        );
+    }
+
+    #[test]
+    fn test_load_zone_previous_name() {
+        let mut resolver = StubResolver::default();
+        resolver
+            .load_zone(
+                "$TTL 3600\n\
+                 sel._domainkey IN A 127.0.0.1\n\
+                 \t IN TXT \"v=DKIM1; p=a\"\n\
+                 \x20   3600 TXT \"v=DKIM1; p=b\"",
+                Some("example.com"),
+            )
+            .unwrap();
+        assert_eq!(
+            resolver.lookup_txt("sel._domainkey.example.com").unwrap(),
+            vec!["v=DKIM1; p=a".to_owned(), "v=DKIM1; p=b".to_owned()]
+        );
+        assert!(resolver.load_zone(" IN TXT \"a\"", None).is_err());
+    }
+
+    #[test]
+    fn test_load_zone_errors() {
+        let mut resolver = StubResolver::default();
+        assert!(resolver.load_zone("a IN TXT ( \"b\"", None).is_err());
+        assert!(resolver.load_zone("a IN TXT \"b", None).is_err());
+        assert!(resolver.load_zone("@ IN TXT \"b\"", None).is_err());
+    }
+}
@@ -1,16 +1,26 @@
 // Inspired from https://docs.rs/dkim/latest/src/dkim/canonicalization.rs.html
 use crate::bytes;
+use crate::prelude::*;
 
 #[derive(PartialEq, Clone, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+#[cfg_attr(
+    feature = "borsh",
+    derive(borsh::BorshSerialize, borsh::BorshDeserialize)
+)]
 pub enum Type {
     Simple,
     Relaxed,
 }
-impl std::string::ToString for Type {
-    fn to_string(&self) -> String {
+impl core::fmt::Display for Type {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         match self {
-            Self::Simple => "simple".to_owned(),
-            Self::Relaxed => "relaxed".to_owned(),
+            Self::Simple => write!(f, "simple"),
+            Self::Relaxed => write!(f, "relaxed"),
         }
     }
 }
@@ -75,10 +85,50 @@ pub(crate) fn canonicalize_body_relaxed(body: &[u8]) -> Vec<u8> {
     body
 }
 
+/// Length of `body` once canonicalized, as the beginning of a longer body:
+/// unlike [canonicalize_body_simple] and [canonicalize_body_relaxed] the
+/// empty lines at its end are kept and no CRLF is added.
+#[cfg(all(feature = "verify", feature = "std"))]
+pub(crate) fn canonicalized_prefix_len(canonicalization: &Type, body: &[u8]) -> usize {
+    match canonicalization {
+        Type::Simple => body.len(),
+        Type::Relaxed => {
+            let mut len = 0;
+            let mut rest = body;
+            loop {
+                match bytes::find(rest, b"\r\n") {
+                    Some(ix) => {
+                        len += relaxed_line_len(&rest[..ix]) + 2;
+                        rest = &rest[ix + 2..];
+                    }
+                    None => return len + relaxed_line_len(rest),
+                }
+            }
+        }
+    }
+}
+
+/// Length of a line without its CRLF once its whitespace is reduced
+#[cfg(all(feature = "verify", feature = "std"))]
+fn relaxed_line_len(line: &[u8]) -> usize {
+    let is_wsp = |c: &u8| *c == b' ' || *c == b'\t';
+    let end = line.iter().rposition(|c| !is_wsp(c)).map_or(0, |ix| ix + 1);
+    let mut previous = false;
+    line[..end]
+        .iter()
+        .filter(|c| {
+            let wsp = is_wsp(c);
+            let kept = !(wsp && previous);
+            previous = wsp;
+            kept
+        })
+        .count()
+}
+
 // https://datatracker.ietf.org/doc/html/rfc6376#section-3.4.1
 pub(crate) fn canonicalize_header_simple(key: &str, value: &[u8]) -> Vec<u8> {
     let mut out = Vec::new();
-    out.extend_from_slice(&key.as_bytes());
+    out.extend_from_slice(key.as_bytes());
     out.extend_from_slice(b": ");
     out.extend_from_slice(value);
     out.extend_from_slice(b"\r\n");
@@ -93,7 +143,7 @@ pub(crate) fn canonicalize_header_relaxed(key: &str, value: &[u8]) -> Vec<u8> {
     let value = canonicalize_header_value_relaxed(value);
 
     let mut out = Vec::new();
-    out.extend_from_slice(&key.as_bytes());
+    out.extend_from_slice(key.as_bytes());
     out.extend_from_slice(b":");
     out.extend_from_slice(&value);
     out.extend_from_slice(b"\r\n");
@@ -155,4 +205,18 @@ mod tests {
         assert_eq!(canonicalize_body_relaxed(b"\r\n"), b"\r\n");
         assert_eq!(canonicalize_body_relaxed(b"hey        \r\n"), b"hey\r\n");
     }
+
+    #[cfg(all(feature = "verify", feature = "std"))]
+    #[test]
+    fn test_canonicalized_prefix_len() {
+        let body = b"a \t b  \r\n\r\n\tc\r\n";
+        assert_eq!(
+            canonicalized_prefix_len(&Type::Relaxed, body),
+            canonicalize_body_relaxed(body).len()
+        );
+        // The empty lines are kept
+        assert_eq!(canonicalized_prefix_len(&Type::Relaxed, b"a\r\n\r\n"), 5);
+        assert_eq!(canonicalized_prefix_len(&Type::Relaxed, b"a  "), 1);
+        assert_eq!(canonicalized_prefix_len(&Type::Simple, b"a  \r\n\r\n"), 7);
+    }
 }
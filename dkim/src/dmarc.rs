@@ -0,0 +1,502 @@
+//! DMARC aggregate reports
+//! <https://datatracker.ietf.org/doc/html/rfc7489#section-7.2>: the DKIM and
+//! SPF outcomes of the emails received from a domain over a period, counted
+//! per source address, disposition and outcome, as the XML of Appendix C.
+//!
+//! Relaxed alignment compares the last two labels of the domains instead of
+//! the organizational domains of the Public Suffix List: domains under a
+//! multi-label public suffix, like `example.co.uk`, are aligned with any
+//! domain of the suffix. The alignment is only reported, never used to decide
+//! what to do with an email.
+use alloc::collections::BTreeMap;
+use core::net::IpAddr;
+
+use crate::prelude::*;
+use crate::spf::{SpfOutput, SpfResult, SpfScope};
+use crate::SignatureResult;
+
+/// What the receiver did with the email
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Disposition {
+    None,
+    Quarantine,
+    Reject,
+}
+
+impl Disposition {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Quarantine => "quarantine",
+            Self::Reject => "reject",
+        }
+    }
+}
+
+/// Identifier alignment mode (`adkim=` and `aspf=`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Alignment {
+    Relaxed,
+    Strict,
+}
+
+impl Alignment {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Relaxed => "r",
+            Self::Strict => "s",
+        }
+    }
+
+    /// Whether an authenticated domain is aligned with the From domain, for
+    /// the `policy_evaluated` of the report only: see the module documentation
+    pub(crate) fn is_aligned(&self, domain: &str, from_domain: &str) -> bool {
+        match self {
+            Self::Strict => domain.eq_ignore_ascii_case(from_domain),
+            Self::Relaxed => organizational_domain(domain)
+                .eq_ignore_ascii_case(&organizational_domain(from_domain)),
+        }
+    }
+}
+
+fn organizational_domain(domain: &str) -> String {
+    let labels: Vec<&str> = domain.trim_end_matches('.').rsplit('.').take(2).collect();
+    labels.into_iter().rev().collect::<Vec<_>>().join(".")
+}
+
+/// The DMARC record of the domain at the time of the report
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PolicyPublished {
+    pub domain: String,
+    pub adkim: Alignment,
+    pub aspf: Alignment,
+    pub p: Disposition,
+    pub sp: Option<Disposition>,
+    pub pct: u8,
+}
+
+impl PolicyPublished {
+    /// `p=none` with relaxed alignment: the default of a monitoring domain
+    pub fn monitor(domain: &str) -> Self {
+        PolicyPublished {
+            domain: domain.to_owned(),
+            adkim: Alignment::Relaxed,
+            aspf: Alignment::Relaxed,
+            p: Disposition::None,
+            sp: None,
+            pct: 100,
+        }
+    }
+}
+
+/// The reporting organization and the period of the report
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReportMetadata {
+    pub org_name: String,
+    pub email: String,
+    pub report_id: String,
+    pub begin: chrono::DateTime<chrono::Utc>,
+    pub end: chrono::DateTime<chrono::Utc>,
+}
+
+/// The authentication outcomes of a received email
+#[derive(Debug, Clone)]
+pub struct EvaluatedEmail {
+    /// Address of the client that handed the email to the MX
+    pub source_ip: IpAddr,
+    /// Domain of the From header
+    pub header_from: String,
+    /// Result of each DKIM-Signature, see
+    /// [crate::verify_email_signatures_with_resolver]
+    pub dkim: Vec<SignatureResult>,
+    pub spf: Option<SpfOutput>,
+    pub disposition: Disposition,
+}
+
+/// `auth_results/dkim` of a record
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct DkimAuthResult {
+    /// Empty if the signature could not be parsed
+    domain: String,
+    selector: Option<String>,
+    result: &'static str,
+    /// Why the verification failed
+    human_result: Option<String>,
+}
+
+/// What identifies a record: all the emails with the same key are counted in
+/// one record
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct RecordKey {
+    source_ip: IpAddr,
+    disposition: Disposition,
+    dkim_aligned_pass: bool,
+    spf_aligned_pass: bool,
+    header_from: String,
+    dkim: Vec<DkimAuthResult>,
+    spf: Option<(String, SpfScope, &'static str)>,
+}
+
+/// Aggregates evaluated emails into an aggregate report
+#[derive(Debug, Clone)]
+pub struct AggregateReport {
+    metadata: ReportMetadata,
+    policy: PolicyPublished,
+    records: BTreeMap<RecordKey, u64>,
+}
+
+impl AggregateReport {
+    pub fn new(metadata: ReportMetadata, policy: PolicyPublished) -> Self {
+        AggregateReport {
+            metadata,
+            policy,
+            records: BTreeMap::new(),
+        }
+    }
+
+    /// Counts an email in its record
+    pub fn add(&mut self, email: &EvaluatedEmail) {
+        let header_from = email.header_from.to_lowercase();
+        let dkim: Vec<DkimAuthResult> = email
+            .dkim
+            .iter()
+            .map(|signature_result| {
+                let signature = signature_result.signature.as_ref();
+                DkimAuthResult {
+                    domain: signature.map_or_else(String::new, |signature| {
+                        signature.signing_domain.to_lowercase()
+                    }),
+                    selector: signature.map(|signature| signature.selector.clone()),
+                    result: signature_result.result.result().as_str(),
+                    human_result: signature_result.result.error().map(|err| err.to_string()),
+                }
+            })
+            .collect();
+        let dkim_aligned_pass = dkim.iter().any(|result| {
+            result.result == "pass" && self.policy.adkim.is_aligned(&result.domain, &header_from)
+        });
+        let spf_aligned_pass = email.spf.as_ref().is_some_and(|spf| {
+            spf.result == SpfResult::Pass && self.policy.aspf.is_aligned(&spf.domain, &header_from)
+        });
+
+        let key = RecordKey {
+            source_ip: email.source_ip,
+            disposition: email.disposition,
+            dkim_aligned_pass,
+            spf_aligned_pass,
+            header_from,
+            dkim,
+            spf: email
+                .spf
+                .as_ref()
+                .map(|spf| (spf.domain.clone(), spf.scope, spf.result.as_str())),
+        };
+        *self.records.entry(key).or_default() += 1;
+    }
+
+    /// Number of emails counted
+    pub fn message_count(&self) -> u64 {
+        self.records.values().sum()
+    }
+
+    /// The report, as described in
+    /// <https://datatracker.ietf.org/doc/html/rfc7489#appendix-C>
+    pub fn to_xml(&self) -> String {
+        let metadata = &self.metadata;
+        let policy = &self.policy;
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<feedback>\n");
+        xml += "  <version>1.0</version>\n";
+        xml += "  <report_metadata>\n";
+        xml += &element(4, "org_name", &metadata.org_name);
+        xml += &element(4, "email", &metadata.email);
+        xml += &element(4, "report_id", &metadata.report_id);
+        xml += "    <date_range>\n";
+        xml += &element(6, "begin", &metadata.begin.timestamp().to_string());
+        xml += &element(6, "end", &metadata.end.timestamp().to_string());
+        xml += "    </date_range>\n";
+        xml += "  </report_metadata>\n";
+        xml += "  <policy_published>\n";
+        xml += &element(4, "domain", &policy.domain);
+        xml += &element(4, "adkim", policy.adkim.as_str());
+        xml += &element(4, "aspf", policy.aspf.as_str());
+        xml += &element(4, "p", policy.p.as_str());
+        if let Some(sp) = policy.sp {
+            xml += &element(4, "sp", sp.as_str());
+        }
+        xml += &element(4, "pct", &policy.pct.to_string());
+        xml += "  </policy_published>\n";
+
+        for (key, count) in &self.records {
+            xml += "  <record>\n";
+            xml += "    <row>\n";
+            xml += &element(6, "source_ip", &key.source_ip.to_string());
+            xml += &element(6, "count", &count.to_string());
+            xml += "      <policy_evaluated>\n";
+            xml += &element(8, "disposition", key.disposition.as_str());
+            xml += &element(8, "dkim", pass_or_fail(key.dkim_aligned_pass));
+            xml += &element(8, "spf", pass_or_fail(key.spf_aligned_pass));
+            xml += "      </policy_evaluated>\n";
+            xml += "    </row>\n";
+            xml += "    <identifiers>\n";
+            xml += &element(6, "header_from", &key.header_from);
+            xml += "    </identifiers>\n";
+            xml += "    <auth_results>\n";
+            for dkim in &key.dkim {
+                xml += "      <dkim>\n";
+                xml += &element(8, "domain", &dkim.domain);
+                if let Some(selector) = &dkim.selector {
+                    xml += &element(8, "selector", selector);
+                }
+                xml += &element(8, "result", dkim.result);
+                if let Some(human_result) = &dkim.human_result {
+                    xml += &element(8, "human_result", human_result);
+                }
+                xml += "      </dkim>\n";
+            }
+            // The SPF result is required, `none` if SPF was not checked
+            let (domain, scope, result) = match &key.spf {
+                Some((domain, scope, result)) => (domain.as_str(), *scope, *result),
+                None => (key.header_from.as_str(), SpfScope::MailFrom, "none"),
+            };
+            xml += "      <spf>\n";
+            xml += &element(8, "domain", domain);
+            xml += &element(8, "scope", scope.as_str());
+            xml += &element(8, "result", result);
+            xml += "      </spf>\n";
+            xml += "    </auth_results>\n";
+            xml += "  </record>\n";
+        }
+        xml += "</feedback>\n";
+        xml
+    }
+}
+
+fn pass_or_fail(pass: bool) -> &'static str {
+    if pass {
+        "pass"
+    } else {
+        "fail"
+    }
+}
+
+fn element(indent: usize, name: &str, value: &str) -> String {
+    format!(
+        "{:indent$}<{name}>{}</{name}>\n",
+        "",
+        escape(value),
+        indent = indent,
+        name = name
+    )
+}
+
+fn escape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => out += "&amp;",
+            '<' => out += "&lt;",
+            '>' => out += "&gt;",
+            '"' => out += "&quot;",
+            '\'' => out += "&apos;",
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{canonicalization::Type, DKIMError, DKIMResult, DkimSignature};
+    use chrono::TimeZone;
+
+    fn signature_result(domain: &str, result: DKIMResult) -> SignatureResult {
+        SignatureResult {
+            signature: Some(
+                DkimSignature::parse(&format!(
+                    "v=1; a=rsa-sha256; d={}; s=20230601; h=from; bh=MTIz; b=NDU2",
+                    domain
+                ))
+                .unwrap(),
+            ),
+            result,
+        }
+    }
+
+    fn report() -> AggregateReport {
+        AggregateReport::new(
+            ReportMetadata {
+                org_name: "Relayer".to_owned(),
+                email: "dmarc@relayer.example".to_owned(),
+                report_id: "gmail.com-1".to_owned(),
+                begin: chrono::Utc.timestamp_opt(1690848000, 0).unwrap(),
+                end: chrono::Utc.timestamp_opt(1690934400, 0).unwrap(),
+            },
+            PolicyPublished {
+                sp: Some(Disposition::Quarantine),
+                ..PolicyPublished::monitor("gmail.com")
+            },
+        )
+    }
+
+    #[test]
+    fn test_alignment() {
+        assert!(Alignment::Relaxed.is_aligned("mail.gmail.com", "gmail.com"));
+        assert!(Alignment::Relaxed.is_aligned("a.gmail.com", "b.Gmail.com"));
+        assert!(!Alignment::Relaxed.is_aligned("gmail.com.evil.example", "gmail.com"));
+        assert!(!Alignment::Strict.is_aligned("mail.gmail.com", "gmail.com"));
+        assert!(Alignment::Strict.is_aligned("GMAIL.com", "gmail.com"));
+    }
+
+    #[test]
+    fn test_aggregate_report() {
+        let mut report = report();
+        let passing = EvaluatedEmail {
+            source_ip: "209.85.220.41".parse().unwrap(),
+            header_from: "gmail.com".to_owned(),
+            dkim: vec![signature_result(
+                "gmail.com",
+                DKIMResult::pass(Type::Relaxed, Type::Relaxed),
+            )],
+            spf: Some(SpfOutput {
+                result: SpfResult::Pass,
+                domain: "gmail.com".to_owned(),
+                scope: SpfScope::MailFrom,
+                error: None,
+            }),
+            disposition: Disposition::None,
+        };
+        report.add(&passing);
+        report.add(&passing);
+        report.add(&EvaluatedEmail {
+            dkim: vec![signature_result(
+                "gmail.com",
+                DKIMResult::fail(DKIMError::BodyHashDidNotVerify),
+            )],
+            spf: None,
+            disposition: Disposition::Reject,
+            ..passing.clone()
+        });
+        assert_eq!(report.message_count(), 3);
+
+        assert_eq!(
+            report.to_xml(),
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<feedback>
+  <version>1.0</version>
+  <report_metadata>
+    <org_name>Relayer</org_name>
+    <email>dmarc@relayer.example</email>
+    <report_id>gmail.com-1</report_id>
+    <date_range>
+      <begin>1690848000</begin>
+      <end>1690934400</end>
+    </date_range>
+  </report_metadata>
+  <policy_published>
+    <domain>gmail.com</domain>
+    <adkim>r</adkim>
+    <aspf>r</aspf>
+    <p>none</p>
+    <sp>quarantine</sp>
+    <pct>100</pct>
+  </policy_published>
+  <record>
+    <row>
+      <source_ip>209.85.220.41</source_ip>
+      <count>2</count>
+      <policy_evaluated>
+        <disposition>none</disposition>
+        <dkim>pass</dkim>
+        <spf>pass</spf>
+      </policy_evaluated>
+    </row>
+    <identifiers>
+      <header_from>gmail.com</header_from>
+    </identifiers>
+    <auth_results>
+      <dkim>
+        <domain>gmail.com</domain>
+        <selector>20230601</selector>
+        <result>pass</result>
+      </dkim>
+      <spf>
+        <domain>gmail.com</domain>
+        <scope>mfrom</scope>
+        <result>pass</result>
+      </spf>
+    </auth_results>
+  </record>
+  <record>
+    <row>
+      <source_ip>209.85.220.41</source_ip>
+      <count>1</count>
+      <policy_evaluated>
+        <disposition>reject</disposition>
+        <dkim>fail</dkim>
+        <spf>fail</spf>
+      </policy_evaluated>
+    </row>
+    <identifiers>
+      <header_from>gmail.com</header_from>
+    </identifiers>
+    <auth_results>
+      <dkim>
+        <domain>gmail.com</domain>
+        <selector>20230601</selector>
+        <result>fail</result>
+        <human_result>body hash did not verify</human_result>
+      </dkim>
+      <spf>
+        <domain>gmail.com</domain>
+        <scope>mfrom</scope>
+        <result>none</result>
+      </spf>
+    </auth_results>
+  </record>
+</feedback>
+"#
+        );
+    }
+
+    #[test]
+    fn test_unparsed_signature_and_helo() {
+        let mut report = report();
+        report.add(&EvaluatedEmail {
+            source_ip: "192.0.2.1".parse().unwrap(),
+            header_from: "gmail.com".to_owned(),
+            dkim: vec![SignatureResult {
+                signature: None,
+                result: DKIMResult::fail(DKIMError::SignatureSyntaxError(
+                    "duplicate tag d".to_owned(),
+                )),
+            }],
+            spf: Some(SpfOutput {
+                result: SpfResult::Pass,
+                domain: "mail.gmail.com".to_owned(),
+                scope: SpfScope::Helo,
+                error: None,
+            }),
+            disposition: Disposition::None,
+        });
+        let xml = report.to_xml();
+        assert!(xml.contains(
+            "      <dkim>
+        <domain></domain>
+        <result>permerror</result>
+        <human_result>signature syntax error: duplicate tag d</human_result>
+      </dkim>
+      <spf>
+        <domain>mail.gmail.com</domain>
+        <scope>helo</scope>
+        <result>pass</result>
+      </spf>
+"
+        ));
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("a<b>&\"c'"), "a&lt;b&gt;&amp;&quot;c&apos;");
+    }
+}
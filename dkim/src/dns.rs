@@ -1,6 +1,354 @@
-use crate::DKIMError;
+use alloc::collections::BTreeMap;
+use core::net::{Ipv4Addr, Ipv6Addr};
+
+use sha2::{Digest, Sha256};
+
+use crate::prelude::*;
+use crate::{public_key, DKIMError};
+
+/// SHA-256 of the SubjectPublicKeyInfo of a key
+pub type Pin = [u8; 32];
+
+/// DER of the SubjectPublicKeyInfo of an Ed25519 key, before the 32 bytes of
+/// the key <https://datatracker.ietf.org/doc/html/rfc8410#section-4>
+const ED25519_SPKI_PREFIX: &[u8] = &[
+    0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00,
+];
 
 /// A trait for entities that perform DNS resolution.
+///
+/// A name without records of the requested type is reported with
+/// [DKIMError::NoKeyForSignature]; other errors are temporary failures. DKIM
+/// only needs TXT records, the other types are used by [crate::spf]: by
+/// default they fail with [DKIMError::UnsupportedLookup], so a resolver used
+/// with [crate::spf::check_host] should implement them.
 pub trait Lookup {
     fn lookup_txt(&self, name: &str) -> Result<Vec<String>, DKIMError>;
+
+    fn lookup_a(&self, name: &str) -> Result<Vec<Ipv4Addr>, DKIMError> {
+        let _ = name;
+        Err(unsupported("A"))
+    }
+
+    fn lookup_aaaa(&self, name: &str) -> Result<Vec<Ipv6Addr>, DKIMError> {
+        let _ = name;
+        Err(unsupported("AAAA"))
+    }
+
+    /// Exchanges of the MX records, by preference
+    fn lookup_mx(&self, name: &str) -> Result<Vec<String>, DKIMError> {
+        let _ = name;
+        Err(unsupported("MX"))
+    }
+}
+
+fn unsupported(record_type: &'static str) -> DKIMError {
+    DKIMError::UnsupportedLookup(record_type)
+}
+
+/// The signature whose key is looked up
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LookupContext<'a> {
+    /// Signing domain (d=)
+    pub domain: &'a str,
+    /// Selector (s=)
+    pub selector: &'a str,
+    /// Signature timestamp (t=), if present and valid
+    pub timestamp: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// A resolver that knows which signature it resolves the key of, so that it
+/// can answer with the key that was valid when the email was signed instead
+/// of the current one.
+///
+/// Every [Lookup] is a [TimedLookup] ignoring the context.
+pub trait TimedLookup {
+    fn lookup_txt_at(&self, name: &str, context: &LookupContext) -> Result<Vec<String>, DKIMError>;
+
+    /// Whether the answer may depend on the timestamp of the context, so that
+    /// it can't be reused for a signature with another timestamp
+    fn depends_on_timestamp(&self) -> bool {
+        true
+    }
+}
+
+impl<T: Lookup + ?Sized> TimedLookup for T {
+    fn lookup_txt_at(&self, name: &str, _: &LookupContext) -> Result<Vec<String>, DKIMError> {
+        self.lookup_txt(name)
+    }
+
+    fn depends_on_timestamp(&self) -> bool {
+        false
+    }
+}
+
+/// A key record that was published during a window of time
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistoricalRecord {
+    /// The TXT record: `v=DKIM1; k=...; p=...`
+    pub record: String,
+    /// Start of the window, unbounded if `None`
+    pub not_before: Option<chrono::DateTime<chrono::Utc>>,
+    /// End of the window, unbounded if `None`
+    pub not_after: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl HistoricalRecord {
+    fn is_valid_at(&self, timestamp: chrono::DateTime<chrono::Utc>) -> bool {
+        self.not_before.map_or(true, |start| start <= timestamp)
+            && self.not_after.map_or(true, |end| timestamp <= end)
+    }
+}
+
+/// Adapter serving rotated-out key records to signatures whose timestamp
+/// (t=) falls in their validity window. Other lookups, including signatures
+/// without a timestamp, are delegated to the inner resolver.
+///
+/// The timestamp is chosen by the signer: whoever holds a retired private key
+/// can still sign emails dated in its window. Only keep the records of keys
+/// that were retired, not of keys that leaked.
+#[derive(Debug, Clone)]
+pub struct HistoricalLookup<L> {
+    inner: L,
+    records: BTreeMap<String, Vec<HistoricalRecord>>,
+}
+
+impl<L: Lookup> HistoricalLookup<L> {
+    pub fn new(inner: L) -> Self {
+        HistoricalLookup {
+            inner,
+            records: BTreeMap::new(),
+        }
+    }
+
+    /// Adds a record that was published under `name` during a window
+    pub fn add_record(&mut self, name: &str, record: HistoricalRecord) {
+        self.records
+            .entry(name.to_lowercase())
+            .or_default()
+            .push(record);
+    }
+
+    pub fn inner(&self) -> &L {
+        &self.inner
+    }
+}
+
+impl<L: Lookup> TimedLookup for HistoricalLookup<L> {
+    fn lookup_txt_at(&self, name: &str, context: &LookupContext) -> Result<Vec<String>, DKIMError> {
+        if let (Some(timestamp), Some(records)) =
+            (context.timestamp, self.records.get(&name.to_lowercase()))
+        {
+            let valid: Vec<String> = records
+                .iter()
+                .filter(|record| record.is_valid_at(timestamp))
+                .map(|record| record.record.clone())
+                .collect();
+            if !valid.is_empty() {
+                return Ok(valid);
+            }
+        }
+        self.inner.lookup_txt(name)
+    }
+}
+
+/// The pin of a key record. The p= of an RSA record is already a
+/// SubjectPublicKeyInfo, the raw key of an Ed25519 record is wrapped in one,
+/// so that pins match `openssl pkey -pubout -outform DER | sha256sum`.
+pub fn key_record_pin(record: &str) -> Result<Pin, DKIMError> {
+    let tags = public_key::key_record_tags(record)?;
+    let tag = |name: &str| {
+        tags.iter()
+            .find(|tag| tag.name == name)
+            .map(|tag| tag.value())
+    };
+    let key = base64::decode(&*tag("p").ok_or(DKIMError::NoKeyForSignature)?)
+        .map_err(|_| DKIMError::KeySyntaxError)?;
+    let spki = match tag("k").as_deref() {
+        Some("ed25519") => [ED25519_SPKI_PREFIX, &key].concat(),
+        _ => key,
+    };
+    Ok(Sha256::digest(&spki).into())
+}
+
+/// Resolver trusting key records supplied by the caller, for example attached
+/// to the email by a relayer, instead of DNS. A record is only served if the
+/// pin of its key was approved for the domain and selector.
+#[derive(Debug, Clone, Default)]
+pub struct PinnedKeyLookup {
+    pins: BTreeMap<(String, String), Vec<Pin>>,
+    records: BTreeMap<(String, String), Vec<String>>,
+}
+
+impl PinnedKeyLookup {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Approves a key for a domain and selector. Several keys can be approved,
+    /// for example during a rotation.
+    pub fn add_pin(&mut self, domain: &str, selector: &str, pin: Pin) {
+        self.pins
+            .entry(pinned_key_id(domain, selector))
+            .or_default()
+            .push(pin);
+    }
+
+    /// Supplies a key record for a domain and selector. It is only served if
+    /// it matches a pin.
+    pub fn supply_record(&mut self, domain: &str, selector: &str, record: &str) {
+        self.records
+            .entry(pinned_key_id(domain, selector))
+            .or_default()
+            .push(record.to_owned());
+    }
+}
+
+fn pinned_key_id(domain: &str, selector: &str) -> (String, String) {
+    (domain.to_lowercase(), selector.to_lowercase())
+}
+
+impl TimedLookup for PinnedKeyLookup {
+    fn lookup_txt_at(&self, _: &str, context: &LookupContext) -> Result<Vec<String>, DKIMError> {
+        let id = pinned_key_id(context.domain, context.selector);
+        let records = self.records.get(&id).ok_or(DKIMError::NoKeyForSignature)?;
+        let pins = self.pins.get(&id).map(Vec::as_slice).unwrap_or_default();
+        let pinned: Vec<String> = records
+            .iter()
+            .filter(|record| {
+                key_record_pin(record)
+                    .map(|pin| pins.contains(&pin))
+                    .unwrap_or(false)
+            })
+            .cloned()
+            .collect();
+        if pinned.is_empty() {
+            return Err(DKIMError::KeyNotPinned);
+        }
+        Ok(pinned)
+    }
+    fn depends_on_timestamp(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    struct CurrentKey;
+    impl Lookup for CurrentKey {
+        fn lookup_txt(&self, _: &str) -> Result<Vec<String>, DKIMError> {
+            Ok(vec!["current".to_owned()])
+        }
+    }
+
+    fn context(timestamp: Option<i64>) -> LookupContext<'static> {
+        LookupContext {
+            domain: "example.com",
+            selector: "s",
+            timestamp: timestamp.map(|secs| chrono::Utc.timestamp_opt(secs, 0).unwrap()),
+        }
+    }
+
+    #[test]
+    fn test_historical_lookup() {
+        let at = |secs| Some(chrono::Utc.timestamp_opt(secs, 0).unwrap());
+        let mut resolver = HistoricalLookup::new(CurrentKey);
+        resolver.add_record(
+            "s._domainkey.example.com",
+            HistoricalRecord {
+                record: "old".to_owned(),
+                not_before: None,
+                not_after: at(1000),
+            },
+        );
+        resolver.add_record(
+            "S._domainkey.example.com",
+            HistoricalRecord {
+                record: "older".to_owned(),
+                not_before: at(100),
+                not_after: at(500),
+            },
+        );
+        let lookup = |timestamp| {
+            resolver
+                .lookup_txt_at("s._domainkey.example.com", &context(timestamp))
+                .unwrap()
+        };
+
+        assert_eq!(lookup(Some(50)), ["old"]);
+        assert_eq!(lookup(Some(500)), ["old", "older"]);
+        assert_eq!(lookup(Some(1000)), ["old"]);
+        assert_eq!(lookup(Some(1001)), ["current"]);
+        assert_eq!(lookup(None), ["current"]);
+        assert_eq!(
+            resolver
+                .lookup_txt_at("other._domainkey.example.com", &context(Some(50)))
+                .unwrap(),
+            ["current"]
+        );
+    }
+
+    fn hex(pin: Pin) -> String {
+        pin.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    #[test]
+    fn test_key_record_pin() {
+        let record = "v=DKIM1; k=ed25519; p=11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=";
+        assert_eq!(
+            hex(key_record_pin(record).unwrap()),
+            "06e3fd8fda29bb60ab59557de61edb0aecdb231134be30e75b455f8e1b792fa9"
+        );
+        assert_eq!(
+            key_record_pin("v=DKIM1; k=rsa").unwrap_err(),
+            DKIMError::NoKeyForSignature
+        );
+        assert_eq!(
+            key_record_pin("v=DKIM1; p=!!").unwrap_err(),
+            DKIMError::KeySyntaxError
+        );
+    }
+
+    #[test]
+    fn test_pinned_key_lookup() {
+        let record = "v=DKIM1; k=ed25519; p=11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=";
+        let other = "v=DKIM1; k=ed25519; p=AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+        let mut resolver = PinnedKeyLookup::new();
+        let lookup = |resolver: &PinnedKeyLookup| {
+            resolver.lookup_txt_at("s._domainkey.example.com", &context(None))
+        };
+        assert_eq!(lookup(&resolver), Err(DKIMError::NoKeyForSignature));
+
+        resolver.supply_record("example.com", "s", other);
+        resolver.supply_record("Example.com", "S", record);
+        assert_eq!(lookup(&resolver), Err(DKIMError::KeyNotPinned));
+
+        resolver.add_pin("example.com", "s", key_record_pin(record).unwrap());
+        assert_eq!(lookup(&resolver).unwrap(), [record]);
+        // the pin is per selector
+        assert_eq!(
+            resolver.lookup_txt_at(
+                "other._domainkey.example.com",
+                &LookupContext {
+                    selector: "other",
+                    ..context(None)
+                }
+            ),
+            Err(DKIMError::NoKeyForSignature)
+        );
+
+        // a pinned p= followed by another key isn't served
+        let duplicated = format!("{}; p=AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=", record);
+        assert_eq!(
+            key_record_pin(&duplicated).unwrap_err(),
+            DKIMError::KeySyntaxError
+        );
+        let mut resolver = PinnedKeyLookup::new();
+        resolver.add_pin("example.com", "s", key_record_pin(record).unwrap());
+        resolver.supply_record("example.com", "s", &duplicated);
+        assert_eq!(lookup(&resolver), Err(DKIMError::KeyNotPinned));
+    }
 }
@@ -0,0 +1,425 @@
+//! Verification of the legacy DomainKeys signatures
+//! <https://datatracker.ietf.org/doc/html/rfc4870>, still added by some
+//! gateways in a `DomainKey-Signature` header. Their result is reported apart
+//! from DKIM, as the `domainkeys` method of `Authentication-Results`.
+//!
+//! Only `rsa-sha1` and the `dns` query method exist. The `g=` granularity of
+//! the key records isn't checked.
+use crate::algorithm::{RsaSha1, SignatureAlgorithm};
+use crate::message::{Header, Message};
+use crate::prelude::*;
+use crate::{bytes, dns, parser, public_key, AuthenticationResult, DKIMError};
+
+pub const HEADER: &str = "DomainKey-Signature";
+
+/// Canonicalization of the headers and the body (the `c=` tag)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+#[cfg_attr(
+    feature = "borsh",
+    derive(borsh::BorshSerialize, borsh::BorshDeserialize)
+)]
+pub enum Canonicalization {
+    Simple,
+    /// Without any whitespace
+    Nofws,
+}
+impl core::fmt::Display for Canonicalization {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Simple => write!(f, "simple"),
+            Self::Nofws => write!(f, "nofws"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "borsh",
+    derive(borsh::BorshSerialize, borsh::BorshDeserialize)
+)]
+/// Result of the DomainKeys verification
+pub struct DomainKeysResult {
+    #[cfg_attr(feature = "serde", serde(rename = "result"))]
+    value: AuthenticationResult,
+    error: Option<DKIMError>,
+    canonicalization: Option<Canonicalization>,
+}
+impl DomainKeysResult {
+    fn pass(canonicalization: Canonicalization) -> Self {
+        DomainKeysResult {
+            value: AuthenticationResult::Pass,
+            error: None,
+            canonicalization: Some(canonicalization),
+        }
+    }
+
+    fn neutral() -> Self {
+        DomainKeysResult {
+            value: AuthenticationResult::Neutral,
+            error: None,
+            canonicalization: None,
+        }
+    }
+
+    fn fail(reason: DKIMError) -> Self {
+        DomainKeysResult {
+            value: reason.result(),
+            error: Some(reason),
+            canonicalization: None,
+        }
+    }
+
+    pub fn error(&self) -> Option<DKIMError> {
+        self.error.clone()
+    }
+
+    /// Returns the RFC 8601 result
+    pub fn result(&self) -> AuthenticationResult {
+        self.value
+    }
+
+    /// Returns the verification result as a summary: pass, fail, neutral,
+    /// policy, temperror or permerror.
+    pub fn summary(&self) -> &'static str {
+        self.value.as_str()
+    }
+
+    /// Returns the canonicalization of the signature that passed
+    pub fn canonicalization(&self) -> Option<Canonicalization> {
+        self.canonicalization
+    }
+
+    /// Similar to `summary` but with detail on fail
+    pub fn with_detail(&self) -> String {
+        if let Some(err) = self.error() {
+            format!("{} ({})", self.value, err)
+        } else {
+            self.value.to_string()
+        }
+    }
+}
+
+// https://datatracker.ietf.org/doc/html/rfc4870#section-3.4
+fn canonicalize_header(canonicalization: Canonicalization, header: &Header) -> Vec<u8> {
+    match canonicalization {
+        Canonicalization::Simple => crate::canonicalization::canonicalize_header_simple(
+            header.get_key_ref(),
+            header.get_value_raw(),
+        ),
+        Canonicalization::Nofws => {
+            let mut out = without_fws(header.get_key_ref().as_bytes());
+            out.push(b':');
+            out.extend(without_fws(header.get_value_raw()));
+            out.extend_from_slice(b"\r\n");
+            out
+        }
+    }
+}
+
+fn canonicalize_body(canonicalization: Canonicalization, body: &[u8]) -> Vec<u8> {
+    let mut body = match canonicalization {
+        Canonicalization::Simple => body.to_vec(),
+        Canonicalization::Nofws => body
+            .split(|&c| c == b'\n')
+            .map(|line| without_fws(line.strip_suffix(b"\r").unwrap_or(line)))
+            .collect::<Vec<_>>()
+            .join(&b"\r\n"[..]),
+    };
+    // Trailing empty lines are ignored with both canonicalizations
+    while body.ends_with(b"\r\n\r\n") {
+        body.truncate(body.len() - 2);
+    }
+    body
+}
+
+fn without_fws(value: &[u8]) -> Vec<u8> {
+    value
+        .iter()
+        .copied()
+        .filter(|c| !matches!(c, b' ' | b'\t' | b'\r' | b'\n'))
+        .collect()
+}
+
+/// Domain of the address of a From or Sender header value
+fn address_domain(value: &[u8]) -> Option<String> {
+    let value = String::from_utf8_lossy(value);
+    let address = match (value.rfind('<'), value.rfind('>')) {
+        (Some(start), Some(end)) if start < end => &value[start + 1..end],
+        _ => value.trim(),
+    };
+    let (_, domain) = address.rsplit_once('@')?;
+    Some(domain.trim().to_lowercase())
+}
+
+// https://datatracker.ietf.org/doc/html/rfc4870#section-3.5
+fn verify_header<T: dns::TimedLookup + ?Sized>(
+    resolver: &T,
+    email: &Message,
+    index: usize,
+) -> Result<Canonicalization, DKIMError> {
+    let value = String::from_utf8_lossy(email.headers[index].get_value_raw());
+    let (_, tags) =
+        parser::tag_list(&value).map_err(|err| DKIMError::SignatureSyntaxError(err.to_string()))?;
+    let get_tag = |name: &str| {
+        tags.iter()
+            .rev()
+            .find(|tag| tag.name == name)
+            .map(|tag| tag.value().into_owned())
+    };
+    let get_required_tag =
+        |name: &'static str| get_tag(name).ok_or(DKIMError::SignatureMissingRequiredTag(name));
+
+    let signature = get_required_tag("b")?;
+    let domain = get_required_tag("d")?;
+    let selector = get_required_tag("s")?;
+    if let Some(algorithm) = get_tag("a") {
+        if algorithm != RsaSha1.name() {
+            return Err(DKIMError::UnsupportedHashAlgorithm(algorithm));
+        }
+    }
+    if let Some(query_method) = get_tag("q") {
+        if query_method != "dns" {
+            return Err(DKIMError::UnsupportedQueryMethod);
+        }
+    }
+    let canonicalization = match get_tag("c").as_deref() {
+        None | Some("simple") => Canonicalization::Simple,
+        Some("nofws") => Canonicalization::Nofws,
+        Some(value) => return Err(DKIMError::UnsupportedCanonicalizationType(value.to_owned())),
+    };
+
+    // Only the headers after the signature are signed, and of them only the
+    // ones in `h=` if it's present
+    let signed_headers: Option<Vec<String>> =
+        get_tag("h").map(|value| value.split(':').map(|name| name.to_lowercase()).collect());
+    let is_signed = |header: &&Header| {
+        signed_headers.as_ref().map_or(true, |names| {
+            names.contains(&header.get_key_ref().to_lowercase())
+        })
+    };
+
+    // The sending address is the signed Sender, or else the signed From. Its
+    // domain must be the signing domain or one of its subdomains.
+    let signed_header = |name: &str| {
+        email.headers[index + 1..]
+            .iter()
+            .filter(is_signed)
+            .find(|header| header.get_key_ref().eq_ignore_ascii_case(name))
+    };
+    let sender = signed_header("Sender")
+        .or_else(|| signed_header("From"))
+        .and_then(|header| address_domain(header.get_value_raw()))
+        .ok_or(DKIMError::FromFieldNotSigned)?;
+    let domain = domain.to_lowercase();
+    if sender != domain && !sender.ends_with(&format!(".{}", domain)) {
+        return Err(DKIMError::DomainMismatch);
+    }
+
+    let mut input = vec![];
+    for header in email.headers[index + 1..].iter().filter(is_signed) {
+        input.extend(canonicalize_header(canonicalization, header));
+    }
+    input.extend_from_slice(b"\r\n");
+    input.extend(canonicalize_body(
+        canonicalization,
+        bytes::get_all_after(email.raw_bytes, b"\r\n\r\n"),
+    ));
+
+    let public_keys =
+        public_key::retrieve_public_keys(resolver, domain, selector, None, RsaSha1.key_type())?;
+    let signature = base64::decode(signature).map_err(|err| {
+        DKIMError::SignatureSyntaxError(format!("failed to decode signature: {}", err))
+    })?;
+    let digest = RsaSha1.digest(&input);
+    let mut first_error = None;
+    for public_key in &public_keys {
+        match RsaSha1.verify(public_key, &digest, &signature) {
+            Ok(true) => return Ok(canonicalization),
+            Ok(false) => {
+                first_error.get_or_insert(DKIMError::SignatureDidNotVerify);
+            }
+            Err(err) => {
+                first_error.get_or_insert(err);
+            }
+        }
+    }
+    Err(first_error.unwrap_or(DKIMError::NoKeyForSignature))
+}
+
+fn verify_inner<T: dns::TimedLookup + ?Sized>(resolver: &T, email: &Message) -> DomainKeysResult {
+    let mut last_error = None;
+    for (index, header) in email.headers.iter().enumerate() {
+        if !header.get_key_ref().eq_ignore_ascii_case(HEADER) {
+            continue;
+        }
+        match verify_header(resolver, email, index) {
+            Ok(canonicalization) => return DomainKeysResult::pass(canonicalization),
+            Err(err) => last_error = Some(err),
+        }
+    }
+
+    match last_error {
+        Some(err) => DomainKeysResult::fail(err),
+        None => DomainKeysResult::neutral(),
+    }
+}
+
+/// Verifies the DomainKey-Signature headers of the email. The result is
+/// `pass` as soon as one of them verifies, and `neutral` without any.
+#[cfg(feature = "std")]
+pub fn verify_email_with_resolver<'a, T: dns::TimedLookup>(
+    email: &'a mailparse::ParsedMail<'a>,
+    resolver: &T,
+) -> DomainKeysResult {
+    verify_inner(resolver, &email.into())
+}
+
+/// Same as [verify_email_with_resolver] on the raw bytes of an email
+pub fn verify_raw_email_with_resolver<T: dns::TimedLookup>(
+    raw_email: &[u8],
+    resolver: &T,
+) -> Result<DomainKeysResult, DKIMError> {
+    Ok(verify_inner(resolver, &Message::parse(raw_email)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys;
+    use crate::testing::{TestKey, TestResolver};
+
+    const EMAIL: &str = "Received: from gateway.example.com\r\nFrom: Joe  SixPack <joe@football.example.com>\r\nTo: Suzie Q <suzie@shopping.example.net>\r\nSubject: Is  dinner\r\n ready?\r\n\r\nHi.\r\n\r\nWe lost the  game. Are you hungry yet?\r\n\r\nJoe.\r\n\r\n\r\n";
+
+    /// Signs EMAIL, inserting the signature after the Received header
+    fn sign(key: &TestKey, tags: &str, canonicalization: Canonicalization) -> String {
+        let (received, rest) = EMAIL.split_once("\r\n").unwrap();
+        let (headers, body) = rest.split_once("\r\n\r\n").unwrap();
+        let message = Message::parse(rest.as_bytes()).unwrap();
+        let h = tags
+            .split(';')
+            .find_map(|tag| tag.trim().strip_prefix("h="))
+            .map(|value| value.to_lowercase());
+
+        let mut input = vec![];
+        for header in &message.headers {
+            let name = header.get_key_ref().to_lowercase();
+            if h.as_ref().map_or(true, |h| h.split(':').any(|n| n == name)) {
+                input.extend(canonicalize_header(canonicalization, header));
+            }
+        }
+        input.extend_from_slice(b"\r\n");
+        input.extend(canonicalize_body(canonicalization, body.as_bytes()));
+
+        let private_key = keys::private_key_from_pem(key.private_key_pem()).unwrap();
+        let signature = RsaSha1.sign(&private_key, &RsaSha1.digest(&input)).unwrap();
+        format!(
+            "{}\r\nDomainKey-Signature: a=rsa-sha1; d={}; s={}; c={}; {};\r\n  b={}\r\n{}\r\n\r\n{}",
+            received,
+            key.domain(),
+            key.selector(),
+            canonicalization,
+            tags,
+            base64::encode(signature),
+            headers,
+            body
+        )
+    }
+
+    fn verify(resolver: &TestResolver, email: &str) -> DomainKeysResult {
+        verify_raw_email_with_resolver(email.as_bytes(), resolver).unwrap()
+    }
+
+    #[test]
+    fn test_canonicalize_nofws() {
+        let message = Message::parse(EMAIL.as_bytes()).unwrap();
+        assert_eq!(
+            canonicalize_header(Canonicalization::Nofws, &message.headers[3]),
+            b"Subject:Isdinnerready?\r\n"
+        );
+        assert_eq!(
+            canonicalize_body(
+                Canonicalization::Nofws,
+                b"Hi.\r\n\r\nWe lost the  game.\t\r\n\r\n\r\n"
+            ),
+            b"Hi.\r\n\r\nWelostthegame.\r\n"
+        );
+        assert_eq!(
+            canonicalize_body(Canonicalization::Simple, b"Joe. \r\n\r\n"),
+            b"Joe. \r\n"
+        );
+    }
+
+    #[test]
+    fn test_verify() {
+        let key = TestKey::rsa("football.example.com", "brisbane");
+        let resolver = key.resolver();
+
+        let email = sign(&key, "q=dns", Canonicalization::Simple);
+        let result = verify(&resolver, &email);
+        assert_eq!(result.with_detail(), "pass");
+        assert_eq!(result.canonicalization(), Some(Canonicalization::Simple));
+        assert_eq!(
+            verify(&resolver, &email.replace("lost", "won")).error(),
+            Some(DKIMError::SignatureDidNotVerify)
+        );
+
+        // nofws survives whitespace changes
+        let email = sign(&key, "h=From:To:Subject", Canonicalization::Nofws);
+        let email = email.replace("the  game", "the game\t");
+        assert_eq!(verify(&resolver, &email).with_detail(), "pass");
+        // and headers outside of h= are not signed
+        let email = email.replace("\r\nSubject:", "\r\nCc: bob@example.net\r\nSubject:");
+        assert_eq!(verify(&resolver, &email).with_detail(), "pass");
+
+        assert_eq!(
+            verify(&resolver, "From: joe@football.example.com\r\n\r\nHi.\r\n").with_detail(),
+            "neutral"
+        );
+    }
+
+    #[test]
+    fn test_verify_errors() {
+        let key = TestKey::rsa("football.example.com", "brisbane");
+        let email = sign(&key, "q=dns", Canonicalization::Simple);
+
+        assert_eq!(
+            verify(&TestResolver::default(), &email).with_detail(),
+            "permerror (no key for signature)"
+        );
+
+        let other = TestKey::rsa("example.org", "brisbane");
+        let email = sign(&other, "q=dns", Canonicalization::Simple);
+        assert_eq!(
+            verify(&other.resolver(), &email).error(),
+            Some(DKIMError::DomainMismatch)
+        );
+
+        // A From before the signature is not signed
+        let email = format!("From: joe@example.org\r\n{}", email);
+        assert_eq!(
+            verify(&other.resolver(), &email).error(),
+            Some(DKIMError::DomainMismatch)
+        );
+        // nor is a From outside of h=
+        let email = sign(&other, "h=To:Subject", Canonicalization::Simple)
+            .replace("\r\nTo:", "\r\nFrom: joe@example.org\r\nTo:");
+        assert_eq!(
+            verify(&other.resolver(), &email).error(),
+            Some(DKIMError::FromFieldNotSigned)
+        );
+
+        let email = sign(&key, "q=dns", Canonicalization::Nofws).replace("c=nofws", "c=relaxed");
+        assert_eq!(
+            verify(&key.resolver(), &email).error(),
+            Some(DKIMError::UnsupportedCanonicalizationType(
+                "relaxed".to_owned()
+            ))
+        );
+    }
+}
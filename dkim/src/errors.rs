@@ -1,102 +1,217 @@
+use core::fmt;
+
+use crate::prelude::*;
+use crate::result::AuthenticationResult;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+#[cfg_attr(
+    feature = "borsh",
+    derive(borsh::BorshSerialize, borsh::BorshDeserialize)
+)]
 /// DKIM error status
 pub enum Status {
     Permfail,
     Tempfail,
 }
 
-quick_error! {
-    #[derive(Debug, PartialEq, Clone)]
-    /// DKIM errors
-    pub enum DKIMError {
-        UnsupportedHashAlgorithm(value: String) {
-            display("unsupported hash algorithm: {}", value)
-        }
-        UnsupportedCanonicalizationType(value: String) {
-            display("unsupported canonicalization: {}", value)
-        }
-        SignatureSyntaxError(err: String) {
-            display("signature syntax error: {}", err)
-        }
-        SignatureMissingRequiredTag(name: &'static str) {
-            display("signature missing required tag ({})", name)
-        }
-        IncompatibleVersion {
-            display("incompatible version")
-        }
-        DomainMismatch {
-            display("domain mismatch")
-        }
-        FromFieldNotSigned {
-            display("From field not signed")
-        }
-        SignatureExpired {
-            display("signature expired")
-        }
-        UnacceptableSignatureHeader {
-            display("unacceptable signature header")
-        }
-        UnsupportedQueryMethod {
-            display("unsupported query method")
-        }
-        KeyUnavailable(err: String) {
-            display("key unavailable: {}", err)
-        }
-        UnknownInternalError(err: String) {
-            display("internal error: {}", err)
-        }
-        NoKeyForSignature {
-            display("no key for signature")
-        }
-        KeySyntaxError {
-            display("key syntax error")
-        }
-        KeyIncompatibleVersion {
-            display("key incompatible version")
-        }
-        InappropriateKeyAlgorithm {
-            display("inappropriate key algorithm")
-        }
-        SignatureDidNotVerify {
-            display("signature did not verify")
-        }
-        BodyHashDidNotVerify {
-            display("body hash did not verify")
-        }
-        MalformedBody {
-            display("malformed email body")
-        }
-        FailedToSign(err: String) {
-            display("failed sign: {}", err)
-        }
-        BuilderError(err: &'static str) {
-            display("failed to build object: {}", err)
+#[derive(Debug, PartialEq, Eq, Clone)]
+/// DKIM errors
+pub enum DKIMError {
+    UnsupportedHashAlgorithm(String),
+    UnsupportedCanonicalizationType(String),
+    SignatureSyntaxError(String),
+    SignatureMissingRequiredTag(&'static str),
+    IncompatibleVersion,
+    DomainMismatch,
+    FromFieldNotSigned,
+    SignatureExpired,
+    UnacceptableSignatureHeader,
+    UnsupportedQueryMethod,
+    KeyUnavailable(String),
+    UnknownInternalError(String),
+    NoKeyForSignature,
+    KeySyntaxError,
+    KeyIncompatibleVersion,
+    InappropriateKeyAlgorithm,
+    KeyNotPinned,
+    SignatureDidNotVerify,
+    BodyHashDidNotVerify,
+    MalformedBody,
+    FailedToSign(String),
+    BuilderError(&'static str),
+    InvalidPrivateKey(String),
+    UnsupportedLookup(&'static str),
+}
+
+impl fmt::Display for DKIMError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use DKIMError::*;
+        match self {
+            UnsupportedHashAlgorithm(value) => write!(f, "unsupported hash algorithm: {}", value),
+            UnsupportedCanonicalizationType(value) => {
+                write!(f, "unsupported canonicalization: {}", value)
+            }
+            SignatureSyntaxError(err) => write!(f, "signature syntax error: {}", err),
+            SignatureMissingRequiredTag(name) => {
+                write!(f, "signature missing required tag ({})", name)
+            }
+            IncompatibleVersion => write!(f, "incompatible version"),
+            DomainMismatch => write!(f, "domain mismatch"),
+            FromFieldNotSigned => write!(f, "From field not signed"),
+            SignatureExpired => write!(f, "signature expired"),
+            UnacceptableSignatureHeader => write!(f, "unacceptable signature header"),
+            UnsupportedQueryMethod => write!(f, "unsupported query method"),
+            KeyUnavailable(err) => write!(f, "key unavailable: {}", err),
+            UnknownInternalError(err) => write!(f, "internal error: {}", err),
+            NoKeyForSignature => write!(f, "no key for signature"),
+            KeySyntaxError => write!(f, "key syntax error"),
+            KeyIncompatibleVersion => write!(f, "key incompatible version"),
+            InappropriateKeyAlgorithm => write!(f, "inappropriate key algorithm"),
+            KeyNotPinned => write!(f, "key does not match any pin"),
+            SignatureDidNotVerify => write!(f, "signature did not verify"),
+            BodyHashDidNotVerify => write!(f, "body hash did not verify"),
+            MalformedBody => write!(f, "malformed email body"),
+            FailedToSign(err) => write!(f, "failed sign: {}", err),
+            BuilderError(err) => write!(f, "failed to build object: {}", err),
+            InvalidPrivateKey(err) => write!(f, "invalid private key: {}", err),
+            UnsupportedLookup(record_type) => {
+                write!(f, "resolver does not support {} lookups", record_type)
+            }
         }
     }
 }
 
+#[cfg(feature = "std")]
+impl std::error::Error for DKIMError {}
+#[cfg(not(feature = "std"))]
+impl core::error::Error for DKIMError {}
+
 impl DKIMError {
-    pub fn status(self) -> Status {
+    /// Whether retrying later could give a different outcome
+    pub fn status(&self) -> Status {
+        match self.result() {
+            AuthenticationResult::TempError => Status::Tempfail,
+            _ => Status::Permfail,
+        }
+    }
+
+    /// The RFC 8601 result to report for a signature that failed with this
+    /// error <https://datatracker.ietf.org/doc/html/rfc8601#section-2.7.1>
+    pub fn result(&self) -> AuthenticationResult {
         use DKIMError::*;
         match self {
+            SignatureDidNotVerify | BodyHashDidNotVerify => AuthenticationResult::Fail,
+            SignatureExpired | UnacceptableSignatureHeader => AuthenticationResult::Policy,
             SignatureSyntaxError(_)
             | SignatureMissingRequiredTag(_)
             | IncompatibleVersion
             | DomainMismatch
             | FromFieldNotSigned
-            | SignatureExpired
-            | UnacceptableSignatureHeader
             | UnsupportedQueryMethod
             | NoKeyForSignature
             | KeySyntaxError
             | KeyIncompatibleVersion
             | InappropriateKeyAlgorithm
-            | SignatureDidNotVerify
-            | BodyHashDidNotVerify
+            | KeyNotPinned
             | MalformedBody
             | UnsupportedCanonicalizationType(_)
-            | UnsupportedHashAlgorithm(_) => Status::Permfail,
-            KeyUnavailable(_) | UnknownInternalError(_) => Status::Tempfail,
-            BuilderError(_) | FailedToSign(_) => unreachable!(),
+            | UnsupportedHashAlgorithm(_)
+            | BuilderError(_)
+            | InvalidPrivateKey(_)
+            | UnsupportedLookup(_) => AuthenticationResult::PermError,
+            KeyUnavailable(_) | UnknownInternalError(_) | FailedToSign(_) => {
+                AuthenticationResult::TempError
+            }
         }
     }
+
+    /// Stable numeric code of the error. Codes are grouped by family:
+    /// 1xx signature, 2xx key, 3xx verification, 4xx signing and 5xx
+    /// internal errors. A code is never reused or changed.
+    pub fn code(&self) -> u16 {
+        use DKIMError::*;
+        match self {
+            UnsupportedHashAlgorithm(_) => 101,
+            UnsupportedCanonicalizationType(_) => 102,
+            SignatureSyntaxError(_) => 103,
+            SignatureMissingRequiredTag(_) => 104,
+            IncompatibleVersion => 105,
+            DomainMismatch => 106,
+            FromFieldNotSigned => 107,
+            SignatureExpired => 108,
+            UnacceptableSignatureHeader => 109,
+            UnsupportedQueryMethod => 110,
+            KeyUnavailable(_) => 201,
+            NoKeyForSignature => 202,
+            KeySyntaxError => 203,
+            KeyIncompatibleVersion => 204,
+            InappropriateKeyAlgorithm => 205,
+            KeyNotPinned => 206,
+            SignatureDidNotVerify => 301,
+            BodyHashDidNotVerify => 302,
+            MalformedBody => 303,
+            FailedToSign(_) => 401,
+            BuilderError(_) => 402,
+            InvalidPrivateKey(_) => 403,
+            UnknownInternalError(_) => 501,
+            UnsupportedLookup(_) => 502,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status() {
+        assert_eq!(DKIMError::BodyHashDidNotVerify.status(), Status::Permfail);
+        assert_eq!(
+            DKIMError::KeyUnavailable("timeout".to_owned()).status(),
+            Status::Tempfail
+        );
+        assert_eq!(DKIMError::BuilderError("oops").status(), Status::Permfail);
+        assert_eq!(DKIMError::UnsupportedLookup("A").status(), Status::Permfail);
+        assert_eq!(
+            DKIMError::FailedToSign("oops".to_owned()).status(),
+            Status::Tempfail
+        );
+    }
+
+    #[test]
+    fn test_result() {
+        assert_eq!(
+            DKIMError::SignatureDidNotVerify.result(),
+            AuthenticationResult::Fail
+        );
+        assert_eq!(
+            DKIMError::SignatureExpired.result(),
+            AuthenticationResult::Policy
+        );
+        assert_eq!(
+            DKIMError::KeySyntaxError.result(),
+            AuthenticationResult::PermError
+        );
+        assert_eq!(
+            DKIMError::UnknownInternalError("oops".to_owned()).result(),
+            AuthenticationResult::TempError
+        );
+    }
+
+    #[test]
+    fn test_code() {
+        assert_eq!(
+            DKIMError::UnsupportedHashAlgorithm("a".to_owned()).code(),
+            101
+        );
+        assert_eq!(DKIMError::KeyUnavailable("a".to_owned()).code(), 201);
+        assert_eq!(DKIMError::BodyHashDidNotVerify.code(), 302);
+        assert_eq!(DKIMError::BuilderError("a").code(), 402);
+        assert_eq!(DKIMError::UnsupportedLookup("A").code(), 502);
+    }
 }
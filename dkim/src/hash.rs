@@ -1,48 +1,63 @@
-use std::collections::HashMap;
+use alloc::collections::BTreeMap;
 
+#[cfg(feature = "sign")]
+use crate::algorithm::SignatureAlgorithm;
 use crate::canonicalization::{
     self, canonicalize_body_relaxed, canonicalize_body_simple, canonicalize_header_relaxed,
     canonicalize_header_simple,
 };
-use crate::header::HEADER;
-use crate::{bytes, DKIMError, DKIMHeader};
-
-#[derive(Debug, Clone)]
+use crate::header::{DKIMHeader, HEADER};
+use crate::message::Message;
+use crate::prelude::*;
+use crate::{bytes, DKIMError};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "borsh",
+    derive(borsh::BorshSerialize, borsh::BorshDeserialize)
+)]
+/// Signing algorithm (the `a=` tag)
 pub enum HashAlgo {
     RsaSha1,
     RsaSha256,
     Ed25519Sha256,
+    /// An algorithm registered in an [crate::AlgorithmRegistry], by name
+    Other(String),
 }
-
-/// Get the body part of an email
-fn get_body<'a>(email: &'a mailparse::ParsedMail<'a>) -> Result<Vec<u8>, DKIMError> {
-    Ok(bytes::get_all_after(email.raw_bytes, b"\r\n\r\n").to_vec())
+impl HashAlgo {
+    /// The algorithm of an `a=` value, [HashAlgo::Other] if it isn't built in
+    pub fn from_name(name: &str) -> Self {
+        match name {
+            "rsa-sha1" => Self::RsaSha1,
+            "rsa-sha256" => Self::RsaSha256,
+            "ed25519-sha256" => Self::Ed25519Sha256,
+            name => Self::Other(name.to_owned()),
+        }
+    }
 }
-
-fn hash_sha1<T: AsRef<[u8]>>(data: T) -> Vec<u8> {
-    use sha1::{Digest, Sha1};
-
-    let mut hasher = Sha1::new();
-    hasher.update(data);
-    hasher.finalize().to_vec()
+impl core::fmt::Display for HashAlgo {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::RsaSha1 => write!(f, "rsa-sha1"),
+            Self::RsaSha256 => write!(f, "rsa-sha256"),
+            Self::Ed25519Sha256 => write!(f, "ed25519-sha256"),
+            Self::Other(name) => write!(f, "{}", name),
+        }
+    }
 }
 
-fn hash_sha256<T: AsRef<[u8]>>(data: T) -> Vec<u8> {
-    use sha2::{Digest, Sha256};
-
-    let mut hasher = Sha256::new();
-    hasher.update(data);
-    hasher.finalize().to_vec()
+/// Get the body part of an email
+fn get_body<'a>(email: &'a Message<'a>) -> Result<Vec<u8>, DKIMError> {
+    Ok(bytes::get_all_after(email.raw_bytes, b"\r\n\r\n").to_vec())
 }
 
-/// Returns the hash of message's body
-/// https://datatracker.ietf.org/doc/html/rfc6376#section-3.7
-pub(crate) fn compute_body_hash<'a>(
+/// Returns the message's body canonicalized and truncated to `length`, as it
+/// is fed to the body hash
+pub(crate) fn canonicalize_body<'a>(
     canonicalization_type: canonicalization::Type,
     length: Option<String>,
-    hash_algo: HashAlgo,
-    email: &'a mailparse::ParsedMail<'a>,
-) -> Result<String, DKIMError> {
+    email: &'a Message<'a>,
+) -> Result<Vec<u8>, DKIMError> {
     let body = get_body(email)?;
 
     let mut canonicalized_body = if canonicalization_type == canonicalization::Type::Simple {
@@ -57,26 +72,33 @@ pub(crate) fn compute_body_hash<'a>(
         canonicalized_body.truncate(length);
     };
 
-    let hash = match hash_algo {
-        HashAlgo::RsaSha1 => hash_sha1(&canonicalized_body),
-        HashAlgo::RsaSha256 => hash_sha256(&canonicalized_body),
-        HashAlgo::Ed25519Sha256 => hash_sha256(&canonicalized_body),
-    };
-    Ok(base64::encode(&hash))
+    Ok(canonicalized_body)
 }
 
-fn select_headers<'a, 'b>(
-    dkim_header: &'b str,
-    email: &'a mailparse::ParsedMail<'a>,
-) -> Result<Vec<(String, &'a [u8])>, DKIMError> {
-    let mut signed_headers = vec![];
+/// Returns the hash of message's body
+#[cfg(feature = "sign")]
+/// https://datatracker.ietf.org/doc/html/rfc6376#section-3.7
+pub(crate) fn compute_body_hash<'a>(
+    canonicalization_type: canonicalization::Type,
+    length: Option<String>,
+    algorithm: &dyn SignatureAlgorithm,
+    email: &'a Message<'a>,
+) -> Result<String, DKIMError> {
+    let canonicalized_body = canonicalize_body(canonicalization_type, length, email)?;
+    Ok(base64::encode(algorithm.digest(&canonicalized_body)))
+}
+
+/// Returns the index in `email.headers` of each header instance selected by
+/// the `h=` list, in signing order
+pub(crate) fn select_header_indices(dkim_header: &str, email: &Message) -> Vec<usize> {
+    let mut indices = vec![];
 
     let email_headers = &email.headers;
     let num_headers = email_headers.len();
-    let mut last_index: HashMap<String, usize> = HashMap::new();
+    let mut last_index: BTreeMap<String, usize> = BTreeMap::new();
 
     'outer: for name in dkim_header
-        .split(":")
+        .split(':')
         .map(|h| h.trim().to_ascii_lowercase())
     {
         let index = last_index.get(&name).unwrap_or(&num_headers);
@@ -87,7 +109,7 @@ fn select_headers<'a, 'b>(
             .skip(num_headers - index)
         {
             if header.1.get_key_ref().eq_ignore_ascii_case(&name) {
-                signed_headers.push((header.1.get_key(), header.1.get_value_raw()));
+                indices.push(header.0);
                 last_index.insert(name, header.0);
                 continue 'outer;
             }
@@ -96,24 +118,35 @@ fn select_headers<'a, 'b>(
         last_index.insert(name, 0);
     }
 
-    Ok(signed_headers)
+    indices
 }
 
-pub(crate) fn compute_headers_hash<'a, 'b>(
+fn select_headers<'a>(
+    dkim_header: &str,
+    email: &'a Message<'a>,
+) -> Result<Vec<(String, &'a [u8])>, DKIMError> {
+    Ok(select_header_indices(dkim_header, email)
+        .into_iter()
+        .map(|i| (email.headers[i].get_key(), email.headers[i].get_value_raw()))
+        .collect())
+}
+
+/// Returns the canonicalized headers, followed by the DKIM-Signature itself
+/// without its signature, as they are fed to the header hash
+pub(crate) fn canonicalize_headers<'a, 'b>(
     canonicalization_type: canonicalization::Type,
     headers: &'b str,
-    hash_algo: HashAlgo,
     dkim_header: &'b DKIMHeader,
-    email: &'a mailparse::ParsedMail<'a>,
+    email: &'a Message<'a>,
 ) -> Result<Vec<u8>, DKIMError> {
     let mut input = Vec::new();
 
     // Add the headers defined in `h=` in the hash
     for (key, value) in select_headers(headers, email)? {
         let canonicalized_value = if canonicalization_type == canonicalization::Type::Simple {
-            canonicalize_header_simple(&key, &value)
+            canonicalize_header_simple(&key, value)
         } else {
-            canonicalize_header_relaxed(&key, &value)
+            canonicalize_header_relaxed(&key, value)
         };
         input.extend_from_slice(&canonicalized_value);
     }
@@ -122,11 +155,11 @@ pub(crate) fn compute_headers_hash<'a, 'b>(
     // signature (b) first.
     {
         let sign = dkim_header.get_raw_tag("b").unwrap();
-        let value = dkim_header.raw_bytes.replace(&sign, "");
+        let value = dkim_header.raw_bytes.replace(sign, "");
         let mut canonicalized_value = if canonicalization_type == canonicalization::Type::Simple {
-            canonicalize_header_simple(HEADER, &value.as_bytes())
+            canonicalize_header_simple(HEADER, value.as_bytes())
         } else {
-            canonicalize_header_relaxed(HEADER, &value.as_bytes())
+            canonicalize_header_relaxed(HEADER, value.as_bytes())
         };
 
         // remove trailing "\r\n"
@@ -135,25 +168,33 @@ pub(crate) fn compute_headers_hash<'a, 'b>(
         input.extend_from_slice(&canonicalized_value);
     }
 
-    let hash = match hash_algo {
-        HashAlgo::RsaSha1 => hash_sha1(&input),
-        HashAlgo::RsaSha256 => hash_sha256(&input),
-        HashAlgo::Ed25519Sha256 => hash_sha256(&input),
-    };
-    Ok(hash)
+    Ok(input)
+}
+
+#[cfg(feature = "sign")]
+pub(crate) fn compute_headers_hash<'a, 'b>(
+    canonicalization_type: canonicalization::Type,
+    headers: &'b str,
+    algorithm: &dyn SignatureAlgorithm,
+    dkim_header: &'b DKIMHeader,
+    email: &'a Message<'a>,
+) -> Result<Vec<u8>, DKIMError> {
+    let input = canonicalize_headers(canonicalization_type, headers, dkim_header, email)?;
+    Ok(algorithm.digest(&input))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::algorithm::{RsaSha1, RsaSha256};
 
-    fn dkim_header() -> DKIMHeader {
-        crate::validate_header("v=1; a=rsa-sha256; q=dns/txt; c=relaxed/relaxed; s=smtp; d=test.com; t=1641506955; h=content-type:to: subject:date:from:mime-version:sender; bh=PU2XIErWsXvhvt1W96ntPWZ2VImjVZ3vBY2T/A+wA3A=; b=PIO0A014nyntOGKdTdtvCJor9ZxvP1M3hoLeEh8HqZ+RvAyEKdAc7VOg+/g/OTaZgsmw6U sZCoN0YNVp+2o9nkaeUslsVz3M4I55HcZnarxl+fhplIMcJ/3s0nIhXL51MfGPRqPbB7/M Gjg9/07/2vFoid6Kitg6Z+CfoD2wlSRa8xDfmeyA2cHpeVuGQhGxu7BXuU8kGbeM4+weit Ql3t9zalhikEPI5Pr7dzYFrgWNOEO6w6rQfG7niKON1BimjdbJlGanC7cO4UL361hhXT4X iXLnC9TG39xKFPT/+4nkHy8pp6YvWkD3wKlBjwkYNm0JvKGwTskCMDeTwxXhAg==").unwrap()
+    fn dkim_header() -> DKIMHeader<'static> {
+        crate::validate_header("v=1; a=rsa-sha256; q=dns/txt; c=relaxed/relaxed; s=smtp; d=test.com; t=1641506955; h=content-type:to: subject:date:from:mime-version:sender; bh=PU2XIErWsXvhvt1W96ntPWZ2VImjVZ3vBY2T/A+wA3A=; b=PIO0A014nyntOGKdTdtvCJor9ZxvP1M3hoLeEh8HqZ+RvAyEKdAc7VOg+/g/OTaZgsmw6U sZCoN0YNVp+2o9nkaeUslsVz3M4I55HcZnarxl+fhplIMcJ/3s0nIhXL51MfGPRqPbB7/M Gjg9/07/2vFoid6Kitg6Z+CfoD2wlSRa8xDfmeyA2cHpeVuGQhGxu7BXuU8kGbeM4+weit Ql3t9zalhikEPI5Pr7dzYFrgWNOEO6w6rQfG7niKON1BimjdbJlGanC7cO4UL361hhXT4X iXLnC9TG39xKFPT/+4nkHy8pp6YvWkD3wKlBjwkYNm0JvKGwTskCMDeTwxXhAg==", chrono::Utc::now()).unwrap()
     }
 
     #[test]
     fn test_compute_body_hash_simple() {
-        let email = mailparse::parse_mail(
+        let email = Message::parse(
             r#"To: test@sauleau.com
 Subject: subject
 From: Sven Sauleau <sven@cloudflare.com>
@@ -166,7 +207,7 @@ Hello Alice
 
         let canonicalization_type = canonicalization::Type::Simple;
         let length = None;
-        let hash_algo = HashAlgo::RsaSha1;
+        let hash_algo = &RsaSha1;
         assert_eq!(
             compute_body_hash(
                 canonicalization_type.clone(),
@@ -177,7 +218,7 @@ Hello Alice
             .unwrap(),
             "uoq1oCgLlTqpdDX/iUbLy7J1Wic="
         );
-        let hash_algo = HashAlgo::RsaSha256;
+        let hash_algo = &RsaSha256;
         assert_eq!(
             compute_body_hash(canonicalization_type, length, hash_algo, &email).unwrap(),
             "frcCV1k9oG9oKj3dpUqdJg1PxRT2RSN/XKdLCPjaYaY="
@@ -186,7 +227,7 @@ Hello Alice
 
     #[test]
     fn test_compute_body_hash_relaxed() {
-        let email = mailparse::parse_mail(
+        let email = Message::parse(
             r#"To: test@sauleau.com
 Subject: subject
 From: Sven Sauleau <sven@cloudflare.com>
@@ -199,7 +240,7 @@ Hello Alice
 
         let canonicalization_type = canonicalization::Type::Relaxed;
         let length = None;
-        let hash_algo = HashAlgo::RsaSha1;
+        let hash_algo = &RsaSha1;
         assert_eq!(
             compute_body_hash(
                 canonicalization_type.clone(),
@@ -210,7 +251,7 @@ Hello Alice
             .unwrap(),
             "2jmj7l5rSw0yVb/vlWAYkK/YBwk="
         );
-        let hash_algo = HashAlgo::RsaSha256;
+        let hash_algo = &RsaSha256;
         assert_eq!(
             compute_body_hash(canonicalization_type, length, hash_algo, &email).unwrap(),
             "47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU="
@@ -219,7 +260,7 @@ Hello Alice
 
     #[test]
     fn test_compute_body_hash_length() {
-        let email = mailparse::parse_mail(
+        let email = Message::parse(
             r#"To: test@sauleau.com
 Subject: subject
 From: Sven Sauleau <sven@cloudflare.com>
@@ -232,7 +273,7 @@ Hello Alice
 
         let canonicalization_type = canonicalization::Type::Relaxed;
         let length = Some("3".to_owned());
-        let hash_algo = HashAlgo::RsaSha1;
+        let hash_algo = &RsaSha1;
         assert_eq!(
             compute_body_hash(
                 canonicalization_type.clone(),
@@ -243,7 +284,7 @@ Hello Alice
             .unwrap(),
             "2jmj7l5rSw0yVb/vlWAYkK/YBwk="
         );
-        let hash_algo = HashAlgo::RsaSha256;
+        let hash_algo = &RsaSha256;
         assert_eq!(
             compute_body_hash(canonicalization_type, length.clone(), hash_algo, &email).unwrap(),
             "47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU="
@@ -252,11 +293,11 @@ Hello Alice
 
     #[test]
     fn test_compute_body_hash_empty_simple() {
-        let email = mailparse::parse_mail(&[]).unwrap();
+        let email = Message::parse(&[]).unwrap();
 
         let canonicalization_type = canonicalization::Type::Simple;
         let length = None;
-        let hash_algo = HashAlgo::RsaSha1;
+        let hash_algo = &RsaSha1;
         assert_eq!(
             compute_body_hash(
                 canonicalization_type.clone(),
@@ -267,7 +308,7 @@ Hello Alice
             .unwrap(),
             "uoq1oCgLlTqpdDX/iUbLy7J1Wic="
         );
-        let hash_algo = HashAlgo::RsaSha256;
+        let hash_algo = &RsaSha256;
         assert_eq!(
             compute_body_hash(canonicalization_type, length.clone(), hash_algo, &email).unwrap(),
             "frcCV1k9oG9oKj3dpUqdJg1PxRT2RSN/XKdLCPjaYaY="
@@ -276,11 +317,11 @@ Hello Alice
 
     #[test]
     fn test_compute_body_hash_empty_relaxed() {
-        let email = mailparse::parse_mail(&[]).unwrap();
+        let email = Message::parse(&[]).unwrap();
 
         let canonicalization_type = canonicalization::Type::Relaxed;
         let length = None;
-        let hash_algo = HashAlgo::RsaSha1;
+        let hash_algo = &RsaSha1;
         assert_eq!(
             compute_body_hash(
                 canonicalization_type.clone(),
@@ -291,7 +332,7 @@ Hello Alice
             .unwrap(),
             "2jmj7l5rSw0yVb/vlWAYkK/YBwk="
         );
-        let hash_algo = HashAlgo::RsaSha256;
+        let hash_algo = &RsaSha256;
         assert_eq!(
             compute_body_hash(canonicalization_type, length.clone(), hash_algo, &email).unwrap(),
             "47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU="
@@ -300,7 +341,7 @@ Hello Alice
 
     #[test]
     fn test_compute_headers_hash_simple() {
-        let email = mailparse::parse_mail(
+        let email = Message::parse(
             r#"To: test@sauleau.com
 Subject: subject
 From: Sven Sauleau <sven@cloudflare.com>
@@ -312,7 +353,7 @@ Hello Alice
         .unwrap();
 
         let canonicalization_type = canonicalization::Type::Simple;
-        let hash_algo = HashAlgo::RsaSha1;
+        let hash_algo = &RsaSha1;
         let headers = "To: Subject".to_owned();
         assert_eq!(
             compute_headers_hash(
@@ -328,7 +369,7 @@ Hello Alice
                 166, 229
             ],
         );
-        let hash_algo = HashAlgo::RsaSha256;
+        let hash_algo = &RsaSha256;
         assert_eq!(
             compute_headers_hash(
                 canonicalization_type.clone(),
@@ -347,7 +388,7 @@ Hello Alice
 
     #[test]
     fn test_compute_headers_hash_relaxed() {
-        let email = mailparse::parse_mail(
+        let email = Message::parse(
             r#"To: test@sauleau.com
 Subject: subject
 From: Sven Sauleau <sven@cloudflare.com>
@@ -359,7 +400,7 @@ Hello Alice
         .unwrap();
 
         let canonicalization_type = canonicalization::Type::Relaxed;
-        let hash_algo = HashAlgo::RsaSha1;
+        let hash_algo = &RsaSha1;
         let headers = "To: Subject".to_owned();
         assert_eq!(
             compute_headers_hash(
@@ -375,7 +416,7 @@ Hello Alice
                 44, 164
             ]
         );
-        let hash_algo = HashAlgo::RsaSha256;
+        let hash_algo = &RsaSha256;
         assert_eq!(
             compute_headers_hash(
                 canonicalization_type.clone(),
@@ -394,8 +435,7 @@ Hello Alice
 
     #[test]
     fn test_get_body() {
-        let email =
-            mailparse::parse_mail("Subject: A\r\n\r\nContent\n.hi\n.hello..".as_bytes()).unwrap();
+        let email = Message::parse("Subject: A\r\n\r\nContent\n.hi\n.hello..".as_bytes()).unwrap();
         assert_eq!(
             String::from_utf8_lossy(&get_body(&email).unwrap()),
             "Content\n.hi\n.hello..".to_owned()
@@ -405,10 +445,9 @@ Hello Alice
     #[test]
     fn test_select_headers() {
         let dkim_headers1 = ["from", "subject", "to", "from"].join(":");
-        let email1 = mailparse::parse_mail(
-            b"from: biz\r\nfoo: bar\r\nfrom: baz\r\nsubject: boring\r\n\r\ntest",
-        )
-        .unwrap();
+        let email1 =
+            Message::parse(b"from: biz\r\nfoo: bar\r\nfrom: baz\r\nsubject: boring\r\n\r\ntest")
+                .unwrap();
 
         let result1 = select_headers(&dkim_headers1, &email1).unwrap();
         assert_eq!(
@@ -422,7 +461,7 @@ Hello Alice
 
         let dkim_headers2 = ["from", "subject", "to", "from"].join(":");
         let email2 =
-            mailparse::parse_mail(b"From: biz\r\nFoo: bar\r\nSubject: Boring\r\n\r\ntest").unwrap();
+            Message::parse(b"From: biz\r\nFoo: bar\r\nSubject: Boring\r\n\r\ntest").unwrap();
 
         let result2 = select_headers(&dkim_headers2, &email2).unwrap();
         assert_eq!(
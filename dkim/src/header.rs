@@ -1,38 +1,66 @@
-use crate::{parser, DKIMError};
-use indexmap::map::IndexMap;
+use alloc::borrow::Cow;
+
+use crate::parser;
+use crate::prelude::*;
+use crate::DKIMError;
 
 pub(crate) const HEADER: &str = "DKIM-Signature";
 pub(crate) const REQUIRED_TAGS: &[&str] = &["v", "a", "b", "bh", "d", "h", "s"];
 
+/// The tags of a DKIM-Signature header, borrowed from its value
 #[derive(Debug, Clone)]
-pub struct DKIMHeader {
-    pub(crate) tags: IndexMap<String, parser::Tag>,
-    pub(crate) raw_bytes: String,
+pub struct DKIMHeader<'a> {
+    /// Tags in order of first appearance
+    pub(crate) tags: Vec<parser::Tag<'a>>,
+    pub(crate) raw_bytes: &'a str,
 }
 
-impl DKIMHeader {
-    pub(crate) fn get_tag(&self, name: &str) -> Option<String> {
-        self.tags.get(name).map(|v| v.value.clone())
+impl<'a> DKIMHeader<'a> {
+    /// Parses the tags of a header value. A repeated tag makes the whole
+    /// header invalid <https://datatracker.ietf.org/doc/html/rfc6376#section-3.2>.
+    pub(crate) fn parse(value: &'a str) -> Result<Self, DKIMError> {
+        let (_, tags) = parser::tag_list(value)
+            .map_err(|err| DKIMError::SignatureSyntaxError(err.to_string()))?;
+        if let Some(name) = parser::duplicate_tag(&tags) {
+            return Err(DKIMError::SignatureSyntaxError(format!(
+                "duplicate tag {}=",
+                name
+            )));
+        }
+        Ok(DKIMHeader {
+            tags,
+            raw_bytes: value,
+        })
     }
 
-    pub(crate) fn get_raw_tag(&self, name: &str) -> Option<String> {
-        self.tags.get(name).map(|v| v.raw_value.clone())
+    pub(crate) fn find_tag(&self, name: &str) -> Option<&parser::Tag<'a>> {
+        self.tags.iter().find(|tag| tag.name == name)
     }
 
-    pub(crate) fn get_required_tag(&self, name: &str) -> String {
+    #[cfg(feature = "verify")]
+    pub(crate) fn get_tag(&self, name: &str) -> Option<Cow<'a, str>> {
+        self.find_tag(name).map(|tag| tag.value())
+    }
+
+    pub(crate) fn get_raw_tag(&self, name: &str) -> Option<&'a str> {
+        self.find_tag(name).map(|tag| tag.raw_value)
+    }
+
+    pub(crate) fn get_required_tag(&self, name: &str) -> Cow<'a, str> {
         // Required tags are guaranteed by the parser to be present so it's safe
         // to assert and unwrap.
         debug_assert!(REQUIRED_TAGS.contains(&name));
-        self.tags.get(name).unwrap().value.clone()
+        self.find_tag(name).unwrap().value()
     }
 }
 
 /// Generate the DKIM-Signature header from the tags
-fn serialize(header: DKIMHeader) -> String {
+#[cfg(feature = "sign")]
+fn serialize(tags: &[(String, String)]) -> String {
     let mut out = "".to_owned();
 
-    for (key, tag) in &header.tags {
-        out += &format!("{}={};", key, tag.value);
+    for (name, value) in tags {
+        out += &format!("{}={};", name, value);
         out += " ";
     }
 
@@ -40,33 +68,95 @@ fn serialize(header: DKIMHeader) -> String {
     out
 }
 
+/// Splits a tag value into the pieces between which folding whitespace may
+/// be inserted without changing its meaning.
+fn fold_points<'a>(name: &str, value: &'a str) -> Vec<&'a str> {
+    match name {
+        // base64 values ignore whitespace entirely
+        "b" | "bh" => value
+            .char_indices()
+            .map(|(i, c)| &value[i..i + c.len_utf8()])
+            .collect(),
+        "h" => value.split_inclusive(':').collect(),
+        "z" => value.split_inclusive('|').collect(),
+        _ => vec![value],
+    }
+}
+
+/// Generate a header line from a list of tags, folded so that lines don't
+/// exceed `width` columns when possible.
+/// <https://datatracker.ietf.org/doc/html/rfc5322#section-2.2.3>
+pub(crate) fn fold_tags(header_name: &str, tags: &[(&str, &str)], width: usize) -> String {
+    let mut out = format!("{}:", header_name);
+    let mut line_len = out.len();
+
+    for (name, value) in tags {
+        let pieces = fold_points(name, value);
+        // `name=`, the first piece and the `;` if nothing follows it
+        let head_len = name.len()
+            + 1
+            + pieces.first().map(|p| p.len()).unwrap_or_default()
+            + if pieces.len() <= 1 { 1 } else { 0 };
+        if line_len + 1 + head_len > width && line_len > 1 {
+            out += "\r\n";
+            line_len = 0;
+        }
+        out += " ";
+        out += name;
+        out += "=";
+        line_len += name.len() + 2;
+
+        let last = pieces.len().saturating_sub(1);
+        for (i, piece) in pieces.iter().enumerate() {
+            let needed = piece.len() + if i == last { 1 } else { 0 };
+            if i > 0 && line_len + needed > width {
+                out += "\r\n ";
+                line_len = 1;
+            }
+            out += piece;
+            line_len += piece.len();
+        }
+        out += ";";
+        line_len += 1;
+    }
+
+    out
+}
+
+#[cfg(feature = "sign")]
 #[derive(Clone)]
 pub(crate) struct DKIMHeaderBuilder {
-    header: DKIMHeader,
+    /// Tags in order of first appearance
+    tags: Vec<(String, String)>,
     time: Option<chrono::DateTime<chrono::offset::Utc>>,
 }
+#[cfg(feature = "sign")]
 impl DKIMHeaderBuilder {
     pub(crate) fn new() -> Self {
         DKIMHeaderBuilder {
-            header: DKIMHeader {
-                tags: IndexMap::new(),
-                raw_bytes: "".to_owned(),
-            },
+            tags: vec![],
             time: None,
         }
     }
 
+    /// Adds a tag, or replaces the value of the tag with the same name in
+    /// place
     pub(crate) fn add_tag(mut self, name: &str, value: &str) -> Self {
-        let tag = parser::Tag {
-            name: name.to_owned(),
-            value: value.to_owned(),
-            raw_value: value.to_owned(),
-        };
-        self.header.tags.insert(name.to_owned(), tag);
+        match self.tags.iter_mut().find(|(n, _)| n == name) {
+            Some((_, existing)) => *existing = value.to_owned(),
+            None => self.tags.push((name.to_owned(), value.to_owned())),
+        }
 
         self
     }
 
+    pub(crate) fn tags(&self) -> Vec<(&str, &str)> {
+        self.tags
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect()
+    }
+
     pub(crate) fn set_signed_headers(self, headers: &[&str]) -> Self {
         let headers: Vec<String> = headers.iter().map(|h| h.to_lowercase()).collect();
         let value = headers.join(":");
@@ -86,9 +176,9 @@ impl DKIMHeaderBuilder {
         self.add_tag("t", &time.timestamp().to_string())
     }
 
-    pub(crate) fn build(mut self) -> Result<DKIMHeader, DKIMError> {
-        self.header.raw_bytes = serialize(self.header.clone());
-        Ok(self.header)
+    /// The value of the header, on a single line
+    pub(crate) fn build(self) -> Result<String, DKIMError> {
+        Ok(serialize(&self.tags))
     }
 }
 
@@ -103,7 +193,7 @@ mod tests {
             .add_tag("a", "something")
             .build()
             .unwrap();
-        assert_eq!(header.raw_bytes, "v=1; a=something;".to_owned());
+        assert_eq!(header, "v=1; a=something;".to_owned());
     }
 
     #[test]
@@ -113,10 +203,7 @@ mod tests {
             .set_signed_headers(&["header1", "header2", "header3"])
             .build()
             .unwrap();
-        assert_eq!(
-            header.raw_bytes,
-            "v=2; h=header1:header2:header3;".to_owned()
-        );
+        assert_eq!(header, "v=2; h=header1:header2:header3;".to_owned());
     }
 
     #[test]
@@ -131,6 +218,32 @@ mod tests {
             .unwrap()
             .build()
             .unwrap();
-        assert_eq!(header.raw_bytes, "t=1609459201; x=1609470001;".to_owned());
+        assert_eq!(header, "t=1609459201; x=1609470001;".to_owned());
+    }
+
+    #[test]
+    fn test_dkim_header_parse_duplicate() {
+        assert_eq!(
+            DKIMHeader::parse("v=1; d=example.com; s=a; d=evil.example").unwrap_err(),
+            DKIMError::SignatureSyntaxError("duplicate tag d=".to_owned())
+        );
+        let header = DKIMHeader::parse("v=1; d=example.com; s=a").unwrap();
+        assert_eq!(header.get_raw_tag("d"), Some("example.com"));
+    }
+
+    #[test]
+    fn test_fold_tags() {
+        assert_eq!(
+            fold_tags("H", &[("a", "1"), ("h", "from:to:subject")], 78),
+            "H: a=1; h=from:to:subject;"
+        );
+        assert_eq!(
+            fold_tags("H", &[("a", "12345"), ("h", "from:to:subject")], 16),
+            "H: a=12345;\r\n h=from:to:\r\n subject;"
+        );
+        assert_eq!(
+            fold_tags("H", &[("b", "abcdefghij")], 8),
+            "H: b=abc\r\n defghi\r\n j;"
+        );
     }
 }
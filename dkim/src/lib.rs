@@ -1,78 +1,127 @@
 // Implementation of DKIM: https://datatracker.ietf.org/doc/html/rfc6376
-
-use indexmap::map::IndexMap;
-use rsa::PublicKey;
+#![cfg_attr(not(feature = "std"), no_std)]
+// Without `verify` and `sign` only the model types are left
+#![cfg_attr(
+    not(any(feature = "verify", feature = "sign")),
+    allow(dead_code, unused_imports)
+)]
+
+extern crate alloc;
+
+#[cfg(feature = "verify")]
+use alloc::borrow::Cow;
+#[cfg(feature = "verify")]
+use alloc::sync::Arc;
+#[cfg(feature = "verify")]
+use prelude::*;
+#[cfg(feature = "rsa")]
 use rsa::RsaPrivateKey;
-use rsa::RsaPublicKey;
-use std::collections::HashSet;
-
-use mailparse::MailHeaderMap;
-
-#[macro_use]
-extern crate quick_error;
 
+#[cfg(any(feature = "verify", feature = "sign"))]
+pub mod algorithm;
+#[cfg(feature = "sign")]
+mod backend;
+#[cfg(all(feature = "verify", feature = "std"))]
+pub mod batch;
 mod bytes;
 pub mod canonicalization;
+#[cfg(feature = "verify")]
+pub mod dmarc;
+#[cfg(feature = "verify")]
 pub mod dns;
+#[cfg(feature = "domainkeys")]
+pub mod domainkeys;
 mod errors;
 mod hash;
 mod header;
+#[cfg(feature = "keys")]
+pub mod keys;
+mod message;
+#[cfg(all(feature = "verify", feature = "std"))]
+pub mod mime;
 mod parser;
+#[cfg(feature = "verify")]
 mod public_key;
 mod result;
-#[cfg(test)]
+#[cfg(all(test, feature = "sign", feature = "verify", feature = "rsa"))]
 mod roundtrip_test;
+#[cfg(any(feature = "serde", feature = "borsh"))]
+mod serialization;
+#[cfg(feature = "sign")]
 mod sign;
-
-pub use errors::DKIMError;
+mod signature;
+#[cfg(feature = "verify")]
+pub mod spf;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+#[cfg(feature = "verify")]
+pub mod trace;
+
+#[cfg(feature = "verify")]
+use algorithm::PublicKey;
+#[cfg(any(feature = "verify", feature = "sign"))]
+pub use algorithm::{AlgorithmRegistry, SignatureAlgorithm};
+#[cfg(all(feature = "sign", any(test, feature = "testing")))]
+pub use backend::MockSigningBackend;
+#[cfg(feature = "sign")]
+pub use backend::SigningBackend;
+pub use errors::{DKIMError, Status};
+pub use hash::HashAlgo;
+#[cfg(feature = "verify")]
 use header::{DKIMHeader, HEADER, REQUIRED_TAGS};
+#[cfg(feature = "verify")]
+use message::Message;
 pub use parser::tag_list as parse_tag_list;
 pub use parser::Tag;
-pub use result::DKIMResult;
-pub use sign::{Signer, SignerBuilder};
+pub use result::{AuthenticationResult, DKIMResult, SignatureResult};
+#[cfg(feature = "sign")]
+pub use sign::{MultiSigner, Signer, SignerBuilder};
+pub use signature::DkimSignature;
+#[cfg(feature = "verify")]
+use trace::{CopiedHeaderDiff, SelectedHeader, SignatureTrace};
+
+#[cfg(all(
+    any(feature = "verify", feature = "sign"),
+    not(any(feature = "rsa", feature = "ed25519"))
+))]
+compile_error!("`verify` and `sign` need at least one of the `rsa` and `ed25519` features");
+
+/// Types of `alloc` that are in the prelude of `std`
+mod prelude {
+    pub(crate) use alloc::borrow::ToOwned;
+    pub(crate) use alloc::format;
+    pub(crate) use alloc::string::{String, ToString};
+    pub(crate) use alloc::vec;
+    pub(crate) use alloc::vec::Vec;
+}
 
+#[cfg(feature = "verify")]
 const SIGN_EXPIRATION_DRIFT_MINS: i64 = 15;
+#[cfg(feature = "verify")]
 const DNS_NAMESPACE: &str = "_domainkey";
 
-#[derive(Debug)]
-pub(crate) enum DkimPublicKey {
-    Rsa(RsaPublicKey),
-    Ed25519(ed25519_dalek::PublicKey),
-}
-
 #[derive(Debug)]
 pub enum DkimPrivateKey {
+    #[cfg(feature = "rsa")]
     Rsa(RsaPrivateKey),
+    #[cfg(feature = "ed25519")]
     Ed25519(ed25519_dalek::Keypair),
 }
 
 // https://datatracker.ietf.org/doc/html/rfc6376#section-6.1.1
-fn validate_header<'a>(value: &'a str) -> Result<DKIMHeader, DKIMError> {
-    let (_, tags) =
-        parser::tag_list(value).map_err(|err| DKIMError::SignatureSyntaxError(err.to_string()))?;
+#[cfg(feature = "verify")]
+fn validate_header(
+    value: &str,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Result<DKIMHeader<'_>, DKIMError> {
+    let header = DKIMHeader::parse(value)?;
 
     // Check presence of required tags
-    {
-        let mut tag_names: HashSet<String> = HashSet::new();
-        for tag in &tags {
-            tag_names.insert(tag.name.clone());
+    for required in REQUIRED_TAGS {
+        if header.find_tag(required).is_none() {
+            return Err(DKIMError::SignatureMissingRequiredTag(required));
         }
-        for required in REQUIRED_TAGS {
-            if tag_names.get(*required).is_none() {
-                return Err(DKIMError::SignatureMissingRequiredTag(required));
-            }
-        }
-    }
-
-    let mut tags_map = IndexMap::new();
-    for tag in &tags {
-        tags_map.insert(tag.name.clone(), tag.clone());
     }
-    let header = DKIMHeader {
-        tags: tags_map,
-        raw_bytes: value.to_owned(),
-    };
-    // FIXME: we could get the keys instead of generating tag_names ourselves
 
     // Check version
     {
@@ -87,7 +136,7 @@ fn validate_header<'a>(value: &'a str) -> Result<DKIMHeader, DKIMError> {
     if let Some(user) = header.get_tag("i") {
         let signing_domain = header.get_required_tag("d");
         // TODO: naive check, should switch to parsing the domains/email
-        if !user.ends_with(&signing_domain) {
+        if !user.ends_with(&*signing_domain) {
             return Err(DKIMError::DomainMismatch);
         }
     }
@@ -95,7 +144,7 @@ fn validate_header<'a>(value: &'a str) -> Result<DKIMHeader, DKIMError> {
     // Check that "h=" tag includes the From header
     {
         let value = header.get_required_tag("h");
-        let headers = value.split(":");
+        let headers = value.split(':');
         let headers: Vec<String> = headers.map(|h| h.to_lowercase()).collect();
         if !headers.contains(&"from".to_string()) {
             return Err(DKIMError::FromFieldNotSigned);
@@ -114,10 +163,9 @@ fn validate_header<'a>(value: &'a str) -> Result<DKIMHeader, DKIMError> {
             expiration.parse::<i64>().unwrap_or_default(),
             0,
         )
-        .ok_or_else(|| DKIMError::MalformedBody)?;
+        .ok_or(DKIMError::MalformedBody)?;
         expiration += chrono::Duration::minutes(SIGN_EXPIRATION_DRIFT_MINS);
-        let now = chrono::Utc::now().naive_utc();
-        if now > expiration {
+        if now.naive_utc() > expiration {
             return Err(DKIMError::SignatureExpired);
         }
     }
@@ -125,106 +173,201 @@ fn validate_header<'a>(value: &'a str) -> Result<DKIMHeader, DKIMError> {
     Ok(header)
 }
 
-// https://datatracker.ietf.org/doc/html/rfc6376#section-6.1.3 Step 4
-fn verify_signature(
-    hash_algo: hash::HashAlgo,
-    header_hash: Vec<u8>,
-    signature: Vec<u8>,
-    public_key: DkimPublicKey,
-) -> Result<bool, DKIMError> {
-    Ok(match public_key {
-        DkimPublicKey::Rsa(public_key) => public_key
-            .verify(
-                rsa::PaddingScheme::PKCS1v15Sign {
-                    hash: Some(match hash_algo {
-                        hash::HashAlgo::RsaSha1 => rsa::hash::Hash::SHA1,
-                        hash::HashAlgo::RsaSha256 => rsa::hash::Hash::SHA2_256,
-                        hash => {
-                            return Err(DKIMError::UnsupportedHashAlgorithm(format!("{:?}", hash)))
-                        }
-                    }),
-                },
-                &header_hash,
-                &signature,
-            )
-            .is_ok(),
-        DkimPublicKey::Ed25519(public_key) => public_key
-            .verify_strict(
-                &header_hash,
-                &ed25519_dalek::Signature::from_bytes(&signature)
-                    .map_err(|err| DKIMError::SignatureSyntaxError(err.to_string()))?,
-            )
-            .is_ok(),
-    })
+/// The checks of a DKIM-Signature done before its key is looked up, for the
+/// `tag_list` benchmark
+#[cfg(feature = "verify")]
+#[doc(hidden)]
+pub fn bench_validate_header(
+    value: &str,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Result<(), DKIMError> {
+    validate_header(value, now).map(|_| ())
 }
 
-fn verify_email_header<'a, T: dns::Lookup>(
-    resolver: &T,
+/// The signature timestamp (t=), used to select historical keys. An invalid
+/// timestamp is ignored like a missing one.
+#[cfg(feature = "verify")]
+fn signature_timestamp(dkim_header: &DKIMHeader) -> Option<chrono::DateTime<chrono::Utc>> {
+    use chrono::TimeZone;
+    let secs = dkim_header.get_tag("t")?.parse::<i64>().ok()?;
+    chrono::Utc.timestamp_opt(secs, 0).single()
+}
+
+/// Where the verification gets the keys of the signatures from: the resolver,
+/// or a cache in front of it (see [batch])
+#[cfg(feature = "verify")]
+pub(crate) trait KeySource {
+    /// The keys of a signature, several during a rotation. The outer error
+    /// is the lookup's, the inner ones are decoding's, which are only
+    /// reported after the body hash is checked.
+    fn public_keys(
+        &self,
+        algorithm: &dyn SignatureAlgorithm,
+        domain: String,
+        selector: String,
+        timestamp: Option<chrono::DateTime<chrono::Utc>>,
+    ) -> Result<PublicKeys, DKIMError>;
+}
+
+/// The decoded keys of a signature
+#[cfg(feature = "verify")]
+pub(crate) type PublicKeys = Arc<Vec<Result<PublicKey, DKIMError>>>;
+
+#[cfg(feature = "verify")]
+impl<T: dns::TimedLookup + ?Sized> KeySource for T {
+    fn public_keys(
+        &self,
+        algorithm: &dyn SignatureAlgorithm,
+        domain: String,
+        selector: String,
+        timestamp: Option<chrono::DateTime<chrono::Utc>>,
+    ) -> Result<PublicKeys, DKIMError> {
+        let public_keys = public_key::retrieve_public_keys(
+            self,
+            domain,
+            selector,
+            timestamp,
+            algorithm.key_type(),
+        )?;
+        Ok(Arc::new(
+            public_keys
+                .iter()
+                .map(|public_key| algorithm.parse_public_key(public_key))
+                .collect(),
+        ))
+    }
+}
+
+#[cfg(feature = "verify")]
+fn verify_email_header<'a, K: KeySource + ?Sized>(
+    keys: &K,
+    registry: &AlgorithmRegistry,
     dkim_header: &'a DKIMHeader,
-    email: &'a mailparse::ParsedMail<'a>,
+    email: &'a Message<'a>,
+    trace: Option<&mut SignatureTrace>,
 ) -> Result<(canonicalization::Type, canonicalization::Type), DKIMError> {
-    let public_key = public_key::retrieve_public_key(
-        resolver,
-        dkim_header.get_required_tag("d"),
-        dkim_header.get_required_tag("s"),
-    )?;
-
     let (header_canonicalization_type, body_canonicalization_type) =
-        parser::parse_canonicalization(dkim_header.get_tag("c"))?;
-    let hash_algo = parser::parse_hash_algo(&dkim_header.get_required_tag("a"))?;
-    let computed_body_hash = hash::compute_body_hash(
+        parser::parse_canonicalization(dkim_header.get_tag("c").as_deref())?;
+    let algorithm = registry.get(&dkim_header.get_required_tag("a"))?;
+    let canonicalized_body = hash::canonicalize_body(
         body_canonicalization_type.clone(),
-        dkim_header.get_tag("l"),
-        hash_algo.clone(),
+        dkim_header.get_tag("l").map(Cow::into_owned),
         email,
     )?;
-    let computed_headers_hash = hash::compute_headers_hash(
+    let computed_body_hash = base64::encode(algorithm.digest(&canonicalized_body));
+    let header_hash_input = hash::canonicalize_headers(
         header_canonicalization_type.clone(),
         &dkim_header.get_required_tag("h"),
-        hash_algo.clone(),
-        &dkim_header,
+        dkim_header,
         email,
     )?;
+    let computed_headers_hash = algorithm.digest(&header_hash_input);
+    let header_body_hash = dkim_header.get_required_tag("bh");
+
+    if let Some(trace) = trace {
+        trace.selected_headers =
+            hash::select_header_indices(&dkim_header.get_required_tag("h"), email)
+                .into_iter()
+                .map(|index| SelectedHeader {
+                    index,
+                    name: email.headers[index].get_key(),
+                    value: String::from_utf8_lossy(email.headers[index].get_value_raw())
+                        .into_owned(),
+                })
+                .collect();
+        trace.copied_headers_diff = match dkim_header.get_tag("z") {
+            Some(copied_headers) => signature::parse_copied_headers(&copied_headers)
+                .into_iter()
+                .map(|(name, copied_value)| CopiedHeaderDiff {
+                    message_value: trace
+                        .selected_headers
+                        .iter()
+                        .find(|h| h.name.eq_ignore_ascii_case(&name))
+                        .map(|h| h.value.clone())
+                        .or_else(|| {
+                            email
+                                .get_first_header(&name)
+                                .map(|h| String::from_utf8_lossy(h.get_value_raw()).into_owned())
+                        }),
+                    name,
+                    copied_value,
+                })
+                .collect(),
+            None => vec![],
+        };
+        trace.canonicalized_body = canonicalized_body;
+        trace.computed_body_hash = Some(computed_body_hash.clone());
+        trace.signed_body_hash = Some(header_body_hash.to_string());
+        trace.header_hash_input = header_hash_input;
+    }
+
+    let public_keys = keys.public_keys(
+        algorithm.as_ref(),
+        dkim_header.get_required_tag("d").into_owned(),
+        dkim_header.get_required_tag("s").into_owned(),
+        signature_timestamp(dkim_header),
+    )?;
 
-    let header_body_hash = dkim_header.get_required_tag("bh").clone();
     if header_body_hash != computed_body_hash {
         return Err(DKIMError::BodyHashDidNotVerify);
     }
 
-    let signature = base64::decode(dkim_header.get_required_tag("b")).map_err(|err| {
+    let signature = base64::decode(&*dkim_header.get_required_tag("b")).map_err(|err| {
         DKIMError::SignatureSyntaxError(format!("failed to decode signature: {}", err))
     })?;
-    if !verify_signature(hash_algo, computed_headers_hash, signature, public_key)? {
-        return Err(DKIMError::SignatureDidNotVerify);
+    // The signature passes if one of the keys verifies it, otherwise the
+    // error of the first key is reported
+    let mut first_error = None;
+    for public_key in public_keys.iter() {
+        let verified = public_key
+            .as_ref()
+            .map_err(Clone::clone)
+            .and_then(|public_key| {
+                algorithm.verify_parsed(public_key, &computed_headers_hash, &signature)
+            });
+        match verified {
+            Ok(true) => return Ok((header_canonicalization_type, body_canonicalization_type)),
+            Ok(false) => {
+                first_error.get_or_insert(DKIMError::SignatureDidNotVerify);
+            }
+            Err(err) => {
+                first_error.get_or_insert(err);
+            }
+        }
     }
-
-    Ok((header_canonicalization_type, body_canonicalization_type))
+    Err(first_error.unwrap_or(DKIMError::NoKeyForSignature))
 }
 
-/// Run the DKIM verification on the email providing an existing resolver
-pub fn verify_email_with_resolver<'a, T: dns::Lookup>(
-    email: &'a mailparse::ParsedMail<'a>,
-    resolver: &T,
-) -> Result<DKIMResult, DKIMError> {
+#[cfg(feature = "verify")]
+fn verify_email_inner<'a, K: KeySource + ?Sized>(
+    email: &'a Message<'a>,
+    keys: &K,
+    registry: &AlgorithmRegistry,
+    now: chrono::DateTime<chrono::Utc>,
+    mut traces: Option<&mut Vec<SignatureTrace>>,
+) -> DKIMResult {
     let mut last_error = None;
 
-    for h in email.headers.get_all_headers(HEADER) {
+    for h in email.get_all_headers(HEADER) {
         let value = String::from_utf8_lossy(h.get_value_raw());
 
-        let dkim_header = match validate_header(&value) {
-            Ok(v) => v,
-            Err(err) => {
-                last_error = Some(err);
-                continue;
-            }
-        };
+        let mut trace = traces.as_ref().map(|_| SignatureTrace {
+            header: value.to_string(),
+            ..Default::default()
+        });
 
-        match verify_email_header(resolver, &dkim_header, email) {
+        let result = validate_header(&value, now).and_then(|dkim_header| {
+            verify_email_header(keys, registry, &dkim_header, email, trace.as_mut())
+        });
+
+        if let (Some(traces), Some(mut trace)) = (traces.as_mut(), trace) {
+            trace.error = result.as_ref().err().cloned();
+            traces.push(trace);
+        }
+
+        match result {
             Ok((header_canonicalization_type, body_canonicalization_type)) => {
-                return Ok(DKIMResult::pass(
-                    header_canonicalization_type,
-                    body_canonicalization_type,
-                ))
+                return DKIMResult::pass(header_canonicalization_type, body_canonicalization_type)
             }
             Err(err) => {
                 last_error = Some(err);
@@ -234,12 +377,141 @@ pub fn verify_email_with_resolver<'a, T: dns::Lookup>(
     }
 
     if let Some(err) = last_error {
-        Ok(DKIMResult::fail(err))
+        DKIMResult::fail(err)
     } else {
-        Ok(DKIMResult::neutral())
+        DKIMResult::neutral()
     }
 }
 
+/// Run the DKIM verification on the email providing an existing resolver
+#[cfg(all(feature = "verify", feature = "std"))]
+pub fn verify_email_with_resolver<'a, T: dns::TimedLookup>(
+    email: &'a mailparse::ParsedMail<'a>,
+    resolver: &T,
+) -> Result<DKIMResult, DKIMError> {
+    Ok(verify_email_inner(
+        &email.into(),
+        resolver,
+        &AlgorithmRegistry::default(),
+        chrono::Utc::now(),
+        None,
+    ))
+}
+
+/// Same as [verify_email_with_resolver] but also returns diagnostics for each
+/// DKIM-Signature that was tried, in the order they were tried.
+#[cfg(all(feature = "verify", feature = "std"))]
+pub fn verify_email_with_trace<'a, T: dns::TimedLookup>(
+    email: &'a mailparse::ParsedMail<'a>,
+    resolver: &T,
+) -> Result<(DKIMResult, Vec<SignatureTrace>), DKIMError> {
+    let mut traces = vec![];
+    let result = verify_email_inner(
+        &email.into(),
+        resolver,
+        &AlgorithmRegistry::default(),
+        chrono::Utc::now(),
+        Some(&mut traces),
+    );
+    Ok((result, traces))
+}
+
+/// Verifies every DKIM-Signature of the email instead of stopping at the
+/// first one that passes, for example to check both the RSA and the Ed25519
+/// signatures of a message. The results are in the order of the headers.
+#[cfg(all(feature = "verify", feature = "std"))]
+pub fn verify_email_signatures_with_resolver<'a, T: dns::TimedLookup>(
+    email: &'a mailparse::ParsedMail<'a>,
+    resolver: &T,
+) -> Result<Vec<SignatureResult>, DKIMError> {
+    Ok(verify_signatures_inner(
+        &email.into(),
+        resolver,
+        &AlgorithmRegistry::default(),
+        chrono::Utc::now(),
+        None,
+    ))
+}
+
+/// Same as [verify_email_signatures_with_resolver] but also returns
+/// diagnostics for each DKIM-Signature, in the order of the headers.
+#[cfg(all(feature = "verify", feature = "std"))]
+pub fn verify_email_signatures_with_trace<'a, T: dns::TimedLookup>(
+    email: &'a mailparse::ParsedMail<'a>,
+    resolver: &T,
+) -> Result<(Vec<SignatureResult>, Vec<SignatureTrace>), DKIMError> {
+    let mut traces = vec![];
+    let results = verify_signatures_inner(
+        &email.into(),
+        resolver,
+        &AlgorithmRegistry::default(),
+        chrono::Utc::now(),
+        Some(&mut traces),
+    );
+    Ok((results, traces))
+}
+
+#[cfg(all(feature = "verify", feature = "std"))]
+fn verify_signatures_inner<'a, K: KeySource + ?Sized>(
+    email: &'a Message<'a>,
+    keys: &K,
+    registry: &AlgorithmRegistry,
+    now: chrono::DateTime<chrono::Utc>,
+    mut traces: Option<&mut Vec<SignatureTrace>>,
+) -> Vec<SignatureResult> {
+    email
+        .get_all_headers(HEADER)
+        .map(|h| {
+            let value = String::from_utf8_lossy(h.get_value_raw());
+            let mut trace = traces.as_ref().map(|_| SignatureTrace {
+                header: value.to_string(),
+                ..Default::default()
+            });
+            let result = validate_header(&value, now).and_then(|dkim_header| {
+                verify_email_header(keys, registry, &dkim_header, email, trace.as_mut())
+            });
+            if let (Some(traces), Some(mut trace)) = (traces.as_mut(), trace) {
+                trace.error = result.as_ref().err().cloned();
+                traces.push(trace);
+            }
+            SignatureResult {
+                signature: DkimSignature::parse_with_registry(&value, registry).ok(),
+                result: match result {
+                    Ok((header_canonicalization_type, body_canonicalization_type)) => {
+                        DKIMResult::pass(header_canonicalization_type, body_canonicalization_type)
+                    }
+                    Err(err) => DKIMResult::fail(err),
+                },
+            }
+        })
+        .collect()
+}
+
+/// Run the DKIM verification on the raw bytes of an email. Expiration (`x=`)
+/// is checked against `now`, so this doesn't need a clock and is available
+/// without the `std` feature.
+#[cfg(feature = "verify")]
+pub fn verify_raw_email_with_resolver<T: dns::TimedLookup>(
+    raw_email: &[u8],
+    resolver: &T,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Result<DKIMResult, DKIMError> {
+    verify_raw_email_with_registry(raw_email, resolver, &AlgorithmRegistry::default(), now)
+}
+
+/// Same as [verify_raw_email_with_resolver] with the algorithms of
+/// `registry`, for example to accept an experimental algorithm
+#[cfg(feature = "verify")]
+pub fn verify_raw_email_with_registry<T: dns::TimedLookup>(
+    raw_email: &[u8],
+    resolver: &T,
+    registry: &AlgorithmRegistry,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Result<DKIMResult, DKIMError> {
+    let email = Message::parse(raw_email)?;
+    Ok(verify_email_inner(&email, resolver, registry, now, None))
+}
+
 #[cfg(test)]
 mod tests {
     use crate::dns::Lookup;
@@ -286,14 +558,14 @@ bh=MTIzNDU2Nzg5MDEyMzQ1Njc4OTAxMjM0NTY3ODkwMTI=;
 b=dzdVyOfAKCdLXdJOc9G2q8LoXSlEniSbav+yuU4zGeeruD00lszZ
       VoG4ZHRNiYzR
         "#;
-        validate_header(header).unwrap();
+        validate_header(header, chrono::Utc::now()).unwrap();
     }
 
     #[test]
     fn test_validate_header_missing_tag() {
         let header = "v=1; a=rsa-sha256; bh=a; b=b";
         assert_eq!(
-            validate_header(header).unwrap_err(),
+            validate_header(header, chrono::Utc::now()).unwrap_err(),
             DKIMError::SignatureMissingRequiredTag("d")
         );
     }
@@ -303,7 +575,7 @@ b=dzdVyOfAKCdLXdJOc9G2q8LoXSlEniSbav+yuU4zGeeruD00lszZ
         let header = r#"v=1; a=rsa-sha256; d=example.net; s=brisbane; i=foo@hein.com; h=headers; bh=hash; b=hash
         "#;
         assert_eq!(
-            validate_header(header).unwrap_err(),
+            validate_header(header, chrono::Utc::now()).unwrap_err(),
             DKIMError::DomainMismatch
         );
     }
@@ -313,7 +585,7 @@ b=dzdVyOfAKCdLXdJOc9G2q8LoXSlEniSbav+yuU4zGeeruD00lszZ
         let header = r#"v=3; a=rsa-sha256; d=example.net; s=brisbane; i=foo@example.net; h=headers; bh=hash; b=hash
         "#;
         assert_eq!(
-            validate_header(header).unwrap_err(),
+            validate_header(header, chrono::Utc::now()).unwrap_err(),
             DKIMError::IncompatibleVersion
         );
     }
@@ -323,7 +595,7 @@ b=dzdVyOfAKCdLXdJOc9G2q8LoXSlEniSbav+yuU4zGeeruD00lszZ
         let header = r#"v=1; a=rsa-sha256; d=example.net; s=brisbane; i=foo@example.net; h=Subject:A:B; bh=hash; b=hash
         "#;
         assert_eq!(
-            validate_header(header).unwrap_err(),
+            validate_header(header, chrono::Utc::now()).unwrap_err(),
             DKIMError::FromFieldNotSigned
         );
     }
@@ -335,7 +607,7 @@ b=dzdVyOfAKCdLXdJOc9G2q8LoXSlEniSbav+yuU4zGeeruD00lszZ
 
         let header = format!("v=1; a=rsa-sha256; d=example.net; s=brisbane; i=foo@example.net; h=From:B; bh=hash; b=hash; x={}", now.timestamp());
 
-        assert!(validate_header(&header).is_ok());
+        assert!(validate_header(&header, chrono::Utc::now()).is_ok());
     }
 
     #[test]
@@ -346,7 +618,7 @@ b=dzdVyOfAKCdLXdJOc9G2q8LoXSlEniSbav+yuU4zGeeruD00lszZ
         let header = format!("v=1; a=rsa-sha256; d=example.net; s=brisbane; i=foo@example.net; h=From:B; bh=hash; b=hash; x={}", now.timestamp());
 
         assert_eq!(
-            validate_header(&header).unwrap_err(),
+            validate_header(&header, chrono::Utc::now()).unwrap_err(),
             DKIMError::SignatureExpired
         );
     }
@@ -381,21 +653,18 @@ We lost the game.  Are you hungry yet?
 Joe."#
             .replace("\n", "\r\n");
 
-        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
-        let h = email
-            .headers
-            .get_all_headers(HEADER)
-            .first()
-            .unwrap()
-            .get_value_raw();
+        let email = Message::parse(raw_email.as_bytes()).unwrap();
+        let h = email.get_first_header(HEADER).unwrap().get_value_raw();
         let raw_header_dkim = String::from_utf8_lossy(h);
 
         let resolver = MockResolver::new();
 
         let dkim_verify_result = verify_email_header(
             &resolver,
-            &validate_header(&raw_header_dkim).unwrap(),
+            &AlgorithmRegistry::default(),
+            &validate_header(&raw_header_dkim, chrono::Utc::now()).unwrap(),
             &email,
+            None,
         );
 
         assert!(dkim_verify_result.is_ok());
@@ -430,23 +699,69 @@ We lost the game. Are you hungry yet?
 Joe.
 "#
             .replace("\n", "\r\n");
-        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
-        let h = email
-            .headers
-            .get_all_headers(HEADER)
-            .first()
-            .unwrap()
-            .get_value_raw();
+        let email = Message::parse(raw_email.as_bytes()).unwrap();
+        let h = email.get_first_header(HEADER).unwrap().get_value_raw();
         let raw_header_rsa = String::from_utf8_lossy(h);
 
         let resolver = MockResolver::new();
 
         let dkim_verify_result = verify_email_header(
             &resolver,
-            &validate_header(&raw_header_rsa).unwrap(),
+            &AlgorithmRegistry::default(),
+            &validate_header(&raw_header_rsa, chrono::Utc::now()).unwrap(),
             &email,
+            None,
         );
 
         assert!(dkim_verify_result.is_ok());
     }
+
+    #[test]
+    fn test_verify_email_with_trace_copied_headers() {
+        let raw_email = r#"DKIM-Signature: v=1; a=rsa-sha256; c=simple/simple; d=example.com;
+ s=newengland; h=From:Subject; z=From:joe@football.example.com|Subject:demo=20run;
+ bh=2jUSOH9NhtVGCQWNr9BrIAPreKQjO6Sn7XIkfJVOzv8=; b=AAAA
+From: joe@football.example.com
+Subject: demo walk
+
+Hi.
+"#
+        .replace("\n", "\r\n");
+        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+
+        let (res, traces) = verify_email_with_trace(&email, &MockResolver::new()).unwrap();
+        assert_eq!(res.summary(), "fail");
+        assert_eq!(traces.len(), 1);
+
+        let diff = &traces[0].copied_headers_diff;
+        assert_eq!(diff.len(), 2);
+        assert_eq!(diff[0].name, "From");
+        assert!(diff[0].matches());
+        assert_eq!(diff[1].name, "Subject");
+        assert_eq!(diff[1].copied_value, "demo run");
+        assert_eq!(diff[1].message_value.as_deref(), Some("demo walk"));
+        assert!(!diff[1].matches());
+    }
+
+    #[test]
+    fn test_verify_email_with_trace_same_result() {
+        // The trace parses z=, which must not turn an invalid b= or a
+        // signature that is otherwise fine into a different error
+        let raw_email = r#"DKIM-Signature: v=1; a=rsa-sha256; c=simple/simple; d=example.com;
+ s=newengland; h=From:Subject; z=From:joe@football.example.com|Subject:demo=20run;
+ bh=2jUSOH9NhtVGCQWNr9BrIAPreKQjO6Sn7XIkfJVOzv8=; b=!!!!
+From: joe@football.example.com
+Subject: demo walk
+
+Hi.
+"#
+        .replace("\n", "\r\n");
+        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+
+        let res = verify_email_with_resolver(&email, &MockResolver::new()).unwrap();
+        let (traced_res, traces) = verify_email_with_trace(&email, &MockResolver::new()).unwrap();
+        assert_eq!(traced_res, res);
+        assert_eq!(traces[0].error, res.error());
+        assert_eq!(traces[0].copied_headers_diff.len(), 2);
+    }
 }
@@ -0,0 +1,189 @@
+//! Minimal view of an email: its header fields and its raw bytes. It can be
+//! built from a [mailparse::ParsedMail] or, without `std`, parsed from the raw
+//! bytes with the same rules as `mailparse::parse_headers`.
+use alloc::borrow::Cow;
+
+use crate::prelude::*;
+#[cfg(feature = "verify")]
+use crate::DKIMError;
+
+/// A header field of the message
+#[derive(Debug, Clone)]
+pub(crate) struct Header<'a> {
+    key: Cow<'a, str>,
+    value: &'a [u8],
+}
+
+impl<'a> Header<'a> {
+    /// Name of the header as it appears in the message
+    pub(crate) fn get_key(&self) -> String {
+        self.key.to_string()
+    }
+
+    pub(crate) fn get_key_ref(&self) -> &str {
+        &self.key
+    }
+
+    /// Value of the header, unfolded and undecoded
+    pub(crate) fn get_value_raw(&self) -> &'a [u8] {
+        self.value
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Message<'a> {
+    pub(crate) headers: Vec<Header<'a>>,
+    pub(crate) raw_bytes: &'a [u8],
+}
+
+#[cfg(feature = "verify")]
+impl<'a> Message<'a> {
+    /// Splits the header fields of a raw email
+    pub(crate) fn parse(raw_bytes: &'a [u8]) -> Result<Self, DKIMError> {
+        let mut headers = vec![];
+        let mut ix = 0;
+        while ix < raw_bytes.len() {
+            match raw_bytes[ix] {
+                b'\n' => break,
+                b'\r' if raw_bytes.get(ix + 1) == Some(&b'\n') => break,
+                b'\r' => {
+                    return Err(DKIMError::MalformedBody);
+                }
+                _ => {}
+            }
+            let (header, len) = parse_header(&raw_bytes[ix..])?;
+            headers.push(header);
+            ix += len;
+        }
+
+        Ok(Message { headers, raw_bytes })
+    }
+
+    /// All the instances of a header, from the top
+    pub(crate) fn get_all_headers<'b>(
+        &'b self,
+        key: &'b str,
+    ) -> impl Iterator<Item = &'b Header<'a>> + 'b {
+        self.headers
+            .iter()
+            .filter(move |h| h.get_key_ref().eq_ignore_ascii_case(key))
+    }
+
+    pub(crate) fn get_first_header<'b>(&'b self, key: &'b str) -> Option<&'b Header<'a>> {
+        self.get_all_headers(key).next()
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a> From<&'a mailparse::ParsedMail<'a>> for Message<'a> {
+    fn from(email: &'a mailparse::ParsedMail<'a>) -> Self {
+        Message {
+            headers: email
+                .headers
+                .iter()
+                .map(|h| Header {
+                    key: h.get_key_ref(),
+                    value: h.get_value_raw(),
+                })
+                .collect(),
+            raw_bytes: email.raw_bytes,
+        }
+    }
+}
+
+#[cfg(feature = "verify")]
+/// Header names are latin1, which maps each byte to the same code point
+fn decode_latin1(bytes: &[u8]) -> Cow<'_, str> {
+    match core::str::from_utf8(bytes) {
+        Ok(s) if s.is_ascii() => Cow::Borrowed(s),
+        _ => Cow::Owned(bytes.iter().map(|&b| b as char).collect()),
+    }
+}
+
+#[cfg(feature = "verify")]
+/// Parses a header field starting at the beginning of `raw`. Returns it with
+/// the number of bytes it spans, including the line ending.
+fn parse_header(raw: &[u8]) -> Result<(Header<'_>, usize), DKIMError> {
+    if raw.first() == Some(&b' ') {
+        return Err(DKIMError::MalformedBody);
+    }
+
+    // A line without a colon is kept as a header with an empty value
+    let key_end = raw
+        .iter()
+        .position(|&c| c == b':' || c == b'\n')
+        .unwrap_or(raw.len());
+    if raw.get(key_end) != Some(&b':') {
+        let len = (key_end + 1).min(raw.len());
+        let header = Header {
+            key: decode_latin1(&raw[..key_end]),
+            value: &raw[key_end..key_end],
+        };
+        return Ok((header, len));
+    }
+
+    let mut ix = key_end + 1;
+    while raw.get(ix) == Some(&b' ') {
+        ix += 1;
+    }
+    let value_start = ix;
+    let mut value_end = ix;
+
+    // The value continues on the lines starting with a whitespace
+    while ix < raw.len() {
+        match raw[ix] {
+            b'\n' => match raw.get(ix + 1) {
+                Some(b' ') | Some(b'\t') => {}
+                _ => {
+                    ix += 1;
+                    break;
+                }
+            },
+            b'\r' => {}
+            _ => value_end = ix + 1,
+        }
+        ix += 1;
+    }
+
+    let header = Header {
+        key: decode_latin1(&raw[..key_end]),
+        value: &raw[value_start..value_end],
+    };
+    Ok((header, ix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_matches_mailparse() {
+        let raw_emails: &[&[u8]] = &[
+            b"From: a@b.c\r\nSubject:  folded\r\n\tsubject \r\n\r\nbody\r\n",
+            b"From: a@b.c\nX-Empty:\nno colon\nSubject: hi\n\nbody",
+            b"Subject: no body",
+            b"Key: value\r\n",
+        ];
+        for raw_email in raw_emails {
+            let expected = mailparse::parse_mail(raw_email).unwrap();
+            let message = Message::parse(raw_email).unwrap();
+            assert_eq!(message.headers.len(), expected.headers.len());
+            for (header, expected) in message.headers.iter().zip(&expected.headers) {
+                assert_eq!(header.get_key(), expected.get_key());
+                assert_eq!(header.get_value_raw(), expected.get_value_raw());
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            Message::parse(b" From: a@b.c\r\n\r\n").unwrap_err(),
+            DKIMError::MalformedBody
+        );
+        assert_eq!(
+            Message::parse(b"From: a@b.c\r\n\rbody").unwrap_err(),
+            DKIMError::MalformedBody
+        );
+    }
+}
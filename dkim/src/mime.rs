@@ -0,0 +1,271 @@
+//! Text of a verified email: the first `text/plain` part of its MIME tree,
+//! decoded from its transfer encoding and charset.
+//!
+//! A signature with a body length (`l=`) only covers the beginning of the
+//! body, anything can be appended to it. The Content-Type and
+//! Content-Transfer-Encoding of the email decide how the body is decoded, a
+//! copy of them added to the email or one which isn't in `h=` can change the
+//! text. [TextPart::fully_signed] tells whether the whole part, up to the
+//! boundary after it, and the headers decoding it are covered.
+use mailparse::MailHeaderMap;
+
+use crate::canonicalization::canonicalized_prefix_len;
+use crate::{DKIMError, DkimSignature};
+
+/// The first `text/plain` part of an email
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TextPart {
+    /// The decoded text
+    pub text: String,
+    /// The charset of the part, `us-ascii` if missing
+    pub charset: String,
+    /// Whether the part lies within the body signed by the signature, and
+    /// the top-level Content-Type and Content-Transfer-Encoding are signed
+    pub fully_signed: bool,
+}
+
+/// Returns the first `text/plain` part of `email` which is not an
+/// attachment, walking the multipart parts depth first. `signature` is the
+/// signature which verified the email.
+pub fn first_text_plain(
+    email: &mailparse::ParsedMail,
+    signature: &DkimSignature,
+) -> Result<Option<TextPart>, DKIMError> {
+    let part = match find_text_plain(email) {
+        Some(part) => part,
+        None => return Ok(None),
+    };
+    let text = part.get_body().map_err(|_| DKIMError::MalformedBody)?;
+
+    let fully_signed = mime_headers_signed(email, signature)
+        && match signature.body_length {
+            None => true,
+            Some(length) => body_up_to(email, part).is_some_and(|body| {
+                canonicalized_prefix_len(&signature.body_canonicalization, body) <= length
+            }),
+        };
+
+    Ok(Some(TextPart {
+        text,
+        charset: part.ctype.charset.clone(),
+        fully_signed,
+    }))
+}
+
+/// The body of `email` up to the end of `part`, `None` if the email has no
+/// body
+fn body_up_to<'a>(
+    email: &'a mailparse::ParsedMail,
+    part: &mailparse::ParsedMail,
+) -> Option<&'a [u8]> {
+    let (_, body_start) = mailparse::parse_headers(email.raw_bytes).ok()?;
+    if body_start >= email.raw_bytes.len() {
+        return None;
+    }
+    // The parts are slices of the raw email, up to the line break before the
+    // next boundary
+    let part_start =
+        (part.raw_bytes.as_ptr() as usize).checked_sub(email.raw_bytes.as_ptr() as usize)?;
+    let part_end = part_start + part.raw_bytes.len();
+    if part_end > email.raw_bytes.len() {
+        return None;
+    }
+    email.raw_bytes.get(body_start..part_end)
+}
+
+/// Whether the top-level Content-Type and Content-Transfer-Encoding are in
+/// `h=`, present or not, and not repeated
+fn mime_headers_signed(email: &mailparse::ParsedMail, signature: &DkimSignature) -> bool {
+    ["Content-Type", "Content-Transfer-Encoding"]
+        .iter()
+        .all(|name| {
+            email.get_headers().get_all_headers(name).len() <= 1
+                && signature
+                    .signed_headers
+                    .iter()
+                    .any(|signed| signed.eq_ignore_ascii_case(name))
+        })
+}
+
+fn find_text_plain<'a, 'b>(
+    part: &'b mailparse::ParsedMail<'a>,
+) -> Option<&'b mailparse::ParsedMail<'a>> {
+    if part.ctype.mimetype.starts_with("multipart/") {
+        return part.subparts.iter().find_map(find_text_plain);
+    }
+    let attachment =
+        part.get_content_disposition().disposition == mailparse::DispositionType::Attachment;
+    (part.ctype.mimetype == "text/plain" && !attachment).then_some(part)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{EmailBuilder, TestKey};
+    use crate::verify_email_signatures_with_resolver;
+
+    const MULTIPART: &str = "--b1\n\
+        Content-Type: text/html\n\
+        \n\
+        <p>ignored</p>\n\
+        --b1\n\
+        Content-Type: multipart/alternative; boundary=b2\n\
+        \n\
+        --b2\n\
+        Content-Type: text/plain; charset=iso-8859-1\n\
+        Content-Transfer-Encoding: quoted-printable\n\
+        \n\
+        add_key ed25519:abc=0A=\n\
+        caf=E9\n\
+        --b2--\n\
+        --b1\n\
+        Content-Type: text/plain\n\
+        Content-Disposition: attachment; filename=a.txt\n\
+        \n\
+        attached\n\
+        --b1--\n";
+
+    /// The first text/plain part of the email and whether it passed
+    fn text_part(key: &TestKey, email: &[u8]) -> (Option<TextPart>, bool) {
+        let parsed = mailparse::parse_mail(email).unwrap();
+        let results = verify_email_signatures_with_resolver(&parsed, &key.resolver()).unwrap();
+        let result = &results[0];
+        let part = first_text_plain(&parsed, result.signature.as_ref().unwrap()).unwrap();
+        (part, result.result.summary() == "pass")
+    }
+
+    /// Length of a body once its line endings are converted to CRLF
+    fn signed_len(body: &str) -> usize {
+        body.len() + body.matches('\n').count()
+    }
+
+    /// The default signed headers and the MIME ones
+    const SIGNED_HEADERS: &[&str] = &[
+        "From",
+        "To",
+        "Subject",
+        "MIME-Version",
+        "Content-Type",
+        "Content-Transfer-Encoding",
+    ];
+
+    fn multipart(key: &TestKey) -> EmailBuilder {
+        EmailBuilder::new(key)
+            .header("MIME-Version", "1.0")
+            .header("Content-Type", "multipart/mixed; boundary=b1")
+            .signed_headers(SIGNED_HEADERS)
+            .body(MULTIPART)
+    }
+
+    #[test]
+    fn test_first_text_plain() {
+        let key = TestKey::rsa("example.com", "2022");
+
+        let (part, pass) = text_part(&key, &multipart(&key).build());
+        assert!(pass);
+        assert_eq!(
+            part,
+            Some(TextPart {
+                text: "add_key ed25519:abc\ncafé".to_owned(),
+                charset: "iso-8859-1".to_owned(),
+                fully_signed: true,
+            })
+        );
+
+        let email = EmailBuilder::new(&key)
+            .header("Content-Transfer-Encoding", "base64")
+            .body("aW5pdA==")
+            .build();
+        let (part, _) = text_part(&key, &email);
+        assert_eq!(part.unwrap().text, "init");
+
+        let email = EmailBuilder::new(&key)
+            .header("Content-Type", "text/html")
+            .body("<p>init</p>")
+            .build();
+        assert_eq!(text_part(&key, &email).0, None);
+    }
+
+    #[test]
+    fn test_body_length() {
+        let key = TestKey::rsa("example.com", "2022");
+        // Signs up to the end of the text/plain part
+        let email = multipart(&key)
+            .body_length(signed_len(
+                &MULTIPART[..MULTIPART.find("--b2--").unwrap() + 7],
+            ))
+            .build();
+        let (part, pass) = text_part(&key, &email);
+        assert!(pass);
+        assert!(part.unwrap().fully_signed);
+
+        // Signs the HTML part only
+        let email = multipart(&key).body_length(40).build();
+        let (part, pass) = text_part(&key, &email);
+        assert!(pass);
+        assert!(!part.unwrap().fully_signed);
+
+        // Text appended after the signed body
+        let email = EmailBuilder::new(&key)
+            .body("init\n")
+            .body_length(6)
+            .tamper_body("init\ntransfer bob.near\n")
+            .build();
+        let (part, pass) = text_part(&key, &email);
+        assert!(pass);
+        let part = part.unwrap();
+        assert_eq!(part.text, "init\r\ntransfer bob.near\r\n");
+        assert!(!part.fully_signed);
+
+        // Bare LF line endings
+        let email = EmailBuilder::new(&key)
+            .signed_headers(SIGNED_HEADERS)
+            .body("hello\n")
+            .body_length(10)
+            .build();
+        let email = String::from_utf8(email).unwrap().replace("\r\n", "\n");
+        let (part, _) = text_part(&key, email.as_bytes());
+        let part = part.unwrap();
+        assert_eq!(part.text, "hello\n");
+        assert!(part.fully_signed);
+
+        // No body
+        let email = EmailBuilder::new(&key)
+            .signed_headers(SIGNED_HEADERS)
+            .body_length(10)
+            .build();
+        let email = String::from_utf8(email).unwrap();
+        let (headers, _) = email.split_once("\r\n\r\n").unwrap();
+        let (part, _) = text_part(&key, format!("{}\r\n", headers).as_bytes());
+        assert!(!part.unwrap().fully_signed);
+    }
+
+    #[test]
+    fn test_mime_headers_signed() {
+        let key = TestKey::rsa("example.com", "2022");
+        // Content-Type and Content-Transfer-Encoding are not in h=
+        let email = EmailBuilder::new(&key).body("init").build();
+        let (part, pass) = text_part(&key, &email);
+        assert!(pass);
+        assert!(!part.unwrap().fully_signed);
+
+        let email = EmailBuilder::new(&key)
+            .signed_headers(SIGNED_HEADERS)
+            .body("init")
+            .build();
+        let (part, pass) = text_part(&key, &email);
+        assert!(pass);
+        assert!(part.unwrap().fully_signed);
+
+        // A Content-Transfer-Encoding added on top of the signed one
+        let email = EmailBuilder::new(&key)
+            .header("Content-Transfer-Encoding", "7bit")
+            .signed_headers(SIGNED_HEADERS)
+            .body("aW5pdA==")
+            .inject_header("Content-Transfer-Encoding", "base64")
+            .build();
+        let (part, pass) = text_part(&key, &email);
+        assert!(pass);
+        assert!(!part.unwrap().fully_signed);
+    }
+}
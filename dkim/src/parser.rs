@@ -1,36 +1,53 @@
-use crate::{canonicalization, hash, DKIMError};
+use alloc::borrow::Cow;
+
+use crate::prelude::*;
+use crate::{canonicalization, DKIMError};
 use nom::bytes::complete::tag;
-use nom::bytes::complete::take_while1;
-use nom::character::complete::alpha1;
-use nom::combinator::opt;
-use nom::multi::fold_many0;
+use nom::bytes::complete::{take_while, take_while1};
+use nom::character::complete::satisfy;
+use nom::combinator::{opt, recognize};
+use nom::multi::{fold_many0, many0_count};
 use nom::sequence::delimited;
 use nom::sequence::pair;
 use nom::sequence::preceded;
 use nom::sequence::terminated;
 use nom::IResult;
 
-#[derive(Clone, Debug, PartialEq)]
-/// DKIM signature tag
-pub struct Tag {
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "borsh", derive(borsh::BorshSerialize))]
+/// DKIM signature tag, borrowed from the parsed text
+pub struct Tag<'a> {
     /// Name of the tag (v, i, a, h, ...)
-    pub name: String,
-    /// Value of the tag with spaces removed
-    pub value: String,
+    pub name: &'a str,
     /// Value of the tag as seen in the text
-    pub raw_value: String,
+    pub raw_value: &'a str,
+}
+
+impl<'a> Tag<'a> {
+    /// Value of the tag with spaces removed. Only allocates if the value
+    /// has spaces, for example a folded `b=`.
+    pub fn value(&self) -> Cow<'a, str> {
+        if self.raw_value.contains(is_fws) {
+            let mut value = String::with_capacity(self.raw_value.len());
+            value.extend(self.raw_value.chars().filter(|&c| !is_fws(c)));
+            Cow::Owned(value)
+        } else {
+            Cow::Borrowed(self.raw_value)
+        }
+    }
 }
 
 /// Main entrypoint of the parser. Parses the DKIM signature tag list
 /// as specified <https://datatracker.ietf.org/doc/html/rfc6376#section-3.6.1>.
 /// tag-list  =  tag-spec *( ";" tag-spec ) [ ";" ]
-pub fn tag_list(input: &str) -> IResult<&str, Vec<Tag>> {
+pub fn tag_list(input: &str) -> IResult<&str, Vec<Tag<'_>>> {
     let (input, start) = tag_spec(input)?;
 
     terminated(
         fold_many0(
             preceded(tag(";"), tag_spec),
-            move || vec![start.clone()],
+            move || vec![start],
             |mut acc: Vec<Tag>, item| {
                 acc.push(item);
                 acc
@@ -40,90 +57,65 @@ pub fn tag_list(input: &str) -> IResult<&str, Vec<Tag>> {
     )(input)
 }
 
+/// Returns the name of the first tag that appears more than once.
+/// "Tags with duplicate names MUST NOT occur within a single tag-list; if a
+/// tag name does occur more than once, the entire tag-list is invalid."
+/// <https://datatracker.ietf.org/doc/html/rfc6376#section-3.2>
+pub(crate) fn duplicate_tag<'a>(tags: &[Tag<'a>]) -> Option<&'a str> {
+    tags.iter()
+        .enumerate()
+        .find(|(i, tag)| tags[..*i].iter().any(|t| t.name == tag.name))
+        .map(|(_, tag)| tag.name)
+}
+
 /// tag-spec  =  [FWS] tag-name [FWS] "=" [FWS] tag-value [FWS]
-fn tag_spec(input: &str) -> IResult<&str, Tag> {
+fn tag_spec(input: &str) -> IResult<&str, Tag<'_>> {
     let (input, name) = delimited(opt(fws), tag_name, opt(fws))(input)?;
     let (input, _) = tag("=")(input)?;
+    let (input, raw_value) = delimited(opt(fws), tag_value, opt(fws))(input)?;
 
-    // Parse the twice to keep the original text
-    let value_input = input;
-    let (_, raw_value) = delimited(opt(fws), raw_tag_value, opt(fws))(value_input)?;
-    let (input, value) = delimited(opt(fws), tag_value, opt(fws))(value_input)?;
-
-    Ok((
-        input,
-        Tag {
-            name: name.to_owned(),
-            value,
-            raw_value,
-        },
-    ))
+    Ok((input, Tag { name, raw_value }))
 }
 
 /// tag-name  =  ALPHA *ALNUMPUNC
 /// ALNUMPUNC =  ALPHA / DIGIT / "_"
 fn tag_name(input: &str) -> IResult<&str, &str> {
-    alpha1(input)
+    recognize(pair(
+        satisfy(|c| c.is_ascii_alphabetic()),
+        take_while(|c: char| c.is_ascii_alphanumeric() || c == '_'),
+    ))(input)
 }
 
 /// tag-value =  [ tval *( 1*(WSP / FWS) tval ) ]
 /// tval      =  1*VALCHAR
 /// VALCHAR   =  %x21-3A / %x3C-7E
-fn tag_value(input: &str) -> IResult<&str, String> {
-    let is_valchar = |c| (c >= '!' && c <= ':') || (c >= '<' && c <= '~');
-    match opt(take_while1(is_valchar))(input)? {
-        (input, Some(start)) => fold_many0(
-            preceded(fws, take_while1(is_valchar)),
-            || start.to_owned(),
-            |mut acc: String, item| {
-                acc += item;
-                acc
-            },
-        )(input),
-        (input, None) => Ok((input, "".to_string())),
-    }
+fn tag_value(input: &str) -> IResult<&str, &str> {
+    let is_valchar = |c| ('!'..=':').contains(&c) || ('<'..='~').contains(&c);
+    recognize(opt(pair(
+        take_while1(is_valchar),
+        many0_count(pair(fws, take_while1(is_valchar))),
+    )))(input)
 }
 
-fn raw_tag_value(input: &str) -> IResult<&str, String> {
-    let is_valchar = |c| (c >= '!' && c <= ':') || (c >= '<' && c <= '~');
-    match opt(take_while1(is_valchar))(input)? {
-        (input, Some(start)) => fold_many0(
-            pair(fws, take_while1(is_valchar)),
-            || start.to_owned(),
-            |mut acc: String, item| {
-                acc += &(item.0.to_owned() + item.1);
-                acc
-            },
-        )(input),
-        (input, None) => Ok((input, "".to_string())),
-    }
+fn is_fws(c: char) -> bool {
+    c == ' ' || c == '\t' || c == '\r' || c == '\n'
 }
 
 /// FWS is folding whitespace.  It allows multiple lines separated by CRLF followed by at least one whitespace, to be joined.
 fn fws(input: &str) -> IResult<&str, &str> {
-    take_while1(|c| c == ' ' || c == '\t' || c == '\r' || c == '\n')(input)
-}
-
-pub(crate) fn parse_hash_algo(value: &String) -> Result<hash::HashAlgo, DKIMError> {
-    use hash::HashAlgo;
-    match value.as_str() {
-        "rsa-sha1" => Ok(HashAlgo::RsaSha1),
-        "rsa-sha256" => Ok(HashAlgo::RsaSha256),
-        "ed25519-sha256" => Ok(HashAlgo::Ed25519Sha256),
-        e => Err(DKIMError::UnsupportedHashAlgorithm(e.to_string())),
-    }
+    take_while1(is_fws)(input)
 }
 
 /// Parses the canonicalization value (passed in c=) and returns canonicalization
 /// for (Header, Body)
 pub(crate) fn parse_canonicalization(
-    value: Option<String>,
+    value: Option<&str>,
 ) -> Result<(canonicalization::Type, canonicalization::Type), DKIMError> {
     use canonicalization::Type::{Relaxed, Simple};
     if value.is_none() {
         return Ok((Simple, Simple));
     }
-    match value.unwrap().as_str() {
+    match value.unwrap() {
         "simple/simple" => Ok((Simple, Simple)),
         "relaxed/simple" => Ok((Relaxed, Simple)),
         "simple/relaxed" => Ok((Simple, Relaxed)),
@@ -149,11 +141,11 @@ mod tests {
         use canonicalization::Type::{Relaxed, Simple};
 
         assert_eq!(
-            parse_canonicalization(Some("simple".to_string())).unwrap(),
+            parse_canonicalization(Some("simple")).unwrap(),
             (Simple, Simple)
         );
         assert_eq!(
-            parse_canonicalization(Some("relaxed".to_string())).unwrap(),
+            parse_canonicalization(Some("relaxed")).unwrap(),
             (Relaxed, Simple)
         );
     }
@@ -165,9 +157,8 @@ mod tests {
             (
                 "",
                 vec![Tag {
-                    name: "a".to_string(),
-                    value: "a/1@.-:=".to_string(),
-                    raw_value: "a/1@.-:=".to_string()
+                    name: "a",
+                    raw_value: "a/1@.-:="
                 }]
             )
         );
@@ -177,18 +168,19 @@ mod tests {
                 "",
                 vec![
                     Tag {
-                        name: "a".to_string(),
-                        value: "a".to_string(),
-                        raw_value: "a".to_string()
+                        name: "a",
+                        raw_value: "a"
                     },
                     Tag {
-                        name: "b".to_string(),
-                        value: "abc".to_string(),
-                        raw_value: "a\n    bc".to_string()
+                        name: "b",
+                        raw_value: "a\n    bc"
                     }
                 ]
             )
         );
+        let (_, tags) = tag_list("a= a ; b = a\n    bc").unwrap();
+        assert!(matches!(tags[0].value(), Cow::Borrowed("a")));
+        assert_eq!(tags[1].value(), "abc");
     }
 
     #[test]
@@ -198,9 +190,8 @@ mod tests {
             (
                 "",
                 Tag {
-                    name: "a".to_string(),
-                    value: "b".to_string(),
-                    raw_value: "b".to_string()
+                    name: "a",
+                    raw_value: "b"
                 }
             )
         );
@@ -209,12 +200,16 @@ mod tests {
             (
                 "",
                 Tag {
-                    name: "a".to_string(),
-                    value: "bcdef".to_string(),
-                    raw_value: "b c d e f".to_string()
+                    name: "a",
+                    raw_value: "b c d e f"
                 }
             )
         );
+        assert_eq!(tag_spec("a=b c d e f").unwrap().1.value(), "bcdef");
+        assert_eq!(tag_spec("a=").unwrap().1.value(), "");
+        assert_eq!(tag_spec("x_1=b").unwrap().1.name, "x_1");
+        assert!(tag_spec("1x=b").is_err());
+        assert!(tag_spec("_x=b").is_err());
     }
 
     #[test]
@@ -225,14 +220,12 @@ mod tests {
                 "",
                 vec![
                     Tag {
-                        name: "k".to_string(),
-                        value: "rsa".to_string(),
-                        raw_value: "rsa".to_string()
+                        name: "k",
+                        raw_value: "rsa"
                     },
                     Tag {
-                        name: "p".to_string(),
-                        value: "kEy+/".to_string(),
-                        raw_value: "kEy+/".to_string()
+                        name: "p",
+                        raw_value: "kEy+/"
                     }
                 ]
             )
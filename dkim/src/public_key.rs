@@ -1,67 +1,84 @@
-use rsa::{pkcs1, pkcs8};
-use std::collections::HashMap;
+use alloc::borrow::Cow;
 
-use crate::{dns, parser, DKIMError, DkimPublicKey, DNS_NAMESPACE};
+use crate::prelude::*;
+use crate::{dns, parser, DKIMError, DNS_NAMESPACE};
 
 const RSA_KEY_TYPE: &str = "rsa";
-const ED25519_KEY_TYPE: &str = "ed25519";
 
 // https://datatracker.ietf.org/doc/html/rfc6376#section-6.1.2
-pub(crate) fn retrieve_public_key<T: dns::Lookup>(
+/// Returns the decoded `p=` of the key records, which must be of `key_type`,
+/// the key type of the signing algorithm. During a key rotation several
+/// records can be published, or returned by a [dns::HistoricalLookup]: the
+/// keys of all the valid records are returned, in the order of the records.
+/// Without any valid record, the error of the first one is returned.
+pub(crate) fn retrieve_public_keys<T: dns::TimedLookup + ?Sized>(
     resolver: &T,
     domain: String,
     subdomain: String,
-) -> Result<DkimPublicKey, DKIMError> {
+    timestamp: Option<chrono::DateTime<chrono::Utc>>,
+    key_type: &str,
+) -> Result<Vec<Vec<u8>>, DKIMError> {
     let dns_name = format!("{}.{}.{}", subdomain, DNS_NAMESPACE, domain);
-    let res = resolver.lookup_txt(&dns_name)?;
-    // TODO: Return multiple keys for when verifiying the signatures. During key
-    // rotation they are often multiple keys to consider.
-    let txt = res.first().ok_or(DKIMError::NoKeyForSignature)?;
+    let context = dns::LookupContext {
+        domain: &domain,
+        selector: &subdomain,
+        timestamp,
+    };
+    let res = resolver.lookup_txt_at(&dns_name, &context)?;
 
-    // Parse the tags inside the DKIM TXT DNS record
-    let (_, tags) = parser::tag_list(txt).map_err(|_| DKIMError::KeySyntaxError)?;
+    let mut keys = vec![];
+    let mut first_error = None;
+    for txt in &res {
+        match parse_key_record(txt, key_type) {
+            Ok(key) => keys.push(key),
+            Err(err) => {
+                first_error.get_or_insert(err);
+            }
+        }
+    }
+    match first_error {
+        Some(err) if keys.is_empty() => Err(err),
+        _ if keys.is_empty() => Err(DKIMError::NoKeyForSignature),
+        _ => Ok(keys),
+    }
+}
 
-    let mut tags_map = HashMap::new();
-    for tag in &tags {
-        tags_map.insert(tag.name.clone(), tag.clone());
+/// Parses the tags of a DKIM TXT DNS record. A record with a duplicated tag
+/// is invalid, so that every reader of the record sees the same key.
+/// <https://datatracker.ietf.org/doc/html/rfc6376#section-3.2>
+pub(crate) fn key_record_tags(txt: &str) -> Result<Vec<parser::Tag<'_>>, DKIMError> {
+    let (_, tags) = parser::tag_list(txt).map_err(|_| DKIMError::KeySyntaxError)?;
+    if parser::duplicate_tag(&tags).is_some() {
+        return Err(DKIMError::KeySyntaxError);
     }
+    Ok(tags)
+}
+
+/// Returns the decoded `p=` of a key record, which must be of `key_type`
+fn parse_key_record(txt: &str, key_type: &str) -> Result<Vec<u8>, DKIMError> {
+    let tags = key_record_tags(txt)?;
+    let tag = |name: &str| {
+        tags.iter()
+            .find(|tag| tag.name == name)
+            .map(|tag| tag.value())
+    };
 
     // Check version
-    if let Some(version) = tags_map.get("v") {
-        if version.value != "DKIM1" {
+    if let Some(version) = tag("v") {
+        if version != "DKIM1" {
             return Err(DKIMError::KeyIncompatibleVersion);
         }
     }
 
-    // Get key type
-    let key_type = match tags_map.get("k") {
-        Some(v) => {
-            if v.value != RSA_KEY_TYPE && v.value != ED25519_KEY_TYPE {
-                return Err(DKIMError::InappropriateKeyAlgorithm);
-            }
-            v.value.clone()
-        }
-        None => RSA_KEY_TYPE.to_string(),
-    };
+    // Check the key type
+    let record_key_type = tag("k").unwrap_or(Cow::Borrowed(RSA_KEY_TYPE));
+    if record_key_type != key_type {
+        return Err(DKIMError::InappropriateKeyAlgorithm);
+    }
 
-    let tag = tags_map.get("p").ok_or(DKIMError::NoKeyForSignature)?;
-    let bytes = base64::decode(&tag.value).map_err(|err| {
-        DKIMError::KeyUnavailable(format!("failed to decode public key: {}", err))
-    })?;
-    let key = if key_type == RSA_KEY_TYPE {
-        DkimPublicKey::Rsa(
-            pkcs8::DecodePublicKey::from_public_key_der(&bytes)
-                .or_else(|_| pkcs1::DecodeRsaPublicKey::from_pkcs1_der(&bytes))
-                .map_err(|err| {
-                    DKIMError::KeyUnavailable(format!("failed to parse public key: {}", err))
-                })?,
-        )
-    } else {
-        DkimPublicKey::Ed25519(ed25519_dalek::PublicKey::from_bytes(&bytes).map_err(|err| {
-            DKIMError::KeyUnavailable(format!("failed to parse public key: {}", err))
-        })?)
-    };
-    Ok(key)
+    let public_key = tag("p").ok_or(DKIMError::NoKeyForSignature)?;
+    base64::decode(&*public_key)
+        .map_err(|err| DKIMError::KeyUnavailable(format!("failed to decode public key: {}", err)))
 }
 
 #[cfg(test)]
@@ -79,7 +96,14 @@ mod tests {
         }
         let resolver = TestResolver {};
 
-        retrieve_public_key(&resolver, "cloudflare.com".to_string(), "dkim".to_string()).unwrap();
+        retrieve_public_keys(
+            &resolver,
+            "cloudflare.com".to_string(),
+            "dkim".to_string(),
+            None,
+            "rsa",
+        )
+        .unwrap();
     }
 
     #[test]
@@ -93,8 +117,14 @@ mod tests {
         }
         let resolver = TestResolver {};
 
-        let key = retrieve_public_key(&resolver, "cloudflare.com".to_string(), "dkim".to_string())
-            .unwrap_err();
+        let key = retrieve_public_keys(
+            &resolver,
+            "cloudflare.com".to_string(),
+            "dkim".to_string(),
+            None,
+            "rsa",
+        )
+        .unwrap_err();
         assert_eq!(key, DKIMError::KeyIncompatibleVersion);
     }
 
@@ -109,8 +139,46 @@ mod tests {
         }
         let resolver = TestResolver {};
 
-        let key = retrieve_public_key(&resolver, "cloudflare.com".to_string(), "dkim".to_string())
-            .unwrap_err();
+        let key = retrieve_public_keys(
+            &resolver,
+            "cloudflare.com".to_string(),
+            "dkim".to_string(),
+            None,
+            "rsa",
+        )
+        .unwrap_err();
         assert_eq!(key, DKIMError::InappropriateKeyAlgorithm);
     }
+
+    #[test]
+    fn test_retrieve_public_keys_rotation() {
+        struct TestResolver {}
+        impl dns::Lookup for TestResolver {
+            fn lookup_txt(&self, _: &str) -> Result<Vec<String>, DKIMError> {
+                Ok(vec![
+                    "v=DKIM1; k=ed25519; p=AAAA".to_string(),
+                    "v=DKIM1; p=AQID".to_string(),
+                    "v=DKIM6; p=BAUG".to_string(),
+                    "v=DKIM1; p=BwgJ".to_string(),
+                ])
+            }
+        }
+        let keys = |key_type| {
+            retrieve_public_keys(
+                &TestResolver {},
+                "cloudflare.com".to_string(),
+                "dkim".to_string(),
+                None,
+                key_type,
+            )
+        };
+
+        assert_eq!(keys("rsa").unwrap(), vec![vec![1, 2, 3], vec![7, 8, 9]]);
+        assert_eq!(keys("ed25519").unwrap(), vec![vec![0, 0, 0]]);
+        // the error of the first record
+        assert_eq!(
+            keys("foo").unwrap_err(),
+            DKIMError::InappropriateKeyAlgorithm
+        );
+    }
 }
@@ -1,9 +1,61 @@
-use crate::{canonicalization, DKIMError};
+use crate::prelude::*;
+use crate::{canonicalization, errors::Status, DKIMError, DkimSignature};
 
-#[derive(Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+#[cfg_attr(
+    feature = "borsh",
+    derive(borsh::BorshSerialize, borsh::BorshDeserialize)
+)]
+/// Result of a DKIM evaluation as defined in
+/// <https://datatracker.ietf.org/doc/html/rfc8601#section-2.7.1>
+pub enum AuthenticationResult {
+    /// The message was signed and the signature passed verification
+    Pass,
+    /// The message was signed, but the signature failed verification
+    Fail,
+    /// The message was not signed
+    Neutral,
+    /// The signature was valid but not acceptable to the verifier
+    Policy,
+    /// The verification could not be completed because of a temporary error
+    TempError,
+    /// The verification could not be completed because of a permanent error
+    PermError,
+}
+impl AuthenticationResult {
+    /// Value used in the `Authentication-Results` header
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Pass => "pass",
+            Self::Fail => "fail",
+            Self::Neutral => "neutral",
+            Self::Policy => "policy",
+            Self::TempError => "temperror",
+            Self::PermError => "permerror",
+        }
+    }
+}
+impl core::fmt::Display for AuthenticationResult {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "borsh",
+    derive(borsh::BorshSerialize, borsh::BorshDeserialize)
+)]
 /// Result of the DKIM verification
 pub struct DKIMResult {
-    value: &'static str,
+    #[cfg_attr(feature = "serde", serde(rename = "result"))]
+    value: AuthenticationResult,
     error: Option<DKIMError>,
     header_canonicalization_type: Option<canonicalization::Type>,
     body_canonicalization_type: Option<canonicalization::Type>,
@@ -15,7 +67,7 @@ impl DKIMResult {
         body_canonicalization_type: canonicalization::Type,
     ) -> Self {
         DKIMResult {
-            value: "pass",
+            value: AuthenticationResult::Pass,
             error: None,
             header_canonicalization_type: Some(header_canonicalization_type),
             body_canonicalization_type: Some(body_canonicalization_type),
@@ -24,16 +76,17 @@ impl DKIMResult {
     /// Constructs a `neutral` result
     pub fn neutral() -> Self {
         DKIMResult {
-            value: "neutral",
+            value: AuthenticationResult::Neutral,
             error: None,
             header_canonicalization_type: None,
             body_canonicalization_type: None,
         }
     }
-    /// Constructs a `fail` result with a reason
+    /// Constructs a failed result with a reason. The result is `fail`,
+    /// `policy`, `temperror` or `permerror` depending on the reason.
     pub fn fail(reason: DKIMError) -> Self {
         DKIMResult {
-            value: "fail",
+            value: reason.result(),
             error: Some(reason),
             header_canonicalization_type: None,
             body_canonicalization_type: None,
@@ -44,11 +97,28 @@ impl DKIMResult {
         self.error.clone()
     }
 
-    /// Returns the verification result as a summary: fail, neutral or pass.
-    pub fn summary(&self) -> &'static str {
+    /// Returns the RFC 8601 result
+    pub fn result(&self) -> AuthenticationResult {
         self.value
     }
 
+    /// Returns whether the failure is permanent or temporary, if any
+    pub fn status(&self) -> Option<Status> {
+        self.error.as_ref().map(|err| err.status())
+    }
+
+    /// Returns the stable numeric code of the failure, if any. See
+    /// [DKIMError::code].
+    pub fn code(&self) -> Option<u16> {
+        self.error.as_ref().map(|err| err.code())
+    }
+
+    /// Returns the verification result as a summary: pass, fail, neutral,
+    /// policy, temperror or permerror.
+    pub fn summary(&self) -> &'static str {
+        self.value.as_str()
+    }
+
     /// Returns the header canocalization type
     pub fn header_canonicalization_type(&self) -> Option<canonicalization::Type> {
         self.header_canonicalization_type.clone()
@@ -65,7 +135,50 @@ impl DKIMResult {
         if let Some(err) = self.error() {
             format!("{} ({})", self.value, err)
         } else {
-            self.value.to_owned()
+            self.value.to_string()
         }
     }
 }
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+/// Result of the verification of a single DKIM-Signature header
+pub struct SignatureResult {
+    /// The signature, if its tags could be parsed
+    pub signature: Option<DkimSignature>,
+    /// Result of its verification
+    pub result: DKIMResult,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fail_result() {
+        let result = DKIMResult::fail(DKIMError::BodyHashDidNotVerify);
+        assert_eq!(result.result(), AuthenticationResult::Fail);
+        assert_eq!(result.status(), Some(Status::Permfail));
+        assert_eq!(result.code(), Some(302));
+        assert_eq!(result.with_detail(), "fail (body hash did not verify)");
+
+        let result = DKIMResult::fail(DKIMError::KeyUnavailable("timeout".to_owned()));
+        assert_eq!(result.summary(), "temperror");
+        assert_eq!(result.status(), Some(Status::Tempfail));
+        assert_eq!(result.code(), Some(201));
+    }
+
+    #[test]
+    fn test_pass_and_neutral_result() {
+        use canonicalization::Type::Simple;
+
+        let result = DKIMResult::pass(Simple, Simple);
+        assert_eq!(result.result(), AuthenticationResult::Pass);
+        assert_eq!(result.status(), None);
+        assert_eq!(result.code(), None);
+
+        let result = DKIMResult::neutral();
+        assert_eq!(result.with_detail(), "neutral");
+        assert_eq!(result.code(), None);
+    }
+}
@@ -1,7 +1,10 @@
 #[cfg(test)]
 mod tests {
     use crate::{
-        dns, verify_email_with_resolver, DKIMError, DKIMResult, DkimPrivateKey, SignerBuilder,
+        canonicalization, dns, verify_email_signatures_with_resolver,
+        verify_email_signatures_with_trace, verify_email_with_resolver, verify_email_with_trace,
+        verify_raw_email_with_registry, verify_raw_email_with_resolver, AuthenticationResult,
+        DKIMError, DKIMResult, DkimPrivateKey, DkimSignature, HashAlgo, MultiSigner, SignerBuilder,
     };
     use chrono::TimeZone;
     use regex::Regex;
@@ -20,31 +23,37 @@ mod tests {
         out
     }
 
-    fn sign(domain: &str, raw_email: &str) -> String {
-        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
-
+    fn signer_builder(domain: &str) -> SignerBuilder<'_> {
         let private_key =
             rsa::RsaPrivateKey::read_pkcs1_pem_file(Path::new("./test/keys/2022.private")).unwrap();
         let time = chrono::Utc.ymd(2021, 1, 1).and_hms_milli(0, 0, 1, 444);
 
-        let signer = SignerBuilder::new()
+        SignerBuilder::new()
             .with_signed_headers(&["From", "Subject"])
             .unwrap()
             .with_private_key(DkimPrivateKey::Rsa(private_key))
             .with_selector("2022")
             .with_signing_domain(domain)
             .with_time(time)
-            .build()
-            .unwrap();
-        let header = signer.sign(&email).unwrap();
+    }
+
+    fn sign(domain: &str, raw_email: &str) -> String {
+        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
 
-        format!("{}\n{}", header, raw_email)
+        let signer = signer_builder(domain).build().unwrap();
+        String::from_utf8(signer.sign_message(&email).unwrap()).unwrap()
     }
 
-    fn verify<T: dns::Lookup>(resolver: &T, raw_email: &str) -> DKIMResult {
+    fn verify<T: dns::TimedLookup>(resolver: &T, raw_email: &str) -> DKIMResult {
         let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
 
-        verify_email_with_resolver(&email, resolver).unwrap()
+        let result = verify_email_with_resolver(&email, resolver).unwrap();
+        assert_eq!(
+            verify_raw_email_with_resolver(raw_email.as_bytes(), resolver, chrono::Utc::now())
+                .unwrap(),
+            result
+        );
+        result
     }
 
     macro_rules! map {
@@ -63,17 +72,18 @@ mod tests {
         db: HashMap<&'static str, String>,
     }
 
-    fn test_resolver(db: HashMap<&'static str, String>) -> TestResolver {
-        impl dns::Lookup for TestResolver {
-            fn lookup_txt(&self, name: &str) -> Result<Vec<String>, DKIMError> {
-                let res = if let Some(value) = self.db.get(name) {
-                    vec![value.to_string()]
-                } else {
-                    unreachable!("attempted to resolve: {}", name)
-                };
-                Ok(res)
-            }
+    impl dns::Lookup for TestResolver {
+        fn lookup_txt(&self, name: &str) -> Result<Vec<String>, DKIMError> {
+            let res = if let Some(value) = self.db.get(name) {
+                vec![value.to_string()]
+            } else {
+                unreachable!("attempted to resolve: {}", name)
+            };
+            Ok(res)
         }
+    }
+
+    fn test_resolver(db: HashMap<&'static str, String>) -> TestResolver {
         TestResolver { db }
     }
 
@@ -164,4 +174,434 @@ sentation" style=3D"width:100%;">
             assert_eq!(res.with_detail(), "pass")
         }
     }
+
+    #[test]
+    fn test_roundtrip_trace() {
+        let resolver = test_resolver(map! {
+            "2022._domainkey.cloudflare.com" => dkim_record()
+        });
+        let email = r#"Subject: subject
+From: Sven Sauleau <sven@cloudflare.com>
+
+Hello Alice
+"#
+        .replace('\n', "\r\n");
+        let signed_email = sign("cloudflare.com", &email);
+
+        let email = mailparse::parse_mail(signed_email.as_bytes()).unwrap();
+        let (res, traces) = verify_email_with_trace(&email, &resolver).unwrap();
+        assert_eq!(res.with_detail(), "pass");
+        assert_eq!(traces.len(), 1);
+        assert_eq!(traces[0].error, None);
+        assert_eq!(traces[0].canonicalized_body, b"Hello Alice\r\n");
+        assert_eq!(
+            traces[0]
+                .selected_headers
+                .iter()
+                .map(|h| (h.index, h.name.as_str()))
+                .collect::<Vec<_>>(),
+            vec![(2, "From"), (1, "Subject")]
+        );
+        assert!(String::from_utf8_lossy(&traces[0].header_hash_input)
+            .starts_with("From: Sven Sauleau <sven@cloudflare.com>\r\nSubject: subject\r\n"));
+
+        let (results, signature_traces) =
+            verify_email_signatures_with_trace(&email, &resolver).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].result.with_detail(), "pass");
+        assert_eq!(signature_traces, traces);
+
+        let tampered_email = signed_email.replace("Hello Alice", "Hello Bob");
+        let email = mailparse::parse_mail(tampered_email.as_bytes()).unwrap();
+        let (res, traces) = verify_email_with_trace(&email, &resolver).unwrap();
+        assert_eq!(res.error(), Some(DKIMError::BodyHashDidNotVerify));
+        assert_eq!(traces[0].error, Some(DKIMError::BodyHashDidNotVerify));
+        assert_eq!(traces[0].canonicalized_body, b"Hello Bob\r\n");
+        assert_ne!(traces[0].computed_body_hash, traces[0].signed_body_hash);
+    }
+
+    #[test]
+    fn test_roundtrip_raw_email_expiry() {
+        let resolver = test_resolver(map! {
+            "2022._domainkey.cloudflare.com" => dkim_record()
+        });
+        let email =
+            "Subject: subject\r\nFrom: Sven Sauleau <sven@cloudflare.com>\r\n\r\nHello Alice\r\n";
+
+        let time = chrono::Utc.ymd(2021, 1, 1).and_hms(0, 0, 0);
+        let signer = signer_builder("cloudflare.com")
+            .with_time(time)
+            .with_expiry(chrono::Duration::hours(1))
+            .build()
+            .unwrap();
+        let header = signer
+            .sign(&mailparse::parse_mail(email.as_bytes()).unwrap())
+            .unwrap();
+        let signed_email = format!("{}\r\n{}", header, email);
+
+        let res = verify_raw_email_with_resolver(signed_email.as_bytes(), &resolver, time).unwrap();
+        assert_eq!(res.with_detail(), "pass");
+
+        let later = time + chrono::Duration::hours(2);
+        let res =
+            verify_raw_email_with_resolver(signed_email.as_bytes(), &resolver, later).unwrap();
+        assert_eq!(res.result(), AuthenticationResult::Policy);
+        assert_eq!(res.error(), Some(DKIMError::SignatureExpired));
+    }
+
+    #[test]
+    fn test_roundtrip_optional_tags() {
+        let resolver = test_resolver(map! {
+            "2022._domainkey.cloudflare.com" => dkim_record()
+        });
+        let email =
+            "Subject: demo; run\r\nFrom: Sven Sauleau <sven@cloudflare.com>\r\n\r\nHello Alice\r\n";
+
+        let signer = signer_builder("cloudflare.com")
+            .with_auid("sven@cloudflare.com")
+            .with_body_length(13)
+            .with_copied_headers(true)
+            .with_query_method("dns/txt")
+            .with_extension_tag("foo", "bar baz")
+            .unwrap()
+            .build()
+            .unwrap();
+        let header = signer
+            .sign(&mailparse::parse_mail(email.as_bytes()).unwrap())
+            .unwrap();
+
+        let signature =
+            DkimSignature::parse(header.strip_prefix("DKIM-Signature:").unwrap()).unwrap();
+        assert_eq!(signature.auid.as_deref(), Some("sven@cloudflare.com"));
+        assert_eq!(signature.body_length, Some(13));
+        assert_eq!(signature.query_method.as_deref(), Some("dns/txt"));
+        assert_eq!(
+            signature.copied_headers,
+            vec![
+                (
+                    "From".to_owned(),
+                    "Sven Sauleau <sven@cloudflare.com>".to_owned()
+                ),
+                ("Subject".to_owned(), "demo; run".to_owned())
+            ]
+        );
+        assert_eq!(
+            signature.extensions(),
+            [("foo".to_owned(), "bar baz".to_owned())]
+        );
+
+        let signed_email = format!("{}\r\n{}", header, email);
+        assert_eq!(verify(&resolver, &signed_email).with_detail(), "pass");
+
+        // content after `l=` isn't signed
+        let appended_email = format!("{}Unsigned footer\r\n", signed_email);
+        assert_eq!(verify(&resolver, &appended_email).with_detail(), "pass");
+
+        let tampered_email = signed_email.replace("Hello Alice", "Hello Bob");
+        assert_eq!(
+            verify(&resolver, &tampered_email).error(),
+            Some(DKIMError::BodyHashDidNotVerify)
+        );
+    }
+
+    #[test]
+    fn test_roundtrip_sign_message_folded() {
+        let resolver = test_resolver(map! {
+            "2022._domainkey.cloudflare.com" => dkim_record()
+        });
+        let email = "From: Sven Sauleau <sven@cloudflare.com>\r\nTo: alice@example.com\r\nSubject:   folded\r\n  subject\r\nDate: Fri, 1 Jan 2021 00:00:00 +0000\r\nMessage-ID: <1@cloudflare.com>\r\n\r\nHello Alice\r\n";
+        let parsed_email = mailparse::parse_mail(email.as_bytes()).unwrap();
+
+        for canonicalization in [
+            canonicalization::Type::Simple,
+            canonicalization::Type::Relaxed,
+        ] {
+            let signer = signer_builder("cloudflare.com")
+                .with_signed_headers(&[
+                    "From",
+                    "To",
+                    "Subject",
+                    "Date",
+                    "Message-ID",
+                    "MIME-Version",
+                    "Content-Type",
+                    "Content-Transfer-Encoding",
+                ])
+                .unwrap()
+                .with_header_canonicalization(canonicalization.clone())
+                .with_body_canonicalization(canonicalization)
+                .build()
+                .unwrap();
+            let signed_email =
+                String::from_utf8(signer.sign_message(&parsed_email).unwrap()).unwrap();
+            assert!(signed_email.ends_with(email));
+
+            let header = signed_email.strip_suffix(email).unwrap();
+            let lines: Vec<&str> = header.trim_end().split("\r\n").collect();
+            assert!(lines.iter().all(|line| line.len() <= 78), "{}", header);
+            // folded inside `h=` and `b=`
+            let unterminated = |tag: &str| {
+                lines.iter().any(|line| match line.split_once(tag) {
+                    Some((_, value)) => !value.contains(';'),
+                    None => false,
+                })
+            };
+            assert!(unterminated(" h="));
+            assert!(unterminated(" b="));
+
+            assert_eq!(verify(&resolver, &signed_email).with_detail(), "pass");
+        }
+    }
+
+    #[test]
+    fn test_roundtrip_overlapping_keys() {
+        // another RSA key, valid during the same window
+        let other_record = "v=DKIM1; p=MIGJAoGBALVI635dLK4cJJAH3Lx6upo3X/Lm1tQz3mezcWTA3BUBnyIsdnRf57aD5BtNmhPrYYDlWlzw3UgnKisIxktkk5+iMQMlFtAS10JB8L3YadXNJY+JBcbeSi5TgJe4WFzNgW95FWDAuSTRXSWZfA/8xjflbTLDx0euFZOM7C4T0GwLAgMBAAE=";
+        let email = sign(
+            "example.com",
+            "From: Sven <sven@example.com>\r\nSubject: hello\r\n\r\nHi\r\n",
+        );
+        let resolver = |records: &[String]| {
+            let mut resolver = dns::HistoricalLookup::new(TestResolver { db: HashMap::new() });
+            for record in records {
+                resolver.add_record(
+                    "2022._domainkey.example.com",
+                    dns::HistoricalRecord {
+                        record: record.clone(),
+                        not_before: None,
+                        not_after: Some(chrono::Utc.ymd(2022, 1, 1).and_hms(0, 0, 0)),
+                    },
+                );
+            }
+            resolver
+        };
+
+        let records = [other_record.to_owned(), dkim_record()];
+        assert_eq!(verify(&resolver(&records), &email).summary(), "pass");
+        let records = [dkim_record(), other_record.to_owned()];
+        assert_eq!(verify(&resolver(&records), &email).summary(), "pass");
+        assert_eq!(
+            verify(&resolver(&records[1..]), &email).error(),
+            Some(DKIMError::SignatureDidNotVerify)
+        );
+    }
+
+    #[test]
+    fn test_roundtrip_rotated_key() {
+        // the key was revoked after the email was signed
+        let resolver = TestResolver {
+            db: map! { "2022._domainkey.example.com" => "v=DKIM1; k=rsa; p=".to_owned() },
+        };
+        let mut resolver = dns::HistoricalLookup::new(resolver);
+        let raw_email = "From: Sven <sven@example.com>\r\nSubject: hello\r\n\r\nHi\r\n";
+        let email = sign("example.com", raw_email);
+        assert!(matches!(
+            verify(&resolver, &email).error(),
+            Some(DKIMError::KeyUnavailable(_))
+        ));
+
+        resolver.add_record(
+            "2022._domainkey.example.com",
+            dns::HistoricalRecord {
+                record: dkim_record(),
+                not_before: None,
+                not_after: Some(chrono::Utc.ymd(2022, 1, 1).and_hms(0, 0, 0)),
+            },
+        );
+        assert_eq!(verify(&resolver, &email).summary(), "pass");
+
+        // signed after the rotation
+        let email = signer_builder("example.com")
+            .with_time(chrono::Utc.ymd(2022, 6, 1).and_hms(0, 0, 0))
+            .build()
+            .unwrap()
+            .sign_message(&mailparse::parse_mail(raw_email.as_bytes()).unwrap())
+            .unwrap();
+        assert!(matches!(
+            verify(&resolver, &String::from_utf8(email).unwrap()).error(),
+            Some(DKIMError::KeyUnavailable(_))
+        ));
+    }
+
+    #[test]
+    fn test_roundtrip_registered_algorithm() {
+        use crate::algorithm::{AlgorithmRegistry, RsaSha256, SignatureAlgorithm};
+        use crate::SigningBackend;
+
+        /// rsa-sha256 under another name
+        struct Experimental;
+        impl SignatureAlgorithm for Experimental {
+            fn name(&self) -> &str {
+                "x-experimental"
+            }
+            fn key_type(&self) -> &str {
+                "rsa"
+            }
+            fn digest(&self, data: &[u8]) -> Vec<u8> {
+                RsaSha256.digest(data)
+            }
+            fn verify(&self, key: &[u8], digest: &[u8], sig: &[u8]) -> Result<bool, DKIMError> {
+                RsaSha256.verify(key, digest, sig)
+            }
+            fn sign(&self, key: &DkimPrivateKey, digest: &[u8]) -> Result<Vec<u8>, DKIMError> {
+                RsaSha256.sign(key, digest)
+            }
+        }
+        struct ExperimentalBackend(DkimPrivateKey);
+        impl SigningBackend for ExperimentalBackend {
+            fn algorithm(&self) -> HashAlgo {
+                HashAlgo::Other("x-experimental".to_owned())
+            }
+            fn public_key(&self) -> Result<Vec<u8>, DKIMError> {
+                self.0.public_key()
+            }
+            fn sign(&self, digest: &[u8]) -> Result<Vec<u8>, DKIMError> {
+                Experimental.sign(&self.0, digest)
+            }
+        }
+
+        let private_key =
+            rsa::RsaPrivateKey::read_pkcs1_pem_file(Path::new("./test/keys/2022.private")).unwrap();
+        let backend = ExperimentalBackend(DkimPrivateKey::Rsa(private_key));
+        let email = mailparse::parse_mail(b"From: sven@example.com\r\n\r\nHi\r\n").unwrap();
+        let builder = SignerBuilder::new()
+            .with_signed_headers(&["From"])
+            .unwrap()
+            .with_signing_backend(&backend)
+            .with_selector("2022")
+            .with_signing_domain("example.com");
+        assert_eq!(
+            builder.build().err(),
+            Some(DKIMError::UnsupportedHashAlgorithm(
+                "x-experimental".to_owned()
+            ))
+        );
+
+        let mut registry = AlgorithmRegistry::default();
+        registry.register(Experimental);
+        let signer = SignerBuilder::new()
+            .with_signed_headers(&["From"])
+            .unwrap()
+            .with_signing_backend(&backend)
+            .with_selector("2022")
+            .with_signing_domain("example.com")
+            .with_registry(&registry)
+            .build()
+            .unwrap();
+        let signed_email = signer.sign_message(&email).unwrap();
+        assert!(signed_email.starts_with(b"DKIM-Signature: v=1; a=x-experimental;"));
+
+        let resolver = test_resolver(map! { "2022._domainkey.example.com" => dkim_record() });
+        let now = chrono::Utc::now();
+        let res = verify_raw_email_with_resolver(&signed_email, &resolver, now).unwrap();
+        assert_eq!(
+            res.error(),
+            Some(DKIMError::UnsupportedHashAlgorithm(
+                "x-experimental".to_owned()
+            ))
+        );
+        let res = verify_raw_email_with_registry(&signed_email, &resolver, &registry, now).unwrap();
+        assert_eq!(res.summary(), "pass");
+
+        // the model and the key record also resolve the algorithm in the registry
+        let parsed = mailparse::parse_mail(&signed_email).unwrap();
+        let value =
+            mailparse::MailHeaderMap::get_first_value(&parsed.get_headers(), "DKIM-Signature")
+                .unwrap();
+        assert_eq!(
+            DkimSignature::parse(&value).unwrap_err(),
+            DKIMError::UnsupportedHashAlgorithm("x-experimental".to_owned())
+        );
+        assert_eq!(
+            DkimSignature::parse_with_registry(&value, &registry)
+                .unwrap()
+                .algorithm,
+            HashAlgo::Other("x-experimental".to_owned())
+        );
+        #[cfg(feature = "keys")]
+        {
+            use crate::keys::DkimRecord;
+            assert!(DkimRecord::from_backend(&backend).is_err());
+            let record = DkimRecord::from_backend_with_registry(&backend, &registry).unwrap();
+            assert_eq!(record.key_type, "rsa");
+        }
+    }
+
+    #[test]
+    fn test_roundtrip_pinned_key() {
+        let email = sign(
+            "example.com",
+            "From: Sven <sven@example.com>\r\nSubject: hello\r\n\r\nHi\r\n",
+        );
+        let mut resolver = dns::PinnedKeyLookup::new();
+        resolver.supply_record("example.com", "2022", &dkim_record());
+        assert_eq!(
+            verify(&resolver, &email).error(),
+            Some(DKIMError::KeyNotPinned)
+        );
+
+        // openssl pkey -in test/keys/2022.private -pubout -outform DER | sha256sum
+        let pin = dns::key_record_pin(&dkim_record()).unwrap();
+        assert_eq!(
+            pin.iter().map(|b| format!("{:02x}", b)).collect::<String>(),
+            "5df255d2b52f00648b9d6f1621d9a4754c126bed7064dfff5a9f0a1ead80f67f"
+        );
+        resolver.add_pin("example.com", "2022", pin);
+        assert_eq!(verify(&resolver, &email).summary(), "pass");
+    }
+
+    #[cfg(feature = "ed25519")]
+    #[test]
+    fn test_roundtrip_multi_signer() {
+        let ed_public_key = std::fs::read_to_string("./test/keys/ed.public").unwrap();
+        let resolver = test_resolver(map! {
+            "2022._domainkey.cloudflare.com" => dkim_record(),
+            "ed._domainkey.cloudflare.com" => format!("v=DKIM1; k=ed25519; p={}", ed_public_key)
+        });
+        let email =
+            "From: Sven Sauleau <sven@cloudflare.com>\r\nSubject: subject\r\n\r\nHello Alice\r\n";
+
+        let secret_key = base64::decode(std::fs::read("./test/keys/ed.private").unwrap()).unwrap();
+        let keypair = ed25519_dalek::Keypair {
+            secret: ed25519_dalek::SecretKey::from_bytes(&secret_key).unwrap(),
+            public: ed25519_dalek::PublicKey::from_bytes(&base64::decode(&ed_public_key).unwrap())
+                .unwrap(),
+        };
+        let rsa_signer = signer_builder("cloudflare.com").build().unwrap();
+        let ed_signer = signer_builder("cloudflare.com")
+            .with_private_key(DkimPrivateKey::Ed25519(keypair))
+            .with_selector("ed")
+            .with_header_canonicalization(canonicalization::Type::Relaxed)
+            .with_body_canonicalization(canonicalization::Type::Relaxed)
+            .build()
+            .unwrap();
+        let multi_signer = MultiSigner::new(vec![rsa_signer, ed_signer]).unwrap();
+
+        let parsed_email = mailparse::parse_mail(email.as_bytes()).unwrap();
+        assert_eq!(multi_signer.sign(&parsed_email).unwrap().len(), 2);
+        let signed_email =
+            String::from_utf8(multi_signer.sign_message(&parsed_email).unwrap()).unwrap();
+        assert_eq!(verify(&resolver, &signed_email).with_detail(), "pass");
+
+        let signed_email = mailparse::parse_mail(signed_email.as_bytes()).unwrap();
+        let results = verify_email_signatures_with_resolver(&signed_email, &resolver).unwrap();
+        let summary: Vec<_> = results
+            .iter()
+            .map(|res| {
+                let signature = res.signature.as_ref().unwrap();
+                (
+                    signature.algorithm.clone(),
+                    signature.selector.as_str(),
+                    res.result.result(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (HashAlgo::RsaSha256, "2022", AuthenticationResult::Pass),
+                (HashAlgo::Ed25519Sha256, "ed", AuthenticationResult::Pass),
+            ]
+        );
+    }
 }
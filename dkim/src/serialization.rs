@@ -0,0 +1,388 @@
+//! Serialized forms of the public types, behind the `serde` and `borsh`
+//! features. These forms are stable.
+//!
+//! With `serde`:
+//! - [crate::canonicalization::Type] is `"simple"` or `"relaxed"`, [HashAlgo] is
+//!   its `a=` value, [crate::AuthenticationResult] is its
+//!   `Authentication-Results` value and [crate::Status] is `"permfail"` or
+//!   `"tempfail"`.
+//! - [DKIMError] is `{"code": 302, "result": "fail", "message": "body hash did
+//!   not verify", "detail": null}`, where `detail` is the payload of the error.
+//!   Only `code` and `detail` are read back. Builder errors (402) are never
+//!   the result of a verification and are not read back.
+//! - [crate::DKIMResult] is `{"result": "pass", "error": null,
+//!   "header_canonicalization_type": "relaxed", "body_canonicalization_type":
+//!   "simple"}`.
+//! - Binary data (`b=`, `bh=` and the canonicalized inputs of a trace) is
+//!   base64 and timestamps are RFC 3339.
+//!
+//! With `borsh`, structs and enums use the derived encoding, [DKIMError] is
+//! its `(u16, Option<String>)` code and detail, and [DkimSignature] is its
+//! unfolded header value.
+use crate::header::REQUIRED_TAGS;
+use crate::prelude::*;
+use crate::DKIMError;
+#[cfg(feature = "borsh")]
+use crate::DkimSignature;
+#[cfg(feature = "serde")]
+use crate::HashAlgo;
+
+/// Payload of the error, if any
+fn error_detail(err: &DKIMError) -> Option<String> {
+    use DKIMError::*;
+    match err {
+        UnsupportedHashAlgorithm(v)
+        | UnsupportedCanonicalizationType(v)
+        | SignatureSyntaxError(v)
+        | KeyUnavailable(v)
+        | UnknownInternalError(v)
+        | FailedToSign(v)
+        | InvalidPrivateKey(v) => Some(v.clone()),
+        SignatureMissingRequiredTag(v) | BuilderError(v) => Some(v.to_string()),
+        _ => None,
+    }
+}
+
+/// Inverse of [DKIMError::code] and [error_detail]
+fn error_from_parts(code: u16, detail: Option<String>) -> Result<DKIMError, String> {
+    use DKIMError::*;
+    let detail = detail.unwrap_or_default();
+    Ok(match code {
+        101 => UnsupportedHashAlgorithm(detail),
+        102 => UnsupportedCanonicalizationType(detail),
+        103 => SignatureSyntaxError(detail),
+        104 => SignatureMissingRequiredTag(
+            REQUIRED_TAGS
+                .iter()
+                .find(|tag| **tag == detail)
+                .ok_or_else(|| format!("unknown required tag: {}", detail))?,
+        ),
+        105 => IncompatibleVersion,
+        106 => DomainMismatch,
+        107 => FromFieldNotSigned,
+        108 => SignatureExpired,
+        109 => UnacceptableSignatureHeader,
+        110 => UnsupportedQueryMethod,
+        201 => KeyUnavailable(detail),
+        202 => NoKeyForSignature,
+        203 => KeySyntaxError,
+        204 => KeyIncompatibleVersion,
+        205 => InappropriateKeyAlgorithm,
+        206 => KeyNotPinned,
+        301 => SignatureDidNotVerify,
+        302 => BodyHashDidNotVerify,
+        303 => MalformedBody,
+        401 => FailedToSign(detail),
+        // Builder errors only carry static messages
+        402 => return Err(format!("builder error can't be read back: {}", detail)),
+        403 => InvalidPrivateKey(detail),
+        501 => UnknownInternalError(detail),
+        code => return Err(format!("unknown error code: {}", code)),
+    })
+}
+
+#[cfg(feature = "serde")]
+mod serde_impls {
+    use super::*;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Serialize)]
+    struct ErrorOut {
+        code: u16,
+        result: crate::AuthenticationResult,
+        message: String,
+        detail: Option<String>,
+    }
+
+    #[derive(Deserialize)]
+    struct ErrorIn {
+        code: u16,
+        #[serde(default)]
+        detail: Option<String>,
+    }
+
+    impl Serialize for HashAlgo {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.collect_str(self)
+        }
+    }
+
+    impl<'de> Deserialize<'de> for HashAlgo {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            Ok(HashAlgo::from_name(&String::deserialize(deserializer)?))
+        }
+    }
+
+    impl Serialize for DKIMError {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            ErrorOut {
+                code: self.code(),
+                result: self.result(),
+                message: self.to_string(),
+                detail: error_detail(self),
+            }
+            .serialize(serializer)
+        }
+    }
+
+    impl<'de> Deserialize<'de> for DKIMError {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            let err = ErrorIn::deserialize(deserializer)?;
+            error_from_parts(err.code, err.detail).map_err(serde::de::Error::custom)
+        }
+    }
+}
+
+/// Serializes bytes as a base64 string. To be used with `#[serde(with)]`.
+#[cfg(feature = "serde")]
+pub(crate) mod base64_bytes {
+    use crate::prelude::*;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub(crate) fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&base64::encode(bytes))
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<u8>, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        base64::decode(value).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(feature = "borsh")]
+mod borsh_impls {
+    use super::*;
+    use borsh::maybestd::io;
+    use borsh::{BorshDeserialize, BorshSerialize};
+
+    impl BorshSerialize for DKIMError {
+        fn serialize<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+            self.code().serialize(writer)?;
+            error_detail(self).serialize(writer)
+        }
+    }
+
+    impl BorshDeserialize for DKIMError {
+        fn deserialize(buf: &mut &[u8]) -> io::Result<Self> {
+            let code = u16::deserialize(buf)?;
+            let detail = Option::<String>::deserialize(buf)?;
+            error_from_parts(code, detail)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+        }
+    }
+
+    impl BorshSerialize for DkimSignature {
+        fn serialize<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+            self.to_header_value().serialize(writer)
+        }
+    }
+
+    impl BorshDeserialize for DkimSignature {
+        fn deserialize(buf: &mut &[u8]) -> io::Result<Self> {
+            let value = String::deserialize(buf)?;
+            DkimSignature::parse_any_algorithm(&value)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canonicalization::Type;
+    use crate::trace::{CopiedHeaderDiff, SelectedHeader, SignatureTrace};
+    use crate::{AuthenticationResult, DKIMResult, DkimSignature, HashAlgo, Status, Tag};
+
+    fn all_errors() -> Vec<DKIMError> {
+        use DKIMError::*;
+        vec![
+            UnsupportedHashAlgorithm("rsa-md5".to_owned()),
+            UnsupportedCanonicalizationType("foo".to_owned()),
+            SignatureSyntaxError("oops".to_owned()),
+            SignatureMissingRequiredTag("bh"),
+            IncompatibleVersion,
+            DomainMismatch,
+            FromFieldNotSigned,
+            SignatureExpired,
+            UnacceptableSignatureHeader,
+            UnsupportedQueryMethod,
+            KeyUnavailable("timeout".to_owned()),
+            UnknownInternalError("oops".to_owned()),
+            NoKeyForSignature,
+            KeySyntaxError,
+            KeyIncompatibleVersion,
+            InappropriateKeyAlgorithm,
+            KeyNotPinned,
+            SignatureDidNotVerify,
+            BodyHashDidNotVerify,
+            MalformedBody,
+            FailedToSign("oops".to_owned()),
+            InvalidPrivateKey("not PEM".to_owned()),
+        ]
+    }
+
+    fn signature() -> DkimSignature {
+        DkimSignature::parse(
+            "v=1; a=ed25519-sha256; c=relaxed/simple; d=example.com; s=sel; \
+             i=@example.com; t=1528637909; x=1528647909; l=12; h=from:subject; \
+             z=Subject:demo=20run; foo=bar; bh=MTIz; b=NDU2",
+        )
+        .unwrap()
+    }
+
+    fn trace() -> SignatureTrace {
+        SignatureTrace {
+            header: "v=1".to_owned(),
+            canonicalized_body: b"Hi.\r\n".to_vec(),
+            computed_body_hash: Some("abc".to_owned()),
+            signed_body_hash: None,
+            selected_headers: vec![SelectedHeader {
+                index: 2,
+                name: "From".to_owned(),
+                value: " joe@example.com".to_owned(),
+            }],
+            header_hash_input: b"from:joe@example.com\r\n".to_vec(),
+            copied_headers_diff: vec![CopiedHeaderDiff {
+                name: "Subject".to_owned(),
+                copied_value: "a".to_owned(),
+                message_value: None,
+            }],
+            error: Some(DKIMError::SignatureDidNotVerify),
+        }
+    }
+
+    #[test]
+    fn test_error_parts() {
+        for err in all_errors() {
+            assert_eq!(error_from_parts(err.code(), error_detail(&err)), Ok(err));
+        }
+        assert!(error_from_parts(999, None).is_err());
+        assert!(error_from_parts(104, Some("zz".to_owned())).is_err());
+        let err = DKIMError::BuilderError("missing required selector");
+        assert!(error_from_parts(err.code(), error_detail(&err)).is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    fn serde_roundtrip<T>(value: &T) -> T
+    where
+        T: serde::Serialize + serde::de::DeserializeOwned,
+    {
+        serde_json::from_str(&serde_json::to_string(value).unwrap()).unwrap()
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_roundtrip() {
+        for err in all_errors() {
+            assert_eq!(serde_roundtrip(&err), err);
+        }
+        let results = vec![
+            DKIMResult::pass(Type::Relaxed, Type::Simple),
+            DKIMResult::neutral(),
+            DKIMResult::fail(DKIMError::KeyUnavailable("timeout".to_owned())),
+        ];
+        for result in results {
+            assert_eq!(serde_roundtrip(&result), result);
+        }
+        // Tags borrow from the serialized form
+        let tag = Tag {
+            name: "b",
+            raw_value: "a b",
+        };
+        let json = serde_json::to_string(&tag).unwrap();
+        assert_eq!(serde_json::from_str::<Tag>(&json).unwrap(), tag);
+        assert_eq!(serde_roundtrip(&signature()), signature());
+        assert_eq!(serde_roundtrip(&trace()), trace());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_forms() {
+        use serde_json::json;
+
+        assert_eq!(
+            serde_json::to_value(Type::Relaxed).unwrap(),
+            json!("relaxed")
+        );
+        assert_eq!(
+            serde_json::to_value(HashAlgo::Ed25519Sha256).unwrap(),
+            json!("ed25519-sha256")
+        );
+        assert_eq!(
+            serde_json::from_value::<HashAlgo>(json!("x-pq-sha256")).unwrap(),
+            HashAlgo::Other("x-pq-sha256".to_owned())
+        );
+        assert_eq!(
+            serde_json::to_value(AuthenticationResult::TempError).unwrap(),
+            json!("temperror")
+        );
+        assert_eq!(
+            serde_json::to_value(Status::Permfail).unwrap(),
+            json!("permfail")
+        );
+        assert_eq!(
+            serde_json::to_value(DKIMError::KeyUnavailable("timeout".to_owned())).unwrap(),
+            json!({
+                "code": 201,
+                "result": "temperror",
+                "message": "key unavailable: timeout",
+                "detail": "timeout",
+            })
+        );
+        assert_eq!(
+            serde_json::to_value(DKIMResult::pass(Type::Relaxed, Type::Simple)).unwrap(),
+            json!({
+                "result": "pass",
+                "error": null,
+                "header_canonicalization_type": "relaxed",
+                "body_canonicalization_type": "simple",
+            })
+        );
+
+        let value = serde_json::to_value(signature()).unwrap();
+        assert_eq!(value["body_hash"], json!("MTIz"));
+        assert_eq!(value["timestamp"], json!("2018-06-10T13:38:29Z"));
+        assert_eq!(value["copied_headers"], json!([["Subject", "demo run"]]));
+    }
+
+    #[cfg(feature = "borsh")]
+    #[test]
+    fn test_borsh_roundtrip() {
+        use borsh::{BorshDeserialize, BorshSerialize};
+
+        fn roundtrip<T: BorshSerialize + BorshDeserialize>(value: &T) -> T {
+            T::try_from_slice(&value.try_to_vec().unwrap()).unwrap()
+        }
+
+        for err in all_errors() {
+            assert_eq!(roundtrip(&err), err);
+        }
+        let results = vec![
+            DKIMResult::pass(Type::Relaxed, Type::Simple),
+            DKIMResult::neutral(),
+            DKIMResult::fail(DKIMError::BodyHashDidNotVerify),
+        ];
+        for result in results {
+            assert_eq!(roundtrip(&result), result);
+        }
+        assert_eq!(roundtrip(&signature()), signature());
+        assert_eq!(roundtrip(&trace()), trace());
+        assert_eq!(roundtrip(&HashAlgo::RsaSha1), HashAlgo::RsaSha1);
+        assert_eq!(roundtrip(&Status::Tempfail), Status::Tempfail);
+        assert_eq!(
+            roundtrip(&AuthenticationResult::Policy),
+            AuthenticationResult::Policy
+        );
+        // Tags borrow their strings, so they are only serialized
+        let tag = Tag {
+            name: "b",
+            raw_value: "a b",
+        };
+        assert_eq!(
+            tag.try_to_vec().unwrap(),
+            ("b", "a b").try_to_vec().unwrap()
+        );
+    }
+}
@@ -1,18 +1,36 @@
-use ed25519_dalek::ExpandedSecretKey;
+use alloc::sync::Arc;
 
-use crate::header::DKIMHeaderBuilder;
-use crate::{canonicalization, hash, DKIMError, DkimPrivateKey, HEADER};
+use crate::header::{fold_tags, DKIMHeader, DKIMHeaderBuilder, HEADER};
+use crate::message::Message;
+use crate::prelude::*;
+use crate::signature::{check_extension_tag, dqp_encode, encode_copied_headers, FOLD_WIDTH};
+use crate::{
+    canonicalization, hash, AlgorithmRegistry, DKIMError, DkimPrivateKey, SignatureAlgorithm,
+    SigningBackend,
+};
 
 /// Builder for the Signer
 pub struct SignerBuilder<'a> {
     signed_headers: Option<&'a [&'a str]>,
-    private_key: Option<DkimPrivateKey>,
+    backend: Option<Box<dyn SigningBackend + 'a>>,
     selector: Option<&'a str>,
     signing_domain: Option<&'a str>,
     time: Option<chrono::DateTime<chrono::offset::Utc>>,
     header_canonicalization: canonicalization::Type,
     body_canonicalization: canonicalization::Type,
     expiry: Option<chrono::Duration>,
+    auid: Option<&'a str>,
+    body_length: Option<usize>,
+    copy_headers: bool,
+    query_method: Option<&'a str>,
+    extension_tags: Vec<(&'a str, &'a str)>,
+    registry: Option<AlgorithmRegistry>,
+}
+
+impl<'a> Default for SignerBuilder<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<'a> SignerBuilder<'a> {
@@ -20,11 +38,17 @@ impl<'a> SignerBuilder<'a> {
     pub fn new() -> Self {
         Self {
             signed_headers: None,
-            private_key: None,
+            backend: None,
             selector: None,
             signing_domain: None,
             expiry: None,
             time: None,
+            auid: None,
+            body_length: None,
+            copy_headers: false,
+            query_method: None,
+            extension_tags: vec![],
+            registry: None,
 
             header_canonicalization: canonicalization::Type::Simple,
             body_canonicalization: canonicalization::Type::Simple,
@@ -44,8 +68,14 @@ impl<'a> SignerBuilder<'a> {
     }
 
     /// Specify the private key used to sign the email
-    pub fn with_private_key(mut self, key: DkimPrivateKey) -> Self {
-        self.private_key = Some(key);
+    pub fn with_private_key(self, key: DkimPrivateKey) -> Self {
+        self.with_signing_backend(key)
+    }
+
+    /// Specify the backend producing the signatures, when the private key
+    /// isn't held in memory
+    pub fn with_signing_backend<B: SigningBackend + 'a>(mut self, backend: B) -> Self {
+        self.backend = Some(Box::new(backend));
         self
     }
 
@@ -85,25 +115,71 @@ impl<'a> SignerBuilder<'a> {
         self
     }
 
+    /// Specify the Agent or User Identifier (i=) on behalf of which the email
+    /// is signed
+    pub fn with_auid(mut self, value: &'a str) -> Self {
+        self.auid = Some(value);
+        self
+    }
+
+    /// Only sign the first `value` bytes of the canonicalized body (l=)
+    pub fn with_body_length(mut self, value: usize) -> Self {
+        self.body_length = Some(value);
+        self
+    }
+
+    /// Copy the signed headers in the signature (z=), to help diagnose
+    /// verification failures
+    pub fn with_copied_headers(mut self, value: bool) -> Self {
+        self.copy_headers = value;
+        self
+    }
+
+    /// Specify the query method of the public key (q=). Only `dns/txt` is
+    /// defined.
+    pub fn with_query_method(mut self, value: &'a str) -> Self {
+        self.query_method = Some(value);
+        self
+    }
+
+    /// Add a tag that isn't defined by DKIM, before the signature
+    pub fn with_extension_tag(mut self, name: &'a str, value: &'a str) -> Result<Self, DKIMError> {
+        check_extension_tag(name, value).map_err(DKIMError::BuilderError)?;
+        if self.extension_tags.iter().any(|(n, _)| *n == name) {
+            return Err(DKIMError::BuilderError("duplicate tag"));
+        }
+
+        self.extension_tags.push((name, value));
+        Ok(self)
+    }
+
+    /// Resolve the algorithm of the signing backend in `registry` instead of
+    /// the default one, for example to sign with an experimental algorithm
+    pub fn with_registry(mut self, registry: &AlgorithmRegistry) -> Self {
+        self.registry = Some(registry.clone());
+        self
+    }
+
     /// Build an instance of the Signer
-    /// Must be provided: signed_headers, private_key, selector, logger and
-    /// signing_domain.
+    /// Must be provided: signed_headers, private_key or signing_backend,
+    /// selector, logger and signing_domain.
     pub fn build(self) -> Result<Signer<'a>, DKIMError> {
         use DKIMError::BuilderError;
 
-        let private_key = self
-            .private_key
+        let backend = self
+            .backend
             .ok_or(BuilderError("missing required private key"))?;
-        let hash_algo = match private_key {
-            DkimPrivateKey::Rsa(_) => hash::HashAlgo::RsaSha256,
-            DkimPrivateKey::Ed25519(_) => hash::HashAlgo::Ed25519Sha256,
-        };
+        let hash_algo = backend.algorithm();
+        let algorithm = self
+            .registry
+            .unwrap_or_default()
+            .get(&hash_algo.to_string())?;
 
         Ok(Signer {
             signed_headers: self
                 .signed_headers
                 .ok_or(BuilderError("missing required signed headers"))?,
-            private_key: private_key,
+            backend,
             selector: self
                 .selector
                 .ok_or(BuilderError("missing required selector"))?,
@@ -113,22 +189,34 @@ impl<'a> SignerBuilder<'a> {
             header_canonicalization: self.header_canonicalization,
             body_canonicalization: self.body_canonicalization,
             expiry: self.expiry,
-            hash_algo: hash_algo,
+            hash_algo,
+            algorithm,
             time: self.time,
+            auid: self.auid,
+            body_length: self.body_length,
+            copy_headers: self.copy_headers,
+            query_method: self.query_method,
+            extension_tags: self.extension_tags,
         })
     }
 }
 
 pub struct Signer<'a> {
     signed_headers: &'a [&'a str],
-    private_key: DkimPrivateKey,
+    backend: Box<dyn SigningBackend + 'a>,
     selector: &'a str,
     signing_domain: &'a str,
     header_canonicalization: canonicalization::Type,
     body_canonicalization: canonicalization::Type,
     expiry: Option<chrono::Duration>,
     hash_algo: hash::HashAlgo,
+    algorithm: Arc<dyn SignatureAlgorithm>,
     time: Option<chrono::DateTime<chrono::offset::Utc>>,
+    auid: Option<&'a str>,
+    body_length: Option<usize>,
+    copy_headers: bool,
+    query_method: Option<&'a str>,
+    extension_tags: Vec<(&'a str, &'a str)>,
 }
 
 /// DKIM signer. Use the [SignerBuilder] to build an instance.
@@ -136,111 +224,263 @@ impl<'a> Signer<'a> {
     /// Sign a message
     /// As specified in <https://datatracker.ietf.org/doc/html/rfc6376#section-5>
     pub fn sign<'b>(&self, email: &'b mailparse::ParsedMail<'b>) -> Result<String, DKIMError> {
+        let email = &Message::from(email);
         let body_hash = self.compute_body_hash(email)?;
-        let dkim_header_builder = self.dkim_header_builder(&body_hash)?;
+        self.signed_header(email, &body_hash)
+    }
+
+    /// Sign a message and return it with the DKIM-Signature header prepended.
+    /// The header is folded at about 78 columns, including inside `b=` and
+    /// `h=`, with the line ending used by the message.
+    pub fn sign_message<'b>(
+        &self,
+        email: &'b mailparse::ParsedMail<'b>,
+    ) -> Result<Vec<u8>, DKIMError> {
+        let email = &Message::from(email);
+        let newline = detect_newline(email.raw_bytes);
+        let body_hash = self.compute_body_hash(email)?;
+
+        let mut out = self
+            .folded_signed_header(email, &body_hash, newline)?
+            .into_bytes();
+        out.extend_from_slice(newline.as_bytes());
+        out.extend_from_slice(email.raw_bytes);
+        Ok(out)
+    }
+
+    /// Generate the DKIM-Signature header on a single line
+    fn signed_header(&self, email: &Message, body_hash: &str) -> Result<String, DKIMError> {
+        let dkim_header_builder = self.dkim_header_builder(email, body_hash)?;
 
         let header_hash = self.compute_header_hash(email, dkim_header_builder.clone())?;
 
-        let signature = match &self.private_key {
-            DkimPrivateKey::Rsa(private_key) => private_key
-                .sign(
-                    rsa::PaddingScheme::PKCS1v15Sign {
-                        hash: Some(match &self.hash_algo {
-                            hash::HashAlgo::RsaSha1 => rsa::hash::Hash::SHA1,
-                            hash::HashAlgo::RsaSha256 => rsa::hash::Hash::SHA2_256,
-                            hash => {
-                                return Err(DKIMError::UnsupportedHashAlgorithm(format!(
-                                    "{:?}",
-                                    hash
-                                )))
-                            }
-                        }),
-                    },
-                    &header_hash,
-                )
-                .map_err(|err| DKIMError::FailedToSign(err.to_string()))?,
-            DkimPrivateKey::Ed25519(keypair) => {
-                let expanded: ExpandedSecretKey = (&keypair.secret).into();
-                expanded
-                    .sign(&header_hash, &keypair.public)
-                    .to_bytes()
-                    .into()
-            }
-        };
+        let signature = self.backend.sign(&header_hash)?;
 
         // add the signature into the DKIM header and generate the header
         let dkim_header = dkim_header_builder
             .add_tag("b", &base64::encode(&signature))
             .build()?;
 
-        Ok(format!("{}: {}", HEADER, dkim_header.raw_bytes))
+        Ok(format!("{}: {}", HEADER, dkim_header))
     }
 
-    fn dkim_header_builder(&self, body_hash: &str) -> Result<DKIMHeaderBuilder, DKIMError> {
-        let now = chrono::offset::Utc::now();
-        let hash_algo = match self.hash_algo {
-            hash::HashAlgo::RsaSha1 => "rsa-sha1",
-            hash::HashAlgo::RsaSha256 => "rsa-sha256",
-            hash::HashAlgo::Ed25519Sha256 => "ed25519-sha256",
-        };
+    /// Generate the folded DKIM-Signature header, without the final line
+    /// ending
+    fn folded_signed_header(
+        &self,
+        email: &Message,
+        body_hash: &str,
+        newline: &str,
+    ) -> Result<String, DKIMError> {
+        let dkim_header_builder = self.dkim_header_builder(email, body_hash)?;
+
+        // The signature covers the header exactly as it will be folded, with
+        // an empty `b=`. Folding doesn't move when `b=` gets its value.
+        let folded = fold_header(&dkim_header_builder.clone().add_tag("b", ""), newline);
+        let dkim_header = DKIMHeader::parse(&folded[HEADER.len() + 2..])?;
+        let header_hash = hash::compute_headers_hash(
+            self.header_canonicalization.clone(),
+            &dkim_header.get_required_tag("h"),
+            self.algorithm.as_ref(),
+            &dkim_header,
+            email,
+        )?;
+        let signature = self.backend.sign(&header_hash)?;
 
+        let dkim_header_builder = dkim_header_builder.add_tag("b", &base64::encode(&signature));
+        Ok(fold_header(&dkim_header_builder, newline))
+    }
+
+    fn dkim_header_builder(
+        &self,
+        email: &Message,
+        body_hash: &str,
+    ) -> Result<DKIMHeaderBuilder, DKIMError> {
+        let now = chrono::offset::Utc::now();
         let mut builder = DKIMHeaderBuilder::new()
             .add_tag("v", "1")
-            .add_tag("a", hash_algo)
-            .add_tag("d", &self.signing_domain)
+            .add_tag("a", &self.hash_algo.to_string())
+            .add_tag("d", self.signing_domain)
             .add_tag("s", self.selector)
             .add_tag(
                 "c",
                 &format!(
                     "{}/{}",
-                    self.header_canonicalization.to_string(),
-                    self.body_canonicalization.to_string()
+                    self.header_canonicalization, self.body_canonicalization
                 ),
-            )
+            );
+        if let Some(auid) = self.auid {
+            builder = builder.add_tag("i", &dqp_encode(auid, b""));
+        }
+        if let Some(query_method) = self.query_method {
+            builder = builder.add_tag("q", query_method);
+        }
+        if let Some(length) = self.body_length {
+            builder = builder.add_tag("l", &length.to_string());
+        }
+        builder = builder
             .add_tag("bh", body_hash)
             .set_signed_headers(self.signed_headers);
-        if let Some(expiry) = self.expiry {
-            builder = builder.set_expiry(expiry)?;
+        if self.copy_headers {
+            let signed_headers = self.signed_headers.join(":");
+            let copied_headers: Vec<(String, String)> =
+                hash::select_header_indices(&signed_headers, email)
+                    .into_iter()
+                    .map(|i| {
+                        let header = &email.headers[i];
+                        (
+                            header.get_key(),
+                            String::from_utf8_lossy(header.get_value_raw()).into_owned(),
+                        )
+                    })
+                    .collect();
+            builder = builder.add_tag("z", &encode_copied_headers(&copied_headers));
         }
         if let Some(time) = self.time {
             builder = builder.set_time(time);
         } else {
-            builder = builder.set_time(now.into());
+            builder = builder.set_time(now);
+        }
+        // the expiry is relative to the time
+        if let Some(expiry) = self.expiry {
+            builder = builder.set_expiry(expiry)?;
+        }
+        for (name, value) in &self.extension_tags {
+            builder = builder.add_tag(name, value);
         }
 
         Ok(builder)
     }
 
-    fn compute_body_hash<'b>(
-        &self,
-        email: &'b mailparse::ParsedMail<'b>,
-    ) -> Result<String, DKIMError> {
-        let length = None;
+    /// What the body hash depends on, besides the body
+    fn body_hash_key(&self) -> (canonicalization::Type, hash::HashAlgo, Option<usize>) {
+        (
+            self.body_canonicalization.clone(),
+            self.hash_algo.clone(),
+            self.body_length,
+        )
+    }
+
+    fn compute_body_hash<'b>(&self, email: &'b Message<'b>) -> Result<String, DKIMError> {
+        let length = self.body_length.map(|length| length.to_string());
         let canonicalization = self.body_canonicalization.clone();
-        hash::compute_body_hash(canonicalization, length, self.hash_algo.clone(), email)
+        hash::compute_body_hash(canonicalization, length, self.algorithm.as_ref(), email)
     }
 
     fn compute_header_hash<'b>(
         &self,
-        email: &'b mailparse::ParsedMail<'b>,
+        email: &'b Message<'b>,
         dkim_header_builder: DKIMHeaderBuilder,
     ) -> Result<Vec<u8>, DKIMError> {
         let canonicalization = self.header_canonicalization.clone();
 
         // For signing the DKIM-Signature header the signature needs to be null
         let dkim_header = dkim_header_builder.add_tag("b", "").build()?;
+        let dkim_header = DKIMHeader::parse(&dkim_header)?;
         let signed_headers = dkim_header.get_required_tag("h");
 
         hash::compute_headers_hash(
             canonicalization,
             &signed_headers,
-            self.hash_algo.clone(),
+            self.algorithm.as_ref(),
             &dkim_header,
             email,
         )
     }
 }
 
+/// Signs a message with several signers at once, for example with an RSA and
+/// an Ed25519 key during a transition
+/// (<https://datatracker.ietf.org/doc/html/rfc8463#section-4>). The body is
+/// canonicalized and hashed once per canonicalization and algorithm.
+pub struct MultiSigner<'a> {
+    signers: Vec<Signer<'a>>,
+}
+
+impl<'a> MultiSigner<'a> {
+    /// New multi-signer. The headers are generated in the order of the
+    /// signers.
+    pub fn new(signers: Vec<Signer<'a>>) -> Result<Self, DKIMError> {
+        if signers.is_empty() {
+            return Err(DKIMError::BuilderError("missing signers"));
+        }
+        Ok(MultiSigner { signers })
+    }
+
+    /// Sign a message, see [Signer::sign]. Returns a DKIM-Signature header per
+    /// signer.
+    pub fn sign<'b>(&self, email: &'b mailparse::ParsedMail<'b>) -> Result<Vec<String>, DKIMError> {
+        let email = &Message::from(email);
+        let body_hashes = self.compute_body_hashes(email)?;
+        self.signers
+            .iter()
+            .zip(&body_hashes)
+            .map(|(signer, body_hash)| signer.signed_header(email, body_hash))
+            .collect()
+    }
+
+    /// Sign a message and return it with all the DKIM-Signature headers
+    /// prepended, see [Signer::sign_message]
+    pub fn sign_message<'b>(
+        &self,
+        email: &'b mailparse::ParsedMail<'b>,
+    ) -> Result<Vec<u8>, DKIMError> {
+        let email = &Message::from(email);
+        let newline = detect_newline(email.raw_bytes);
+        let body_hashes = self.compute_body_hashes(email)?;
+
+        let mut out = vec![];
+        for (signer, body_hash) in self.signers.iter().zip(&body_hashes) {
+            out.extend_from_slice(
+                signer
+                    .folded_signed_header(email, body_hash, newline)?
+                    .as_bytes(),
+            );
+            out.extend_from_slice(newline.as_bytes());
+        }
+        out.extend_from_slice(email.raw_bytes);
+        Ok(out)
+    }
+
+    /// Body hash of each signer, computed once per distinct parameters
+    fn compute_body_hashes(&self, email: &Message) -> Result<Vec<String>, DKIMError> {
+        let mut computed: Vec<(_, String)> = vec![];
+        let mut body_hashes = vec![];
+        for signer in &self.signers {
+            let key = signer.body_hash_key();
+            let body_hash = match computed.iter().find(|(k, _)| *k == key) {
+                Some((_, body_hash)) => body_hash.clone(),
+                None => {
+                    let body_hash = signer.compute_body_hash(email)?;
+                    computed.push((key, body_hash.clone()));
+                    body_hash
+                }
+            };
+            body_hashes.push(body_hash);
+        }
+        Ok(body_hashes)
+    }
+}
+
+/// The line ending of the message, CRLF unless its first line ends with LF
+fn detect_newline(raw_bytes: &[u8]) -> &'static str {
+    match raw_bytes.iter().position(|&c| c == b'\n') {
+        Some(i) if i == 0 || raw_bytes[i - 1] != b'\r' => "\n",
+        _ => "\r\n",
+    }
+}
+
+/// Generate the folded DKIM-Signature header line, without the final line
+/// ending
+fn fold_header(header: &DKIMHeaderBuilder, newline: &str) -> String {
+    let folded = fold_tags(HEADER, &header.tags(), FOLD_WIDTH);
+    if newline == "\r\n" {
+        folded
+    } else {
+        folded.replace("\r\n", newline)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -278,6 +518,34 @@ Hello Alice
         assert_eq!(header, "DKIM-Signature: v=1; a=rsa-sha256; d=example.com; s=s20; c=simple/simple; bh=frcCV1k9oG9oKj3dpUqdJg1PxRT2RSN/XKdLCPjaYaY=; h=from:subject; t=1609459201; b=ohfeeUk89mJI/nTb8cViCbOY11tYBkj0xecrpXVwPdkvLMYMZemydr01nUuruhrzaqxFcqgjdEB/alen4NygDo3Kj//GsEUksRO13Hi1aW5lfxLj7Ifux96CbKm3EEcI5rD9tXQ0LaW5nYUdqYdFVIgmU/qTtXRenMxesHhggknm1n6x7K4NsqBS+9leidXtKf8hTSCC7f4XMGFe2YQrCKHfYFBb/MTuzCHbF/CgZHKgMhBAYXMkuEwIGjh4xnR256AmJdxHN+JdrWYzkMdRiuDmYvlnUJdPWq0hD3fR1DxS5/YF6hNHMP9b1yM8eiUQVnqrbzR8C5KWJiM8JhaBcg==;")
     }
 
+    #[test]
+    fn test_sign_expiry() {
+        let email =
+            mailparse::parse_mail(b"Subject: subject\r\nFrom: sven@cloudflare.com\r\n\r\nHi\r\n")
+                .unwrap();
+        let private_key =
+            rsa::RsaPrivateKey::read_pkcs1_pem_file(Path::new("./test/keys/2022.private")).unwrap();
+        let time = chrono::Utc.ymd(2021, 1, 1).and_hms(0, 0, 1);
+
+        // x= is the signing time plus the expiry
+        let signer = SignerBuilder::new()
+            .with_signed_headers(&["From", "Subject"])
+            .unwrap()
+            .with_private_key(DkimPrivateKey::Rsa(private_key))
+            .with_selector("s20")
+            .with_signing_domain("example.com")
+            .with_time(time)
+            .with_expiry(chrono::Duration::hours(1))
+            .build()
+            .unwrap();
+        let header = signer.sign(&email).unwrap();
+        assert!(
+            header.contains(" t=1609459201; x=1609462801;"),
+            "{}",
+            header
+        );
+    }
+
     #[test]
     fn test_sign_ed25519() {
         let raw_email = r#"From: Joe SixPack <joe@football.example.com>
@@ -333,4 +601,143 @@ Joe."#
 
         assert_eq!(header, "DKIM-Signature: v=1; a=ed25519-sha256; d=football.example.com; s=brisbane; c=relaxed/relaxed; bh=2jUSOH9NhtVGCQWNr9BrIAPreKQjO6Sn7XIkfJVOzv8=; h=from:to:subject:date:message-id:from:subject:date; t=1528637909; b=wITr2H3sBuBfMsnUwlRTO7Oq/C/jd2vubDm50DrXtMFEBLRiz9GfrgCozcg764+gYqWXV3Snd1ynYh8sJ5BXBg==;")
     }
+
+    #[test]
+    fn test_extension_tag_validation() {
+        let builder = || {
+            SignerBuilder::new()
+                .with_extension_tag("foo", "bar")
+                .unwrap()
+        };
+
+        assert!(builder().with_extension_tag("baz", "a b").is_ok());
+        assert!(builder().with_extension_tag("x_1", "a").is_ok());
+        assert_eq!(
+            builder().with_extension_tag("foo", "baz").err(),
+            Some(DKIMError::BuilderError("duplicate tag"))
+        );
+        assert_eq!(
+            builder().with_extension_tag("l", "1").err(),
+            Some(DKIMError::BuilderError("duplicate tag"))
+        );
+        assert_eq!(
+            builder().with_extension_tag("x-1", "1").err(),
+            Some(DKIMError::BuilderError("invalid extension tag name"))
+        );
+        assert_eq!(
+            builder().with_extension_tag("1x", "1").err(),
+            Some(DKIMError::BuilderError("invalid extension tag name"))
+        );
+        assert_eq!(
+            builder().with_extension_tag("baz", "a;b").err(),
+            Some(DKIMError::BuilderError("invalid extension tag value"))
+        );
+    }
+
+    #[test]
+    fn test_signer_send_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Signer<'static>>();
+        assert_send_sync::<MultiSigner<'static>>();
+    }
+
+    #[test]
+    fn test_sign_with_signing_backend() {
+        let email =
+            mailparse::parse_mail(b"Subject: subject\r\nFrom: a@example.com\r\n\r\nHello\r\n")
+                .unwrap();
+        let backend = crate::MockSigningBackend::new(hash::HashAlgo::Ed25519Sha256, vec![]);
+        backend.push_response(Ok(vec![1, 2, 3]));
+        backend.push_response(Err(DKIMError::FailedToSign("kms unavailable".to_owned())));
+
+        let signer = SignerBuilder::new()
+            .with_signed_headers(&["From", "Subject"])
+            .unwrap()
+            .with_signing_backend(&backend)
+            .with_selector("s20")
+            .with_signing_domain("example.com")
+            .build()
+            .unwrap();
+
+        let header = signer.sign(&email).unwrap();
+        assert!(header.contains(" a=ed25519-sha256;"));
+        assert!(header.ends_with(" b=AQID;"));
+        assert_eq!(
+            signer.sign(&email).unwrap_err(),
+            DKIMError::FailedToSign("kms unavailable".to_owned())
+        );
+
+        // the header hash is signed, once per call
+        let digests = backend.digests();
+        assert_eq!(digests.len(), 2);
+        assert_eq!(digests[0].len(), 32);
+    }
+
+    #[test]
+    fn test_multi_signer_body_hashes() {
+        use crate::algorithm::RsaSha256;
+        use core::sync::atomic::{AtomicUsize, Ordering};
+
+        /// rsa-sha256, counting the digests it computes
+        struct CountingRsaSha256;
+        static DIGESTS: AtomicUsize = AtomicUsize::new(0);
+        impl SignatureAlgorithm for CountingRsaSha256 {
+            fn name(&self) -> &str {
+                "rsa-sha256"
+            }
+            fn key_type(&self) -> &str {
+                "rsa"
+            }
+            fn digest(&self, data: &[u8]) -> Vec<u8> {
+                DIGESTS.fetch_add(1, Ordering::SeqCst);
+                RsaSha256.digest(data)
+            }
+            fn verify(&self, key: &[u8], digest: &[u8], sig: &[u8]) -> Result<bool, DKIMError> {
+                RsaSha256.verify(key, digest, sig)
+            }
+            fn sign(&self, key: &DkimPrivateKey, digest: &[u8]) -> Result<Vec<u8>, DKIMError> {
+                RsaSha256.sign(key, digest)
+            }
+        }
+        let mut registry = AlgorithmRegistry::empty();
+        registry.register(CountingRsaSha256);
+
+        let email = Message::parse(b"From: a@example.com\r\n\r\nHello  Alice \r\n\r\n").unwrap();
+        let signer = |canonicalization: canonicalization::Type| {
+            let private_key =
+                rsa::RsaPrivateKey::read_pkcs1_pem_file(Path::new("./test/keys/2022.private"))
+                    .unwrap();
+            SignerBuilder::new()
+                .with_signed_headers(&["From"])
+                .unwrap()
+                .with_private_key(DkimPrivateKey::Rsa(private_key))
+                .with_selector("s20")
+                .with_signing_domain("example.com")
+                .with_body_canonicalization(canonicalization)
+                .with_registry(&registry)
+                .build()
+                .unwrap()
+        };
+        let signers = vec![
+            signer(canonicalization::Type::Simple),
+            signer(canonicalization::Type::Relaxed),
+            signer(canonicalization::Type::Simple),
+        ];
+        let expected: Vec<String> = signers
+            .iter()
+            .map(|signer| signer.compute_body_hash(&email).unwrap())
+            .collect();
+        assert_ne!(expected[0], expected[1]);
+
+        let multi_signer = MultiSigner::new(signers).unwrap();
+        DIGESTS.store(0, Ordering::SeqCst);
+        assert_eq!(multi_signer.compute_body_hashes(&email).unwrap(), expected);
+        // one body hash per canonicalization, not per signer
+        assert_eq!(DIGESTS.load(Ordering::SeqCst), 2);
+
+        assert_eq!(
+            MultiSigner::new(vec![]).err(),
+            Some(DKIMError::BuilderError("missing signers"))
+        );
+    }
 }
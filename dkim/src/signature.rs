@@ -0,0 +1,501 @@
+//! Typed model of the DKIM-Signature header field
+//! <https://datatracker.ietf.org/doc/html/rfc6376#section-3.5>
+use alloc::borrow::Cow;
+use core::str::FromStr;
+
+use chrono::TimeZone;
+
+use crate::header::{fold_tags, HEADER, REQUIRED_TAGS};
+use crate::prelude::*;
+#[cfg(any(feature = "verify", feature = "sign"))]
+use crate::AlgorithmRegistry;
+use crate::{canonicalization, hash::HashAlgo, parser, DKIMError};
+
+/// Column at which [DkimSignature::to_header] folds the header
+pub(crate) const FOLD_WIDTH: usize = 78;
+
+pub(crate) const KNOWN_TAGS: &[&str] = &[
+    "v", "a", "b", "bh", "c", "d", "h", "i", "l", "q", "s", "t", "x", "z",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+/// A parsed DKIM-Signature
+pub struct DkimSignature {
+    /// Signing algorithm (a=)
+    pub algorithm: HashAlgo,
+    /// Signature data (b=), decoded from base64
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::base64_bytes"))]
+    pub signature: Vec<u8>,
+    /// Hash of the canonicalized body (bh=), decoded from base64
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::base64_bytes"))]
+    pub body_hash: Vec<u8>,
+    /// Header canonicalization (first half of c=)
+    pub header_canonicalization: canonicalization::Type,
+    /// Body canonicalization (second half of c=)
+    pub body_canonicalization: canonicalization::Type,
+    /// Signing domain identifier (d=)
+    pub signing_domain: String,
+    /// Signed header fields (h=), as they appear in the signature
+    pub signed_headers: Vec<String>,
+    /// Agent or User Identifier (i=)
+    pub auid: Option<String>,
+    /// Body length count (l=)
+    pub body_length: Option<usize>,
+    /// Query method (q=)
+    pub query_method: Option<String>,
+    /// Selector (s=)
+    pub selector: String,
+    /// Signature timestamp (t=)
+    pub timestamp: Option<chrono::DateTime<chrono::Utc>>,
+    /// Signature expiration (x=)
+    pub expiration: Option<chrono::DateTime<chrono::Utc>>,
+    /// Copied header fields (z=), decoded
+    pub copied_headers: Vec<(String, String)>,
+    /// Any other tag, in the order they appeared, see
+    /// [DkimSignature::extensions]
+    extensions: Vec<(String, String)>,
+}
+
+impl DkimSignature {
+    /// Parses the value of a DKIM-Signature header. The algorithm must be one
+    /// of the default [AlgorithmRegistry].
+    ///
+    /// Only the syntax is checked here; policy checks such as the expiration
+    /// or the presence of From in h= are done during verification.
+    #[cfg(any(feature = "verify", feature = "sign"))]
+    pub fn parse(value: &str) -> Result<Self, DKIMError> {
+        Self::parse_with_registry(value, &AlgorithmRegistry::default())
+    }
+
+    /// Parses the value of a DKIM-Signature header. Without the `verify` and
+    /// `sign` features there is no registry and any algorithm is accepted.
+    #[cfg(not(any(feature = "verify", feature = "sign")))]
+    pub fn parse(value: &str) -> Result<Self, DKIMError> {
+        Self::parse_any_algorithm(value)
+    }
+
+    /// Same as [DkimSignature::parse] with the algorithms of `registry`, for
+    /// example to accept an experimental algorithm
+    #[cfg(any(feature = "verify", feature = "sign"))]
+    pub fn parse_with_registry(
+        value: &str,
+        registry: &AlgorithmRegistry,
+    ) -> Result<Self, DKIMError> {
+        Self::parse_inner(value, |name| registry.parse(name))
+    }
+
+    /// Parses the value of a signature that was already validated, for
+    /// example when it is deserialized: any algorithm name is accepted
+    #[cfg(any(feature = "borsh", not(any(feature = "verify", feature = "sign"))))]
+    pub(crate) fn parse_any_algorithm(value: &str) -> Result<Self, DKIMError> {
+        Self::parse_inner(value, |name| Ok(HashAlgo::from_name(name)))
+    }
+
+    fn parse_inner(
+        value: &str,
+        parse_algorithm: impl Fn(&str) -> Result<HashAlgo, DKIMError>,
+    ) -> Result<Self, DKIMError> {
+        let (_, tags) = parser::tag_list(value)
+            .map_err(|err| DKIMError::SignatureSyntaxError(err.to_string()))?;
+        if let Some(name) = parser::duplicate_tag(&tags) {
+            return Err(DKIMError::SignatureSyntaxError(format!(
+                "duplicate tag {}=",
+                name
+            )));
+        }
+
+        let get = |name: &str| {
+            tags.iter()
+                .find(|tag| tag.name == name)
+                .map(|tag| tag.value())
+        };
+        for required in REQUIRED_TAGS {
+            if get(required).is_none() {
+                return Err(DKIMError::SignatureMissingRequiredTag(required));
+            }
+        }
+        let required = |name: &str| get(name).unwrap_or_default();
+
+        if required("v") != "1" {
+            return Err(DKIMError::IncompatibleVersion);
+        }
+
+        let (header_canonicalization, body_canonicalization) =
+            parser::parse_canonicalization(get("c").as_deref())?;
+
+        Ok(DkimSignature {
+            algorithm: parse_algorithm(&required("a"))?,
+            signature: decode_base64("b", &required("b"))?,
+            body_hash: decode_base64("bh", &required("bh"))?,
+            header_canonicalization,
+            body_canonicalization,
+            signing_domain: required("d").into_owned(),
+            signed_headers: required("h")
+                .split(':')
+                .map(|h| h.trim().to_owned())
+                .collect(),
+            auid: get("i").map(Cow::into_owned),
+            body_length: get("l")
+                .map(|v| {
+                    v.parse::<usize>().map_err(|err| {
+                        DKIMError::SignatureSyntaxError(format!("invalid length: {}", err))
+                    })
+                })
+                .transpose()?,
+            query_method: get("q").map(Cow::into_owned),
+            selector: required("s").into_owned(),
+            timestamp: get("t").map(|v| parse_timestamp("t", &v)).transpose()?,
+            expiration: get("x").map(|v| parse_timestamp("x", &v)).transpose()?,
+            copied_headers: get("z")
+                .map(|v| parse_copied_headers(&v))
+                .unwrap_or_default(),
+            extensions: tags
+                .iter()
+                .filter(|tag| !KNOWN_TAGS.contains(&tag.name))
+                .map(|tag| (tag.name.to_owned(), tag.raw_value.replace("\r\n", "")))
+                .collect(),
+        })
+    }
+
+    /// The tags that aren't defined by DKIM, in the order they appeared, with
+    /// their value unfolded
+    pub fn extensions(&self) -> &[(String, String)] {
+        &self.extensions
+    }
+
+    /// Add a tag that isn't defined by DKIM, serialized before the signature
+    pub fn add_extension(&mut self, name: &str, value: &str) -> Result<(), DKIMError> {
+        let error = |msg: &str| DKIMError::SignatureSyntaxError(format!("{}: {}=", msg, name));
+        check_extension_tag(name, value).map_err(error)?;
+        if self.extensions.iter().any(|(n, _)| n == name) {
+            return Err(error("duplicate tag"));
+        }
+        self.extensions.push((name.to_owned(), value.to_owned()));
+        Ok(())
+    }
+
+    /// Returns the tags of the signature in the order they are serialized
+    fn tags(&self) -> Vec<(&str, String)> {
+        let mut tags = vec![
+            ("v", "1".to_owned()),
+            ("a", self.algorithm.to_string()),
+            ("d", self.signing_domain.clone()),
+            ("s", self.selector.clone()),
+            (
+                "c",
+                format!(
+                    "{}/{}",
+                    self.header_canonicalization, self.body_canonicalization
+                ),
+            ),
+        ];
+        if let Some(auid) = &self.auid {
+            tags.push(("i", auid.clone()));
+        }
+        if let Some(query_method) = &self.query_method {
+            tags.push(("q", query_method.clone()));
+        }
+        if let Some(length) = self.body_length {
+            tags.push(("l", length.to_string()));
+        }
+        tags.push(("bh", base64::encode(&self.body_hash)));
+        tags.push(("h", self.signed_headers.join(":")));
+        if !self.copied_headers.is_empty() {
+            tags.push(("z", encode_copied_headers(&self.copied_headers)));
+        }
+        if let Some(timestamp) = self.timestamp {
+            tags.push(("t", timestamp.timestamp().to_string()));
+        }
+        if let Some(expiration) = self.expiration {
+            tags.push(("x", expiration.timestamp().to_string()));
+        }
+        // Skips the extensions that would change the other tags, which can
+        // only come from a deserialized signature
+        for (name, value) in &self.extensions {
+            if check_extension_tag(name, value).is_ok() && !tags.iter().any(|(n, _)| n == name) {
+                tags.push((name, value.clone()));
+            }
+        }
+        tags.push(("b", base64::encode(&self.signature)));
+        tags
+    }
+
+    /// Serializes the signature as an unfolded header value
+    pub fn to_header_value(&self) -> String {
+        let tags: Vec<String> = self
+            .tags()
+            .iter()
+            .map(|(name, value)| format!("{}={};", name, value))
+            .collect();
+        tags.join(" ")
+    }
+
+    /// Serializes the signature as a complete `DKIM-Signature:` header,
+    /// folded at 78 columns with CRLF line endings.
+    pub fn to_header(&self) -> String {
+        let tags = self.tags();
+        let tags: Vec<(&str, &str)> = tags
+            .iter()
+            .map(|(name, value)| (*name, value.as_str()))
+            .collect();
+        fold_tags(HEADER, &tags, FOLD_WIDTH)
+    }
+}
+
+impl FromStr for DkimSignature {
+    type Err = DKIMError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Self::parse(value)
+    }
+}
+
+/// Checks that a tag that isn't defined by DKIM can be serialized without
+/// changing the other tags
+pub(crate) fn check_extension_tag(name: &str, value: &str) -> Result<(), &'static str> {
+    // tag-name = ALPHA *ALNUMPUNC
+    let mut chars = name.bytes();
+    if !chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+        || !chars.all(|c| c.is_ascii_alphanumeric() || c == b'_')
+    {
+        return Err("invalid extension tag name");
+    }
+    if KNOWN_TAGS.contains(&name) {
+        return Err("duplicate tag");
+    }
+    // tag-value, with whitespace only between the characters
+    let is_valchar = |c: u8| (0x21..=0x7E).contains(&c) && c != b';';
+    if value.trim() != value
+        || !value
+            .bytes()
+            .all(|c| is_valchar(c) || c == b' ' || c == b'\t')
+    {
+        return Err("invalid extension tag value");
+    }
+    Ok(())
+}
+
+fn decode_base64(name: &str, value: &str) -> Result<Vec<u8>, DKIMError> {
+    base64::decode(value).map_err(|err| {
+        DKIMError::SignatureSyntaxError(format!("failed to decode {}=: {}", name, err))
+    })
+}
+
+fn parse_timestamp(name: &str, value: &str) -> Result<chrono::DateTime<chrono::Utc>, DKIMError> {
+    value
+        .parse::<i64>()
+        .ok()
+        .and_then(|secs| chrono::Utc.timestamp_opt(secs, 0).single())
+        .ok_or_else(|| DKIMError::SignatureSyntaxError(format!("invalid {}= timestamp", name)))
+}
+
+/// Decodes the z= tag into a list of (name, value)
+pub(crate) fn parse_copied_headers(value: &str) -> Vec<(String, String)> {
+    value
+        .split('|')
+        .filter(|field| !field.is_empty())
+        .map(|field| match field.split_once(':') {
+            Some((name, value)) => (name.trim().to_owned(), dqp_decode(value)),
+            None => (field.trim().to_owned(), "".to_owned()),
+        })
+        .collect()
+}
+
+/// Encodes a list of (name, value) as a z= tag value
+pub(crate) fn encode_copied_headers(headers: &[(String, String)]) -> String {
+    let fields: Vec<String> = headers
+        .iter()
+        .map(|(name, value)| format!("{}:{}", name, dqp_encode(value, b"|")))
+        .collect();
+    fields.join("|")
+}
+
+/// DKIM-Quoted-Printable encoding. Bytes listed in `extra` are encoded even
+/// when they would be safe.
+/// <https://datatracker.ietf.org/doc/html/rfc6376#section-2.11>
+pub(crate) fn dqp_encode(value: &str, extra: &[u8]) -> String {
+    let mut out = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        let safe = matches!(byte, 0x21..=0x3A | 0x3C | 0x3E..=0x7E);
+        if safe && !extra.contains(&byte) {
+            out.push(byte as char);
+        } else {
+            out += &format!("={:02X}", byte);
+        }
+    }
+    out
+}
+
+/// DKIM-Quoted-Printable decoding. Whitespace is ignored and escape sequences
+/// that are not `=` followed by two hex digits are kept as-is.
+pub(crate) fn dqp_decode(value: &str) -> String {
+    let bytes: Vec<u8> = value
+        .bytes()
+        .filter(|b| !matches!(b, b' ' | b'\t' | b'\r' | b'\n'))
+        .collect();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'='
+            && i + 2 < bytes.len()
+            && bytes[i + 1].is_ascii_hexdigit()
+            && bytes[i + 2].is_ascii_hexdigit()
+        {
+            let hex = core::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or_default();
+            out.push(u8::from_str_radix(hex, 16).unwrap_or_default());
+            i += 3;
+            continue;
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RFC_HEADER: &str = r#"v=1; a=rsa-sha256; d=example.net; s=brisbane;
+c=relaxed/simple; q=dns/txt; i=foo@eng.example.net;
+t=1117574938; x=9118006938; l=200;
+h=from:to:subject:date:keywords:keywords;
+z=From:foo@eng.example.net|To:joe@example.com|
+Subject:demo=20run|Date:July=205,=202005=203:44:08=20PM=20-0700;
+bh=MTIzNDU2Nzg5MDEyMzQ1Njc4OTAxMjM0NTY3ODkwMTI=;
+b=dzdVyOfAKCdLXdJOc9G2q8LoXSlEniSbav+yuU4zGeeruD00lszZ
+      VoG4ZHRNiYzR
+        "#;
+
+    #[test]
+    fn test_parse() {
+        let signature = DkimSignature::parse(RFC_HEADER).unwrap();
+
+        assert_eq!(signature.algorithm, HashAlgo::RsaSha256);
+        assert_eq!(
+            signature.header_canonicalization,
+            canonicalization::Type::Relaxed
+        );
+        assert_eq!(
+            signature.body_canonicalization,
+            canonicalization::Type::Simple
+        );
+        assert_eq!(signature.signing_domain, "example.net");
+        assert_eq!(signature.selector, "brisbane");
+        assert_eq!(signature.auid.as_deref(), Some("foo@eng.example.net"));
+        assert_eq!(signature.query_method.as_deref(), Some("dns/txt"));
+        assert_eq!(signature.body_length, Some(200));
+        assert_eq!(
+            signature.signed_headers,
+            vec!["from", "to", "subject", "date", "keywords", "keywords"]
+        );
+        assert_eq!(signature.timestamp.unwrap().timestamp(), 1117574938);
+        assert_eq!(signature.expiration.unwrap().timestamp(), 9118006938);
+        assert_eq!(signature.body_hash, b"12345678901234567890123456789012");
+        assert_eq!(
+            signature.copied_headers,
+            vec![
+                ("From".to_owned(), "foo@eng.example.net".to_owned()),
+                ("To".to_owned(), "joe@example.com".to_owned()),
+                ("Subject".to_owned(), "demo run".to_owned()),
+                (
+                    "Date".to_owned(),
+                    "July 5, 2005 3:44:08 PM -0700".to_owned()
+                ),
+            ]
+        );
+        assert!(signature.extensions().is_empty());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            DkimSignature::parse("v=1; a=rsa-sha256; bh=a; b=b").unwrap_err(),
+            DKIMError::SignatureMissingRequiredTag("d")
+        );
+        assert_eq!(
+            DkimSignature::parse("v=1; a=rsa-sha256; d=a; s=b; h=from; bh=; b=; l=x").unwrap_err(),
+            DKIMError::SignatureSyntaxError(
+                "invalid length: invalid digit found in string".to_owned()
+            )
+        );
+        assert_eq!(
+            DkimSignature::parse("v=1; a=foo; d=a; s=b; h=from; bh=; b=").unwrap_err(),
+            DKIMError::UnsupportedHashAlgorithm("foo".to_owned())
+        );
+        assert_eq!(
+            DkimSignature::parse("v=1; a=rsa-sha256; d=a; s=b; h=from; bh=; b=; d=c").unwrap_err(),
+            DKIMError::SignatureSyntaxError("duplicate tag d=".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_extensions() {
+        let mut signature = DkimSignature::parse(RFC_HEADER).unwrap();
+        let error = |msg: &str| Err(DKIMError::SignatureSyntaxError(msg.to_owned()));
+        assert_eq!(
+            signature.add_extension("b", "AAAA"),
+            error("duplicate tag: b=")
+        );
+        assert_eq!(
+            signature.add_extension("foo", "bar; h=to"),
+            error("invalid extension tag value: foo=")
+        );
+        assert_eq!(
+            signature.add_extension("foo-1", "bar"),
+            error("invalid extension tag name: foo-1=")
+        );
+        signature.add_extension("foo", "bar").unwrap();
+        assert_eq!(
+            signature.add_extension("foo", "baz"),
+            error("duplicate tag: foo=")
+        );
+
+        // the unchecked extensions of a deserialized signature are skipped
+        let expected = signature.to_header_value();
+        signature.extensions.push(("h".to_owned(), "to".to_owned()));
+        signature
+            .extensions
+            .push(("baz".to_owned(), "a; h=to".to_owned()));
+        assert_eq!(signature.to_header_value(), expected);
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let mut signature = DkimSignature::parse(RFC_HEADER).unwrap();
+        signature.add_extension("foo", "bar  baz").unwrap();
+
+        let value = signature.to_header_value();
+        assert_eq!(DkimSignature::parse(&value).unwrap(), signature);
+
+        let header = signature.to_header();
+        let value = header.strip_prefix("DKIM-Signature:").unwrap();
+        assert_eq!(DkimSignature::parse(value).unwrap(), signature);
+    }
+
+    #[test]
+    fn test_to_header_folding() {
+        let mut signature = DkimSignature::parse(RFC_HEADER).unwrap();
+        signature.signature = vec![42; 256];
+        signature.signed_headers = (0..20).map(|i| format!("header{}", i)).collect();
+
+        let header = signature.to_header();
+        for line in header.split("\r\n") {
+            assert!(line.len() <= FOLD_WIDTH, "line too long: {}", line);
+        }
+        for line in header.split("\r\n").skip(1) {
+            assert!(line.starts_with(' '));
+        }
+        assert!(header.starts_with("DKIM-Signature: v=1; a=rsa-sha256;"));
+    }
+
+    #[test]
+    fn test_dqp() {
+        assert_eq!(dqp_encode("a b;c=d|e", b"|"), "a=20b=3Bc=3Dd=7Ce");
+        assert_eq!(dqp_encode("a|b", b""), "a|b");
+        assert_eq!(dqp_decode("a=20b=3B\r\n c=3Dd=7Ce"), "a b;c=d|e");
+        assert_eq!(dqp_decode("a=zz="), "a=zz=");
+        assert_eq!(dqp_decode("=+1=-1=3d"), "=+1=-1=");
+    }
+}
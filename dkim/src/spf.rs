@@ -0,0 +1,793 @@
+//! Sender Policy Framework <https://datatracker.ietf.org/doc/html/rfc7208>:
+//! checks that the client that handed an email to the MX of the relayer is
+//! allowed to send for the domain of its MAIL FROM.
+//!
+//! Records are resolved through [Lookup], so an evaluation can run entirely
+//! against an in-memory resolver. The `ptr` mechanism is deprecated and never
+//! matches, and the `exp` modifier is ignored.
+use core::fmt;
+use core::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::dns::Lookup;
+use crate::prelude::*;
+use crate::DKIMError;
+
+/// Maximum number of terms causing DNS queries in one evaluation
+/// <https://datatracker.ietf.org/doc/html/rfc7208#section-4.6.4>
+const DNS_LOOKUP_LIMIT: usize = 10;
+/// Maximum number of DNS queries returning no records
+const VOID_LOOKUP_LIMIT: usize = 2;
+/// Maximum number of exchanges looked up by an `mx` mechanism
+const MX_LIMIT: usize = 10;
+/// Maximum length of a domain name
+const DOMAIN_MAX_LEN: usize = 253;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+/// Result of an SPF evaluation
+/// <https://datatracker.ietf.org/doc/html/rfc7208#section-2.6>
+pub enum SpfResult {
+    None,
+    Neutral,
+    Pass,
+    Fail,
+    SoftFail,
+    TempError,
+    PermError,
+}
+
+impl SpfResult {
+    /// The result as reported in `Authentication-Results` and `Received-SPF`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Neutral => "neutral",
+            Self::Pass => "pass",
+            Self::Fail => "fail",
+            Self::SoftFail => "softfail",
+            Self::TempError => "temperror",
+            Self::PermError => "permerror",
+        }
+    }
+}
+
+impl fmt::Display for SpfResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The identity checked by [check_host]
+/// <https://datatracker.ietf.org/doc/html/rfc7208#section-2.3>
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SpfScope {
+    /// The domain of the MAIL FROM
+    MailFrom,
+    /// The HELO identity, for an empty MAIL FROM
+    Helo,
+}
+
+impl SpfScope {
+    /// The scope as reported in DMARC aggregate reports
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::MailFrom => "mfrom",
+            Self::Helo => "helo",
+        }
+    }
+}
+
+/// Outcome of [check_host]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpfOutput {
+    pub result: SpfResult,
+    /// The domain whose policy was evaluated: the domain of the MAIL FROM, or
+    /// the HELO identity if the MAIL FROM is empty. DMARC aligns it with the
+    /// domain of the From header.
+    pub domain: String,
+    /// Which of the identities `domain` is
+    pub scope: SpfScope,
+    /// The resolver error behind a `temperror`, for example
+    /// [DKIMError::UnsupportedLookup] if the policy needs A, AAAA or MX
+    /// records and the resolver only implements [Lookup::lookup_txt]
+    pub error: Option<DKIMError>,
+}
+
+/// Evaluates the SPF policy of the MAIL FROM for a client `ip` that
+/// introduced itself with `helo`. An empty MAIL FROM (a bounce) is checked as
+/// `postmaster@<helo>`.
+pub fn check_host<T: Lookup + ?Sized>(
+    resolver: &T,
+    ip: IpAddr,
+    helo: &str,
+    mail_from: &str,
+) -> SpfOutput {
+    let mail_from = mail_from.trim_start_matches('<').trim_end_matches('>');
+    let (local_part, domain, scope) = match mail_from.rsplit_once('@') {
+        Some((local_part, domain)) if !local_part.is_empty() => {
+            (local_part, domain, SpfScope::MailFrom)
+        }
+        Some((_, domain)) => ("postmaster", domain, SpfScope::MailFrom),
+        None if mail_from.is_empty() => ("postmaster", helo, SpfScope::Helo),
+        None => ("postmaster", mail_from, SpfScope::MailFrom),
+    };
+    let domain = domain.trim_end_matches('.').to_lowercase();
+
+    let mut evaluation = Evaluation {
+        resolver,
+        ip: canonical_ip(ip),
+        helo,
+        local_part,
+        sender_domain: &domain,
+        lookups: 0,
+        void_lookups: 0,
+        error: None,
+    };
+    let result = if is_valid_domain(&domain) {
+        evaluation.check_host(&domain).unwrap_or_else(|err| err)
+    } else {
+        SpfResult::None
+    };
+    let error = evaluation.error;
+    SpfOutput {
+        result,
+        domain,
+        scope,
+        error,
+    }
+}
+
+/// An IPv4-mapped IPv6 address is evaluated as IPv4
+fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => IpAddr::V4(ip),
+            None => IpAddr::V6(ip),
+        },
+        ip => ip,
+    }
+}
+
+fn is_valid_domain(domain: &str) -> bool {
+    let labels: Vec<&str> = domain.split('.').collect();
+    labels.len() > 1
+        && domain.len() <= DOMAIN_MAX_LEN
+        && labels
+            .iter()
+            .all(|label| !label.is_empty() && label.len() <= 63)
+}
+
+/// State of an evaluation, shared by the included policies. Errors are the
+/// `temperror` and `permerror` results, which end the evaluation.
+struct Evaluation<'a, T: ?Sized> {
+    resolver: &'a T,
+    ip: IpAddr,
+    helo: &'a str,
+    local_part: &'a str,
+    sender_domain: &'a str,
+    lookups: usize,
+    void_lookups: usize,
+    /// The resolver error that ended the evaluation
+    error: Option<DKIMError>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Qualifier {
+    Pass,
+    Fail,
+    SoftFail,
+    Neutral,
+}
+
+impl Qualifier {
+    fn result(&self) -> SpfResult {
+        match self {
+            Qualifier::Pass => SpfResult::Pass,
+            Qualifier::Fail => SpfResult::Fail,
+            Qualifier::SoftFail => SpfResult::SoftFail,
+            Qualifier::Neutral => SpfResult::Neutral,
+        }
+    }
+}
+
+impl<'a, T: Lookup + ?Sized> Evaluation<'a, T> {
+    /// <https://datatracker.ietf.org/doc/html/rfc7208#section-4>
+    fn check_host(&mut self, domain: &str) -> Result<SpfResult, SpfResult> {
+        let record = match self.fetch_record(domain)? {
+            Some(record) => record,
+            None => return Ok(SpfResult::None),
+        };
+
+        let mut redirect = None;
+        for term in record.split_ascii_whitespace().skip(1) {
+            if let Some((name, value)) = modifier(term) {
+                if name.eq_ignore_ascii_case("redirect") {
+                    if redirect.is_some() {
+                        return Err(SpfResult::PermError);
+                    }
+                    redirect = Some(value);
+                }
+                continue;
+            }
+
+            let (qualifier, mechanism) = match term.as_bytes()[0] {
+                b'+' => (Qualifier::Pass, &term[1..]),
+                b'-' => (Qualifier::Fail, &term[1..]),
+                b'~' => (Qualifier::SoftFail, &term[1..]),
+                b'?' => (Qualifier::Neutral, &term[1..]),
+                _ => (Qualifier::Pass, term),
+            };
+            if self.matches(domain, mechanism)? {
+                return Ok(qualifier.result());
+            }
+        }
+
+        match redirect {
+            Some(target) => {
+                self.count_lookup()?;
+                let target = self.expand(target, domain)?;
+                match self.check_host(&target)? {
+                    SpfResult::None => Err(SpfResult::PermError),
+                    result => Ok(result),
+                }
+            }
+            None => Ok(SpfResult::Neutral),
+        }
+    }
+
+    /// The `v=spf1` record of the domain
+    fn fetch_record(&mut self, domain: &str) -> Result<Option<String>, SpfResult> {
+        let records = match self.resolver.lookup_txt(domain) {
+            Ok(records) => records,
+            Err(DKIMError::NoKeyForSignature) => return Ok(None),
+            Err(err) => {
+                self.error = Some(err);
+                return Err(SpfResult::TempError);
+            }
+        };
+        let mut records = records.into_iter().filter(|record| {
+            let version = record.split_ascii_whitespace().next().unwrap_or_default();
+            version.eq_ignore_ascii_case("v=spf1")
+        });
+        match (records.next(), records.next()) {
+            (Some(record), None) => Ok(Some(record)),
+            (None, _) => Ok(None),
+            (Some(_), Some(_)) => Err(SpfResult::PermError),
+        }
+    }
+
+    fn matches(&mut self, domain: &str, mechanism: &str) -> Result<bool, SpfResult> {
+        let (name, argument) = match mechanism.find([':', '/']) {
+            Some(index) => mechanism.split_at(index),
+            None => (mechanism, ""),
+        };
+        let name = name.to_ascii_lowercase();
+        match name.as_str() {
+            "all" if argument.is_empty() => Ok(true),
+            "include" => {
+                let target = required_domain_spec(argument)?;
+                self.count_lookup()?;
+                let target = self.expand(target, domain)?;
+                match self.check_host(&target) {
+                    Ok(SpfResult::Pass) => Ok(true),
+                    Ok(SpfResult::Fail | SpfResult::SoftFail | SpfResult::Neutral) => Ok(false),
+                    Ok(SpfResult::TempError) | Err(SpfResult::TempError) => {
+                        Err(SpfResult::TempError)
+                    }
+                    _ => Err(SpfResult::PermError),
+                }
+            }
+            "a" => {
+                self.count_lookup()?;
+                let (target, cidr) = self.domain_and_cidr(argument, domain)?;
+                self.host_matches(&target, cidr)
+            }
+            "mx" => {
+                self.count_lookup()?;
+                let (target, cidr) = self.domain_and_cidr(argument, domain)?;
+                let exchanges = self.resolve(|r| r.lookup_mx(&target))?;
+                if exchanges.len() > MX_LIMIT {
+                    return Err(SpfResult::PermError);
+                }
+                for exchange in exchanges {
+                    if self.host_matches(exchange.trim_end_matches('.'), cidr)? {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+            "ptr" => {
+                self.count_lookup()?;
+                Ok(false)
+            }
+            "ip4" => {
+                let (ip, prefix) = parse_network(required_argument(argument)?, 32)?;
+                let ip: Ipv4Addr = ip.parse().map_err(|_| SpfResult::PermError)?;
+                Ok(match self.ip {
+                    IpAddr::V4(client) => in_network(&client.octets(), &ip.octets(), prefix),
+                    IpAddr::V6(_) => false,
+                })
+            }
+            "ip6" => {
+                let (ip, prefix) = parse_network(required_argument(argument)?, 128)?;
+                let ip: Ipv6Addr = ip.parse().map_err(|_| SpfResult::PermError)?;
+                Ok(match self.ip {
+                    IpAddr::V6(client) => in_network(&client.octets(), &ip.octets(), prefix),
+                    IpAddr::V4(_) => false,
+                })
+            }
+            "exists" => {
+                let target = required_domain_spec(argument)?;
+                self.count_lookup()?;
+                let target = self.expand(target, domain)?;
+                Ok(!self.resolve(|r| r.lookup_a(&target))?.is_empty())
+            }
+            _ => Err(SpfResult::PermError),
+        }
+    }
+
+    /// Whether an address of `host` is in the network of the client
+    fn host_matches(&mut self, host: &str, cidr: (u8, u8)) -> Result<bool, SpfResult> {
+        Ok(match self.ip {
+            IpAddr::V4(client) => self
+                .resolve(|r| r.lookup_a(host))?
+                .iter()
+                .any(|ip| in_network(&client.octets(), &ip.octets(), cidr.0)),
+            IpAddr::V6(client) => self
+                .resolve(|r| r.lookup_aaaa(host))?
+                .iter()
+                .any(|ip| in_network(&client.octets(), &ip.octets(), cidr.1)),
+        })
+    }
+
+    /// Runs a query, counting the answers without records
+    fn resolve<R>(
+        &mut self,
+        query: impl FnOnce(&T) -> Result<Vec<R>, DKIMError>,
+    ) -> Result<Vec<R>, SpfResult> {
+        let records = match query(self.resolver) {
+            Ok(records) => records,
+            Err(DKIMError::NoKeyForSignature) => vec![],
+            Err(err) => {
+                self.error = Some(err);
+                return Err(SpfResult::TempError);
+            }
+        };
+        if records.is_empty() {
+            self.void_lookups += 1;
+            if self.void_lookups > VOID_LOOKUP_LIMIT {
+                return Err(SpfResult::PermError);
+            }
+        }
+        Ok(records)
+    }
+
+    fn count_lookup(&mut self) -> Result<(), SpfResult> {
+        self.lookups += 1;
+        if self.lookups > DNS_LOOKUP_LIMIT {
+            return Err(SpfResult::PermError);
+        }
+        Ok(())
+    }
+
+    /// Parses `[:domain-spec][/ip4-cidr][//ip6-cidr]`
+    fn domain_and_cidr(
+        &self,
+        argument: &str,
+        domain: &str,
+    ) -> Result<(String, (u8, u8)), SpfResult> {
+        let (spec, cidr) = match argument.find('/') {
+            Some(index) => argument.split_at(index),
+            None => (argument, ""),
+        };
+        let target = match spec.strip_prefix(':') {
+            Some(spec) => self.expand(spec, domain)?,
+            None if spec.is_empty() => domain.to_owned(),
+            None => return Err(SpfResult::PermError),
+        };
+
+        let (ip4_cidr, ip6_cidr) = match cidr.find("//") {
+            Some(index) => (&cidr[..index], &cidr[index + 1..]),
+            None => (cidr, ""),
+        };
+        let prefix = |cidr: &str, max: u8| match cidr.strip_prefix('/') {
+            Some(length) => parse_prefix(length, max),
+            None if cidr.is_empty() => Ok(max),
+            None => Err(SpfResult::PermError),
+        };
+        Ok((target, (prefix(ip4_cidr, 32)?, prefix(ip6_cidr, 128)?)))
+    }
+
+    /// Expands the macros of a domain-spec
+    /// <https://datatracker.ietf.org/doc/html/rfc7208#section-7>
+    fn expand(&self, spec: &str, domain: &str) -> Result<String, SpfResult> {
+        let mut out = String::new();
+        let mut chars = spec.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                out.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('%') => out.push('%'),
+                Some('_') => out.push(' '),
+                Some('-') => out += "%20",
+                Some('{') => {
+                    let body: String = chars.by_ref().take_while(|c| *c != '}').collect();
+                    out += &self.expand_macro(&body, domain)?;
+                }
+                _ => return Err(SpfResult::PermError),
+            }
+        }
+
+        // Long names are truncated from the left
+        let mut out = out.trim_end_matches('.');
+        while out.len() > DOMAIN_MAX_LEN {
+            out = out.split_once('.').ok_or(SpfResult::PermError)?.1;
+        }
+        Ok(out.to_owned())
+    }
+
+    fn expand_macro(&self, body: &str, domain: &str) -> Result<String, SpfResult> {
+        let mut chars = body.chars();
+        let letter = chars.next().ok_or(SpfResult::PermError)?;
+        let value = match letter.to_ascii_lowercase() {
+            's' => format!("{}@{}", self.local_part, self.sender_domain),
+            'l' => self.local_part.to_owned(),
+            'o' => self.sender_domain.to_owned(),
+            'd' => domain.to_owned(),
+            'i' => match self.ip {
+                IpAddr::V4(ip) => ip.to_string(),
+                IpAddr::V6(ip) => ip
+                    .octets()
+                    .iter()
+                    .flat_map(|byte| [byte >> 4, byte & 0xf])
+                    .map(|nibble| format!("{:x}", nibble))
+                    .collect::<Vec<_>>()
+                    .join("."),
+            },
+            'p' => "unknown".to_owned(),
+            'v' => match self.ip {
+                IpAddr::V4(_) => "in-addr".to_owned(),
+                IpAddr::V6(_) => "ip6".to_owned(),
+            },
+            'h' => self.helo.to_owned(),
+            _ => return Err(SpfResult::PermError),
+        };
+
+        let rest = chars.as_str();
+        let digits_len = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let (digits, rest) = rest.split_at(digits_len);
+        let (reverse, delimiters) = match rest.strip_prefix(['r', 'R']) {
+            Some(delimiters) => (true, delimiters),
+            None => (false, rest),
+        };
+        if !delimiters.chars().all(|c| ".-+,/_=".contains(c)) {
+            return Err(SpfResult::PermError);
+        }
+        let delimiters = if delimiters.is_empty() {
+            "."
+        } else {
+            delimiters
+        };
+
+        let mut parts: Vec<&str> = value.split(|c| delimiters.contains(c)).collect();
+        if reverse {
+            parts.reverse();
+        }
+        if !digits.is_empty() {
+            let keep: usize = digits.parse().map_err(|_| SpfResult::PermError)?;
+            if keep == 0 {
+                return Err(SpfResult::PermError);
+            }
+            parts = parts.split_off(parts.len().saturating_sub(keep));
+        }
+        let value = parts.join(".");
+
+        Ok(if letter.is_ascii_uppercase() {
+            url_escape(&value)
+        } else {
+            value
+        })
+    }
+}
+
+/// Splits a `name=value` modifier
+fn modifier(term: &str) -> Option<(&str, &str)> {
+    let (name, value) = term.split_once('=')?;
+    let mut chars = name.chars();
+    let valid = chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+        && chars.all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c));
+    valid.then_some((name, value))
+}
+
+fn required_argument(argument: &str) -> Result<&str, SpfResult> {
+    match argument.strip_prefix(':') {
+        Some(argument) if !argument.is_empty() => Ok(argument),
+        _ => Err(SpfResult::PermError),
+    }
+}
+
+fn required_domain_spec(argument: &str) -> Result<&str, SpfResult> {
+    let spec = required_argument(argument)?;
+    if spec.contains('/') {
+        return Err(SpfResult::PermError);
+    }
+    Ok(spec)
+}
+
+/// Splits `ip[/prefix]`
+fn parse_network(value: &str, max: u8) -> Result<(&str, u8), SpfResult> {
+    match value.split_once('/') {
+        Some((ip, prefix)) => Ok((ip, parse_prefix(prefix, max)?)),
+        None => Ok((value, max)),
+    }
+}
+
+fn parse_prefix(value: &str, max: u8) -> Result<u8, SpfResult> {
+    // no leading zeros
+    if value.len() > 1 && value.starts_with('0') {
+        return Err(SpfResult::PermError);
+    }
+    match value.parse::<u8>() {
+        Ok(prefix) if prefix <= max => Ok(prefix),
+        _ => Err(SpfResult::PermError),
+    }
+}
+
+/// Whether the first `prefix` bits of two addresses are equal
+fn in_network(ip: &[u8], network: &[u8], prefix: u8) -> bool {
+    let prefix = prefix as usize;
+    let (bytes, bits) = (prefix / 8, prefix % 8);
+    if ip[..bytes] != network[..bytes] {
+        return false;
+    }
+    bits == 0 || {
+        let mask = 0xffu8 << (8 - bits);
+        ip[bytes] & mask == network[bytes] & mask
+    }
+}
+
+fn url_escape(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            byte => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// The address of the client in a `Received` header added by the trusted MX
+/// `by`, for example `[192.0.2.1]` in `from mail.example.com (mail.example.com
+/// [192.0.2.1]) by mx.relayer.example; ...`. Headers added by other hosts are
+/// ignored: the client controls them.
+///
+/// The address is the last literal of the comment following the HELO name,
+/// as written by Postfix: the HELO name itself is chosen by the client and
+/// may be an address literal too.
+pub fn received_client_ip(value: &str, by: &str) -> Option<IpAddr> {
+    let value = value.split(';').next()?;
+    let words: Vec<&str> = value.split_ascii_whitespace().collect();
+    let by_index = words.iter().position(|w| w.eq_ignore_ascii_case("by"))?;
+    let host = words.get(by_index + 1)?;
+    if !host.trim_end_matches('.').eq_ignore_ascii_case(by) {
+        return None;
+    }
+    let comment = match &words[..by_index] {
+        [from, _helo, comment @ ..] if from.eq_ignore_ascii_case("from") => comment,
+        _ => return None,
+    };
+    if !comment.first()?.starts_with('(') {
+        return None;
+    }
+    let end = comment.iter().position(|word| word.ends_with(')'))?;
+    comment[..=end].iter().rev().find_map(|word| {
+        let start = word.rfind('[')?;
+        let end = word[start..].find(']')? + start;
+        let ip = &word[start + 1..end];
+        let ip = ip.strip_prefix("IPv6:").unwrap_or(ip);
+        ip.parse().ok()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestResolver;
+
+    fn resolver() -> TestResolver {
+        TestResolver::default()
+            .with_record(
+                "example.com",
+                "v=spf1 ip4:192.0.2.0/24 ip6:2001:db8::/32 include:_spf.example.net mx -all",
+            )
+            .with_record("example.com", "google-site-verification=abc")
+            .with_record("_spf.example.net", "v=spf1 a:out.example.net ~all")
+            .with_a("out.example.net", "198.51.100.7")
+            .with_mx("example.com", "mx.example.com")
+            .with_a("mx.example.com", "203.0.113.25")
+            .with_record("soft.example.org", "v=spf1 redirect=example.com")
+            .with_record(
+                "exists.example.org",
+                "v=spf1 exists:%{ir}.%{lr+-}._spf.%{d} -all",
+            )
+            .with_a("1.2.0.192.bob.user._spf.exists.example.org", "127.0.0.2")
+    }
+
+    fn check(ip: &str, mail_from: &str) -> SpfResult {
+        check_host(&resolver(), ip.parse().unwrap(), "helo.example", mail_from).result
+    }
+
+    #[test]
+    fn test_mechanisms() {
+        assert_eq!(check("192.0.2.1", "alice@example.com"), SpfResult::Pass);
+        assert_eq!(check("192.0.3.1", "alice@example.com"), SpfResult::Fail);
+        assert_eq!(check("2001:db8::1", "alice@example.com"), SpfResult::Pass);
+        assert_eq!(
+            check("::ffff:192.0.2.1", "alice@example.com"),
+            SpfResult::Pass
+        );
+        // include and mx
+        assert_eq!(check("198.51.100.7", "alice@example.com"), SpfResult::Pass);
+        assert_eq!(check("203.0.113.25", "alice@example.com"), SpfResult::Pass);
+        // redirect
+        assert_eq!(check("192.0.2.1", "a@soft.example.org"), SpfResult::Pass);
+        assert_eq!(check("10.0.0.1", "a@soft.example.org"), SpfResult::Fail);
+        // exists with macros
+        assert_eq!(
+            check("192.0.2.1", "user-bob@exists.example.org"),
+            SpfResult::Pass
+        );
+        assert_eq!(
+            check("192.0.2.2", "user-bob@exists.example.org"),
+            SpfResult::Fail
+        );
+        // no policy
+        assert_eq!(check("192.0.2.1", "alice@example.org"), SpfResult::None);
+    }
+
+    #[test]
+    fn test_identity() {
+        let output = check_host(
+            &resolver(),
+            "192.0.2.1".parse().unwrap(),
+            "example.com",
+            "<>",
+        );
+        assert_eq!(
+            output,
+            SpfOutput {
+                result: SpfResult::Pass,
+                domain: "example.com".to_owned(),
+                scope: SpfScope::Helo,
+                error: None,
+            }
+        );
+        let output = check_host(
+            &resolver(),
+            "192.0.2.1".parse().unwrap(),
+            "helo",
+            "<Alice@Example.COM>",
+        );
+        assert_eq!(output.domain, "example.com");
+        assert_eq!(output.scope, SpfScope::MailFrom);
+        assert_eq!(output.result, SpfResult::Pass);
+    }
+
+    #[test]
+    fn test_errors() {
+        let resolver = TestResolver::default()
+            .with_record("two.example", "v=spf1 -all")
+            .with_record("two.example", "v=spf1 +all")
+            .with_record("unknown.example", "v=spf1 foo -all")
+            .with_record("redirect.example", "v=spf1 redirect=none.example")
+            .with_record("cidr.example", "v=spf1 ip4:192.0.2.0/33 -all")
+            .with_record("loop.example", "v=spf1 include:loop.example -all")
+            .with_record(
+                "void.example",
+                "v=spf1 a:a.void.example a:b.void.example a:c.void.example -all",
+            );
+        let check = |domain: &str| {
+            check_host(
+                &resolver,
+                "192.0.2.1".parse().unwrap(),
+                "helo",
+                &format!("a@{}", domain),
+            )
+            .result
+        };
+        assert_eq!(check("two.example"), SpfResult::PermError);
+        assert_eq!(check("unknown.example"), SpfResult::PermError);
+        assert_eq!(check("redirect.example"), SpfResult::PermError);
+        assert_eq!(check("cidr.example"), SpfResult::PermError);
+        assert_eq!(check("loop.example"), SpfResult::PermError);
+        assert_eq!(check("void.example"), SpfResult::PermError);
+
+        // resolvers without A records can't evaluate `a`
+        struct TxtOnly;
+        impl Lookup for TxtOnly {
+            fn lookup_txt(&self, _: &str) -> Result<Vec<String>, DKIMError> {
+                Ok(vec!["v=spf1 a -all".to_owned()])
+            }
+        }
+        let output = check_host(&TxtOnly, "192.0.2.1".parse().unwrap(), "h", "a@b.example");
+        assert_eq!(output.result, SpfResult::TempError);
+        assert_eq!(output.error, Some(DKIMError::UnsupportedLookup("A")));
+    }
+
+    #[test]
+    fn test_dns_lookup_limit() {
+        let mut resolver = TestResolver::default();
+        for i in 0..11 {
+            resolver = resolver.with_record(
+                &format!("l{}.example", i),
+                &format!("v=spf1 include:l{}.example", i + 1),
+            );
+        }
+        resolver = resolver.with_record("l11.example", "v=spf1 +all");
+        let check = |domain: &str| {
+            check_host(
+                &resolver,
+                "192.0.2.1".parse().unwrap(),
+                "helo",
+                &format!("a@{}", domain),
+            )
+            .result
+        };
+        // 10 includes from l1, 11 from l0
+        assert_eq!(check("l1.example"), SpfResult::Pass);
+        assert_eq!(check("l0.example"), SpfResult::PermError);
+    }
+
+    #[test]
+    fn test_received_client_ip() {
+        let value = "from mail.example.com (mail.example.com [192.0.2.1])\r\n \
+                     by mx.relayer.example (Postfix) with ESMTPS id 4Q; Tue, 1 Aug 2023";
+        assert_eq!(
+            received_client_ip(value, "mx.relayer.example"),
+            Some("192.0.2.1".parse().unwrap())
+        );
+        assert_eq!(received_client_ip(value, "other.example"), None);
+        assert_eq!(
+            received_client_ip(
+                "from h ([IPv6:2001:db8::1]) by MX.relayer.example.; now",
+                "mx.relayer.example"
+            ),
+            Some("2001:db8::1".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_received_client_ip_helo_literal() {
+        let value = "from [6.6.6.6] (mail.example.com [192.0.2.1])\r\n \
+                     by mx.relayer.example (Postfix) with ESMTPS id 4Q; Tue, 1 Aug 2023";
+        assert_eq!(
+            received_client_ip(value, "mx.relayer.example"),
+            Some("192.0.2.1".parse().unwrap())
+        );
+        assert_eq!(
+            received_client_ip(
+                "from [6.6.6.6] (unknown [192.0.2.1]) by mx.relayer.example; now",
+                "mx.relayer.example"
+            ),
+            Some("192.0.2.1".parse().unwrap())
+        );
+        // Without the comment of the MX, the HELO literal is not an address
+        assert_eq!(
+            received_client_ip(
+                "from [6.6.6.6] by mx.relayer.example; now",
+                "mx.relayer.example"
+            ),
+            None
+        );
+    }
+}
@@ -0,0 +1,440 @@
+//! Fixtures to test code that consumes DKIM-signed emails: a throwaway key,
+//! a resolver serving its record (and the records of SPF policies) and a
+//! builder of signed, optionally tampered, emails.
+//!
+//! A test signs an email with [EmailBuilder] and verifies it with the
+//! resolver of its [TestKey], for example
+//! `verify_raw_email_with_resolver(&EmailBuilder::new(&key).build(), &key.resolver(), now)`.
+use std::collections::BTreeMap;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use crate::keys::{self, DkimRecord};
+use crate::{dns, DKIMError, SignerBuilder};
+
+/// RSA key shared by the fixtures. Generating a key in every test is slow.
+const RSA_PRIVATE_KEY: &str = include_str!("../test/keys/2022.private");
+
+/// A key pair for a domain and selector. Never use it outside of tests.
+#[derive(Debug, Clone)]
+pub struct TestKey {
+    domain: String,
+    selector: String,
+    pem: String,
+}
+
+impl TestKey {
+    /// The RSA key of the fixtures. It is public, in the sources of this
+    /// crate.
+    pub fn rsa(domain: &str, selector: &str) -> Self {
+        TestKey {
+            domain: domain.to_owned(),
+            selector: selector.to_owned(),
+            pem: RSA_PRIVATE_KEY.to_owned(),
+        }
+    }
+
+    /// A freshly generated Ed25519 key
+    #[cfg(feature = "ed25519")]
+    pub fn ed25519(domain: &str, selector: &str) -> Self {
+        TestKey {
+            domain: domain.to_owned(),
+            selector: selector.to_owned(),
+            pem: keys::to_pkcs8_pem(&keys::generate_ed25519()).expect("serializable key"),
+        }
+    }
+
+    pub fn domain(&self) -> &str {
+        &self.domain
+    }
+
+    pub fn selector(&self) -> &str {
+        &self.selector
+    }
+
+    /// Private key, PKCS#1 or PKCS#8 PEM
+    pub fn private_key_pem(&self) -> &str {
+        &self.pem
+    }
+
+    /// Name of the key record: `<selector>._domainkey.<domain>`
+    pub fn dns_name(&self) -> String {
+        format!("{}._domainkey.{}", self.selector, self.domain)
+    }
+
+    /// Value of the key record: `v=DKIM1; k=...; p=...`
+    pub fn dns_record(&self) -> String {
+        let key = keys::private_key_from_pem(&self.pem).expect("valid key");
+        DkimRecord::from_backend(&key).expect("public key").to_txt()
+    }
+
+    /// A resolver serving the record of this key only
+    pub fn resolver(&self) -> TestResolver {
+        TestResolver::default().with_key(self)
+    }
+
+    /// Signs a raw email, for example a fixture written by a mail client,
+    /// with the headers Gmail signs
+    pub fn sign(&self, raw_email: &[u8]) -> Vec<u8> {
+        let parsed = mailparse::parse_mail(raw_email).expect("valid email");
+        let private_key = keys::private_key_from_pem(&self.pem).expect("valid key");
+        SignerBuilder::new()
+            .with_signed_headers(&[
+                "From",
+                "To",
+                "Subject",
+                "Date",
+                "Message-ID",
+                "MIME-Version",
+                "Content-Type",
+                "Content-Transfer-Encoding",
+            ])
+            .expect("From is signed")
+            .with_private_key(private_key)
+            .with_selector(&self.selector)
+            .with_signing_domain(&self.domain)
+            .with_header_canonicalization(crate::canonicalization::Type::Relaxed)
+            .with_body_canonicalization(crate::canonicalization::Type::Relaxed)
+            .build()
+            .expect("valid signer")
+            .sign_message(&parsed)
+            .expect("signed email")
+    }
+}
+
+/// In-memory resolver. Unknown names have no key.
+#[derive(Debug, Clone, Default)]
+pub struct TestResolver {
+    records: BTreeMap<String, Vec<String>>,
+    a: BTreeMap<String, Vec<Ipv4Addr>>,
+    aaaa: BTreeMap<String, Vec<Ipv6Addr>>,
+    mx: BTreeMap<String, Vec<String>>,
+}
+
+impl TestResolver {
+    /// Serve the record of a key
+    pub fn with_key(self, key: &TestKey) -> Self {
+        self.with_record(&key.dns_name(), &key.dns_record())
+    }
+
+    /// Serve a TXT record, in addition to the ones already served for the name
+    pub fn with_record(mut self, name: &str, value: &str) -> Self {
+        self.records
+            .entry(name.to_lowercase())
+            .or_default()
+            .push(value.to_owned());
+        self
+    }
+
+    /// Serve an A record
+    pub fn with_a(mut self, name: &str, ip: &str) -> Self {
+        let ip = ip.parse().expect("IPv4 address");
+        self.a.entry(name.to_lowercase()).or_default().push(ip);
+        self
+    }
+
+    /// Serve an AAAA record
+    pub fn with_aaaa(mut self, name: &str, ip: &str) -> Self {
+        let ip = ip.parse().expect("IPv6 address");
+        self.aaaa.entry(name.to_lowercase()).or_default().push(ip);
+        self
+    }
+
+    /// Serve an MX record. Exchanges are served in the order they are added.
+    pub fn with_mx(mut self, name: &str, exchange: &str) -> Self {
+        self.mx
+            .entry(name.to_lowercase())
+            .or_default()
+            .push(exchange.to_owned());
+        self
+    }
+}
+
+fn lookup<T: Clone>(map: &BTreeMap<String, Vec<T>>, name: &str) -> Result<Vec<T>, DKIMError> {
+    map.get(&name.to_lowercase())
+        .cloned()
+        .ok_or(DKIMError::NoKeyForSignature)
+}
+
+impl dns::Lookup for TestResolver {
+    fn lookup_txt(&self, name: &str) -> Result<Vec<String>, DKIMError> {
+        lookup(&self.records, name)
+    }
+
+    fn lookup_a(&self, name: &str) -> Result<Vec<Ipv4Addr>, DKIMError> {
+        lookup(&self.a, name)
+    }
+
+    fn lookup_aaaa(&self, name: &str) -> Result<Vec<Ipv6Addr>, DKIMError> {
+        lookup(&self.aaaa, name)
+    }
+
+    fn lookup_mx(&self, name: &str) -> Result<Vec<String>, DKIMError> {
+        lookup(&self.mx, name)
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Tampering {
+    Header(String, String),
+    Body(String),
+    Inject(String, String),
+}
+
+/// Builder of signed emails. The headers are signed in order: From, To,
+/// Subject and the extra headers, with relaxed canonicalization.
+#[derive(Debug, Clone)]
+pub struct EmailBuilder {
+    key: TestKey,
+    from: String,
+    to: String,
+    subject: String,
+    body: String,
+    headers: Vec<(String, String)>,
+    signed_headers: Option<Vec<String>>,
+    body_length: Option<usize>,
+    time: Option<chrono::DateTime<chrono::Utc>>,
+    tamperings: Vec<Tampering>,
+}
+
+impl EmailBuilder {
+    /// New email signed with `key`, from `sender@<domain of the key>`
+    pub fn new(key: &TestKey) -> Self {
+        EmailBuilder {
+            key: key.clone(),
+            from: format!("sender@{}", key.domain),
+            to: "relayer@example.com".to_owned(),
+            subject: "".to_owned(),
+            body: "".to_owned(),
+            headers: vec![],
+            signed_headers: None,
+            body_length: None,
+            time: None,
+            tamperings: vec![],
+        }
+    }
+
+    /// Value of the From header, an address or a mailbox like
+    /// `Alice <alice@example.com>`
+    pub fn from(mut self, value: &str) -> Self {
+        self.from = value.to_owned();
+        self
+    }
+
+    pub fn to(mut self, value: &str) -> Self {
+        self.to = value.to_owned();
+        self
+    }
+
+    pub fn subject(mut self, value: &str) -> Self {
+        self.subject = value.to_owned();
+        self
+    }
+
+    /// Body of the email. Line endings are converted to CRLF.
+    pub fn body(mut self, value: &str) -> Self {
+        self.body = value.to_owned();
+        self
+    }
+
+    /// Add a header after the default ones. Adding a From or a Subject makes
+    /// it a duplicate.
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// Override the signed headers (h=)
+    pub fn signed_headers(mut self, names: &[&str]) -> Self {
+        self.signed_headers = Some(names.iter().map(|name| name.to_string()).collect());
+        self
+    }
+
+    /// Sign only the first `length` bytes of the canonicalized body (l=)
+    pub fn body_length(mut self, length: usize) -> Self {
+        self.body_length = Some(length);
+        self
+    }
+
+    /// Timestamp of the signature (t=), now by default
+    pub fn time(mut self, value: chrono::DateTime<chrono::Utc>) -> Self {
+        self.time = Some(value);
+        self
+    }
+
+    /// After signing, change the value of the first instance of a header
+    pub fn tamper_header(mut self, name: &str, value: &str) -> Self {
+        self.tamperings
+            .push(Tampering::Header(name.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// After signing, replace the body
+    pub fn tamper_body(mut self, value: &str) -> Self {
+        self.tamperings.push(Tampering::Body(value.to_owned()));
+        self
+    }
+
+    /// After signing, add a header at the top of the email
+    pub fn inject_header(mut self, name: &str, value: &str) -> Self {
+        self.tamperings
+            .push(Tampering::Inject(name.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// The email before signing
+    pub fn build_unsigned(&self) -> Vec<u8> {
+        let mut email = format!(
+            "From: {}\r\nTo: {}\r\nSubject: {}\r\n",
+            self.from, self.to, self.subject
+        );
+        for (name, value) in &self.headers {
+            email += &format!("{}: {}\r\n", name, value);
+        }
+        email += "\r\n";
+        email += &crlf(&self.body);
+        email.into_bytes()
+    }
+
+    /// The signed email, with the tamperings applied
+    pub fn build(&self) -> Vec<u8> {
+        let unsigned = self.build_unsigned();
+        let parsed = mailparse::parse_mail(&unsigned).expect("valid email");
+
+        let signed_headers: Vec<String> = match &self.signed_headers {
+            Some(names) => names.clone(),
+            None => ["From", "To", "Subject"]
+                .iter()
+                .map(|name| name.to_string())
+                .chain(self.headers.iter().map(|(name, _)| name.clone()))
+                .collect(),
+        };
+        let signed_headers: Vec<&str> = signed_headers.iter().map(|h| h.as_str()).collect();
+        let private_key = keys::private_key_from_pem(&self.key.pem).expect("valid key");
+        let mut signer = SignerBuilder::new()
+            .with_signed_headers(&signed_headers)
+            .expect("From is signed")
+            .with_private_key(private_key)
+            .with_selector(&self.key.selector)
+            .with_signing_domain(&self.key.domain)
+            .with_header_canonicalization(crate::canonicalization::Type::Relaxed)
+            .with_body_canonicalization(crate::canonicalization::Type::Relaxed);
+        if let Some(length) = self.body_length {
+            signer = signer.with_body_length(length);
+        }
+        if let Some(time) = self.time {
+            signer = signer.with_time(time);
+        }
+        let signer = signer.build().expect("valid signer");
+        let signed = signer.sign_message(&parsed).expect("signed email");
+
+        let mut email = String::from_utf8(signed).expect("UTF-8 email");
+        for tampering in &self.tamperings {
+            email = apply(email, tampering);
+        }
+        email.into_bytes()
+    }
+}
+
+fn crlf(value: &str) -> String {
+    value.replace("\r\n", "\n").replace('\n', "\r\n")
+}
+
+fn apply(email: String, tampering: &Tampering) -> String {
+    let (headers, body) = email.split_once("\r\n\r\n").unwrap_or((&email, ""));
+    match tampering {
+        Tampering::Header(name, value) => {
+            let prefix = format!("{}:", name.to_lowercase());
+            let mut replaced = false;
+            let headers: Vec<String> = headers
+                .split("\r\n")
+                .map(|line| {
+                    if !replaced && line.to_lowercase().starts_with(&prefix) {
+                        replaced = true;
+                        format!("{}: {}", &line[..name.len()], value)
+                    } else {
+                        line.to_owned()
+                    }
+                })
+                .collect();
+            assert!(replaced, "no {} header to tamper with", name);
+            format!("{}\r\n\r\n{}", headers.join("\r\n"), body)
+        }
+        Tampering::Body(value) => format!("{}\r\n\r\n{}", headers, crlf(value)),
+        Tampering::Inject(name, value) => format!("{}: {}\r\n{}", name, value, email),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{verify_raw_email_with_resolver, DKIMResult};
+
+    fn verify(key: &TestKey, email: &[u8]) -> DKIMResult {
+        verify_raw_email_with_resolver(email, &key.resolver(), chrono::Utc::now()).unwrap()
+    }
+
+    #[test]
+    fn test_email_builder() {
+        let key = TestKey::rsa("example.com", "test");
+        let builder = EmailBuilder::new(&key)
+            .from("Alice <alice@example.com>")
+            .subject("transfer bob.near 1")
+            .body("Hello\nBob\n");
+        let email = builder.build();
+        let email_str = String::from_utf8(email.clone()).unwrap();
+        assert!(email_str.starts_with("DKIM-Signature: v=1; a=rsa-sha256; d=example.com; s=test;"));
+        assert!(email_str.ends_with(
+            "From: Alice <alice@example.com>\r\nTo: relayer@example.com\r\n\
+             Subject: transfer bob.near 1\r\n\r\nHello\r\nBob\r\n"
+        ));
+        assert_eq!(verify(&key, &email).summary(), "pass");
+
+        // the key of another selector
+        let other_key = TestKey::rsa("example.com", "other");
+        assert_eq!(
+            verify_raw_email_with_resolver(&email, &other_key.resolver(), chrono::Utc::now())
+                .unwrap()
+                .error(),
+            Some(DKIMError::NoKeyForSignature)
+        );
+    }
+
+    #[test]
+    fn test_email_builder_tampering() {
+        let key = TestKey::ed25519("example.com", "ed");
+        let builder = EmailBuilder::new(&key).subject("init");
+        assert_eq!(verify(&key, &builder.build()).summary(), "pass");
+
+        let email = builder
+            .clone()
+            .tamper_header("subject", "delete_key")
+            .build();
+        assert!(String::from_utf8_lossy(&email).contains("\r\nSubject: delete_key\r\n"));
+        assert_eq!(
+            verify(&key, &email).error(),
+            Some(DKIMError::SignatureDidNotVerify)
+        );
+
+        let email = builder.clone().tamper_body("other body").build();
+        assert_eq!(
+            verify(&key, &email).error(),
+            Some(DKIMError::BodyHashDidNotVerify)
+        );
+
+        // an unsigned header on top doesn't break the signature
+        let email = builder
+            .clone()
+            .inject_header("From", "mallory@example.org")
+            .build();
+        assert!(email.starts_with(b"From: mallory@example.org\r\nDKIM-Signature:"));
+        assert_eq!(verify(&key, &email).summary(), "pass");
+
+        // both instances are signed
+        let email = builder
+            .header("Subject", "transfer mallory.near 100")
+            .build();
+        let signature = String::from_utf8_lossy(&email);
+        assert!(signature.contains("h=from:to:subject:subject;"));
+        assert_eq!(verify(&key, &email).summary(), "pass");
+    }
+}
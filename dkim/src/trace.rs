@@ -0,0 +1,144 @@
+//! Opt-in diagnostics collected during the verification, to understand why a
+//! signature did not verify. See [crate::verify_email_with_trace].
+use core::fmt;
+
+use crate::prelude::*;
+use crate::DKIMError;
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "borsh",
+    derive(borsh::BorshSerialize, borsh::BorshDeserialize)
+)]
+/// A header instance picked by the `h=` list
+pub struct SelectedHeader {
+    /// Index of the header in the message, starting from the top
+    pub index: usize,
+    /// Name of the header as it appears in the message
+    pub name: String,
+    /// Raw value of the header
+    pub value: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "borsh",
+    derive(borsh::BorshSerialize, borsh::BorshDeserialize)
+)]
+/// Comparison of a header copied in `z=` with the message
+pub struct CopiedHeaderDiff {
+    /// Name of the header
+    pub name: String,
+    /// Value at signing time, decoded from `z=`
+    pub copied_value: String,
+    /// Value found in the message, if the header is still present
+    pub message_value: Option<String>,
+}
+
+impl CopiedHeaderDiff {
+    /// Whether the header is unchanged, ignoring differences in whitespace
+    pub fn matches(&self) -> bool {
+        match &self.message_value {
+            Some(value) => normalize(value) == normalize(&self.copied_value),
+            None => false,
+        }
+    }
+}
+
+fn normalize(value: &str) -> String {
+    value.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "borsh",
+    derive(borsh::BorshSerialize, borsh::BorshDeserialize)
+)]
+/// Diagnostics for a single DKIM-Signature header
+pub struct SignatureTrace {
+    /// Raw value of the DKIM-Signature header
+    pub header: String,
+    /// Body after canonicalization and `l=` truncation
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::base64_bytes"))]
+    pub canonicalized_body: Vec<u8>,
+    /// Body hash computed from `canonicalized_body`
+    pub computed_body_hash: Option<String>,
+    /// Body hash found in `bh=`
+    pub signed_body_hash: Option<String>,
+    /// Header instances selected by `h=`, in signing order
+    pub selected_headers: Vec<SelectedHeader>,
+    /// Exact data fed to the header hash
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::base64_bytes"))]
+    pub header_hash_input: Vec<u8>,
+    /// Header-by-header comparison with `z=`, empty when absent
+    pub copied_headers_diff: Vec<CopiedHeaderDiff>,
+    /// Why this signature did not verify
+    pub error: Option<DKIMError>,
+}
+
+impl fmt::Display for SignatureTrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "signature: {}", self.header.trim())?;
+        match &self.error {
+            Some(err) => writeln!(f, "result: fail ({})", err)?,
+            None => writeln!(f, "result: pass")?,
+        }
+        writeln!(
+            f,
+            "body: {} bytes, computed bh={}, signed bh={}",
+            self.canonicalized_body.len(),
+            self.computed_body_hash.as_deref().unwrap_or("-"),
+            self.signed_body_hash.as_deref().unwrap_or("-"),
+        )?;
+        for header in &self.selected_headers {
+            writeln!(f, "selected header #{}: {}", header.index, header.name)?;
+        }
+        writeln!(
+            f,
+            "header hash input: {:?}",
+            String::from_utf8_lossy(&self.header_hash_input)
+        )?;
+        for diff in &self.copied_headers_diff {
+            if diff.matches() {
+                writeln!(f, "z= {}: unchanged", diff.name)?;
+            } else {
+                writeln!(
+                    f,
+                    "z= {}: {:?} -> {:?}",
+                    diff.name, diff.copied_value, diff.message_value
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_copied_header_diff_matches() {
+        let diff = CopiedHeaderDiff {
+            name: "Subject".to_owned(),
+            copied_value: "demo run".to_owned(),
+            message_value: Some(" demo\r\n  run".to_owned()),
+        };
+        assert!(diff.matches());
+
+        let diff = CopiedHeaderDiff {
+            message_value: Some("demo walk".to_owned()),
+            ..diff
+        };
+        assert!(!diff.matches());
+
+        let diff = CopiedHeaderDiff {
+            message_value: None,
+            ..diff
+        };
+        assert!(!diff.matches());
+    }
+}
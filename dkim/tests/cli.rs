@@ -0,0 +1,122 @@
+//! End-to-end tests of the `dkim` binary
+#![cfg(feature = "cli")]
+
+use std::path::Path;
+use std::process::{Command, Output};
+
+const EMAIL: &str = "From: Sven <sven@example.com>\r\nSubject: hello\r\n\r\nHi  there \r\n";
+
+fn dkim(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_dkim"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn temp_dir(name: &str) -> std::path::PathBuf {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn test_keygen_sign_verify() {
+    let dir = temp_dir("keygen_sign_verify");
+    let email_path = dir.join("email.eml");
+    std::fs::write(&email_path, EMAIL).unwrap();
+
+    let output = dkim(&[
+        "keygen",
+        "--algorithm",
+        "ed25519",
+        "--selector",
+        "ed",
+        "--out",
+        dir.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+
+    let output = dkim(&[
+        "sign",
+        email_path.to_str().unwrap(),
+        "--key",
+        dir.join("ed.private").to_str().unwrap(),
+        "--domain",
+        "example.com",
+        "--selector",
+        "ed",
+    ]);
+    assert!(output.status.success());
+    let signed_email = String::from_utf8(output.stdout).unwrap();
+    assert!(signed_email.starts_with("DKIM-Signature: v=1; a=ed25519-sha256; d=example.com;"));
+    assert!(signed_email.ends_with(EMAIL));
+    let signed_path = dir.join("signed.eml");
+    std::fs::write(&signed_path, &signed_email).unwrap();
+
+    let zone = dir.join("ed.txt");
+    let output = dkim(&[
+        "verify",
+        signed_path.to_str().unwrap(),
+        "--zone",
+        zone.to_str().unwrap(),
+        "--origin",
+        "example.com",
+    ]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "#0 d=example.com s=ed a=ed25519-sha256: pass\n"
+    );
+
+    // unknown key
+    let output = dkim(&["verify", signed_path.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "#0 d=example.com s=ed a=ed25519-sha256: permerror (no key for signature)\n"
+    );
+}
+
+#[test]
+fn test_canonicalize_and_inspect() {
+    let dir = temp_dir("canonicalize_and_inspect");
+    let signed_path = dir.join("signed.eml");
+    let email_path = dir.join("email.eml");
+    std::fs::write(&email_path, EMAIL).unwrap();
+    let output = dkim(&[
+        "sign",
+        email_path.to_str().unwrap(),
+        "--key",
+        "./test/keys/2022.private",
+        "--domain",
+        "example.com",
+        "--selector",
+        "2022",
+        "--headers",
+        "From,Subject",
+    ]);
+    assert!(output.status.success());
+    std::fs::write(&signed_path, output.stdout).unwrap();
+
+    let output = dkim(&["canonicalize", signed_path.to_str().unwrap()]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains(
+        "--- headers ---\nfrom:Sven <sven@example.com>\r\nsubject:hello\r\ndkim-signature:v=1;"
+    ));
+    assert!(stdout.ends_with("--- body ---\nHi there\r\n"));
+
+    let output = dkim(&[
+        "inspect",
+        signed_path.to_str().unwrap(),
+        "--zone",
+        "./test/keys/2022.txt",
+        "--origin",
+        "example.com",
+    ]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("  headers (h=): from:subject\n"));
+    assert!(stdout.contains("  key record 2022._domainkey.example.com:\n    v=DKIM1\n"));
+}
@@ -0,0 +1,194 @@
+//! Composition of the emails sent to the dkim-controller contract: commands
+//! validated with the grammar of `email-command`, `mailto:` URIs opening
+//! them in the mail client of the user, and the account controlled by a
+//! sender.
+//!
+//! ```
+//! use email_auth_client::{transfer, Client};
+//!
+//! let client = Client::new("controller.near", "controller@example.com");
+//! let command = transfer("bob.near", "1.5").unwrap();
+//! assert_eq!(
+//!     client.mailto(&command),
+//!     "mailto:controller@example.com?subject=transfer%20bob.near%201.5"
+//! );
+//! assert_eq!(
+//!     client.account_id("alice@example.com").unwrap(),
+//!     "alice_example_com.controller.near"
+//! );
+//! ```
+use std::fmt;
+
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+
+pub use email_auth_types::CommandEnum;
+pub use email_command::{parse_command, CommandError};
+
+#[cfg(feature = "wasm")]
+mod wasm;
+
+/// Characters percent-encoded in a `mailto:` URI (RFC 6068): all but the
+/// unreserved ones
+const MAILTO_COMPONENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+/// Characters percent-encoded in the address of a `mailto:` URI
+const MAILTO_ADDRESS: &AsciiSet = &MAILTO_COMPONENT.remove(b'@');
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientError {
+    /// The controller rejects the emails of addresses with this character
+    UnsupportedCharacter(char),
+    /// The derived account id is not a valid account id
+    InvalidAccountId(CommandError),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::UnsupportedCharacter(c) => {
+                write!(
+                    f,
+                    "the sender email contains an unsupported character '{}'",
+                    c
+                )
+            }
+            ClientError::InvalidAccountId(err) => write!(f, "no account for the sender: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+/// Client of a dkim-controller contract receiving the emails sent to `inbox`
+#[derive(Debug, Clone)]
+pub struct Client {
+    controller_id: String,
+    inbox: String,
+}
+
+impl Client {
+    pub fn new(controller_id: &str, inbox: &str) -> Self {
+        Client {
+            controller_id: controller_id.to_owned(),
+            inbox: inbox.to_owned(),
+        }
+    }
+
+    /// The account controlled by the emails of `sender`, an address like
+    /// `alice@example.com`, as derived by the controller
+    pub fn account_id(&self, sender: &str) -> Result<String, ClientError> {
+        let prefix = email_auth_types::sender_to_account(sender)
+            .map_err(ClientError::UnsupportedCharacter)?;
+        email_command::parse_account_id(&format!("{}.{}", prefix, self.controller_id))
+            .map_err(ClientError::InvalidAccountId)
+    }
+
+    /// `mailto:` URI of an email to the controller with `command` as Subject
+    pub fn mailto(&self, command: &CommandEnum) -> String {
+        format!(
+            "mailto:{}?subject={}",
+            utf8_percent_encode(&self.inbox, MAILTO_ADDRESS),
+            utf8_percent_encode(&command.to_string(), MAILTO_COMPONENT)
+        )
+    }
+}
+
+/// Creates the account of the sender
+pub fn init() -> CommandEnum {
+    CommandEnum::Init
+}
+
+/// Adds a full access key, `ed25519:<base58>`, to the account of the sender
+pub fn add_key(public_key: &str) -> Result<CommandEnum, CommandError> {
+    Ok(CommandEnum::AddKey(email_command::parse_public_key(
+        public_key,
+    )?))
+}
+
+/// Deletes a key, `ed25519:<base58>`, of the account of the sender
+pub fn delete_key(public_key: &str) -> Result<CommandEnum, CommandError> {
+    Ok(CommandEnum::DeleteKey(email_command::parse_public_key(
+        public_key,
+    )?))
+}
+
+/// Transfers `amount` NEAR, like `1.5`, from the account of the sender
+pub fn transfer(to: &str, amount: &str) -> Result<CommandEnum, CommandError> {
+    Ok(CommandEnum::Transfer(
+        email_command::parse_account_id(to)?,
+        email_command::parse_amount(amount)?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use email_command::CommandErrorKind;
+
+    const KEY: &str = "ed25519:3tXAA9zf5YSLxYELSbxwhEvMd7h9itTfCcUfEc3QfPgD";
+
+    #[test]
+    fn test_commands() {
+        assert_eq!(init().to_string(), "init");
+        assert_eq!(
+            add_key(&KEY.replace("ed25519", "ED25519"))
+                .unwrap()
+                .to_string(),
+            format!("add_key {}", KEY)
+        );
+        assert_eq!(
+            delete_key(KEY).unwrap().to_string(),
+            format!("delete_key {}", KEY)
+        );
+        assert_eq!(
+            transfer("bob.near", "1.50").unwrap().to_string(),
+            "transfer bob.near 1.5"
+        );
+
+        let err = transfer("Bob.near", "1").unwrap_err();
+        assert_eq!(
+            err.kind,
+            CommandErrorKind::InvalidAccountId("must be lowercase")
+        );
+        assert_eq!(err.suggestion.as_deref(), Some("bob.near"));
+        let err = add_key(&KEY[8..]).unwrap_err();
+        assert_eq!(err.suggestion.as_deref(), Some(KEY));
+    }
+
+    #[test]
+    fn test_mailto() {
+        let client = Client::new("controller.near", "relay+near@example.com");
+        assert_eq!(
+            client.mailto(&add_key(KEY).unwrap()),
+            format!(
+                "mailto:relay%2Bnear@example.com?subject=add_key%20ed25519%3A{}",
+                &KEY[8..]
+            )
+        );
+    }
+
+    #[test]
+    fn test_account_id() {
+        let client = Client::new("controller.near", "controller@example.com");
+        assert_eq!(
+            client.account_id("alice.b-c@example.com").unwrap(),
+            "alice_b-c_example_com.controller.near"
+        );
+        assert_eq!(
+            client.account_id("alice+near@example.com"),
+            Err(ClientError::UnsupportedCharacter('+'))
+        );
+        // Rejected by the controller when it parses the account id
+        assert!(matches!(
+            client.account_id("Alice@example.com"),
+            Err(ClientError::InvalidAccountId(_))
+        ));
+        assert!(matches!(
+            client.account_id("alice_@example.com"),
+            Err(ClientError::InvalidAccountId(_))
+        ));
+    }
+}
@@ -0,0 +1,64 @@
+//! JavaScript bindings, built with `wasm-pack build --target web -- --features wasm`.
+//! The commands are strings and the errors are thrown as `Error`s with the
+//! message of the Rust error.
+//!
+//! ```js
+//! const client = new EmailAuthClient("controller.near", "controller@example.com");
+//! window.location.href = client.mailto(transferCommand("bob.near", "1.5"));
+//! ```
+use wasm_bindgen::prelude::*;
+
+use crate::{parse_command, Client};
+
+fn js_error(err: impl std::fmt::Display) -> JsError {
+    JsError::new(&err.to_string())
+}
+
+#[wasm_bindgen(js_name = EmailAuthClient)]
+pub struct WasmClient(Client);
+
+#[wasm_bindgen(js_class = EmailAuthClient)]
+impl WasmClient {
+    #[wasm_bindgen(constructor)]
+    pub fn new(controller_id: &str, inbox: &str) -> WasmClient {
+        WasmClient(Client::new(controller_id, inbox))
+    }
+
+    /// The account controlled by the emails of `sender`
+    #[wasm_bindgen(js_name = accountId)]
+    pub fn account_id(&self, sender: &str) -> Result<String, JsError> {
+        self.0.account_id(sender).map_err(js_error)
+    }
+
+    /// `mailto:` URI of a command, in any form accepted by the grammar
+    pub fn mailto(&self, command: &str) -> Result<String, JsError> {
+        let command = parse_command(command).map_err(js_error)?;
+        Ok(self.0.mailto(&command))
+    }
+}
+
+#[wasm_bindgen(js_name = initCommand)]
+pub fn init_command() -> String {
+    crate::init().to_string()
+}
+
+#[wasm_bindgen(js_name = addKeyCommand)]
+pub fn add_key_command(public_key: &str) -> Result<String, JsError> {
+    crate::add_key(public_key)
+        .map(|command| command.to_string())
+        .map_err(js_error)
+}
+
+#[wasm_bindgen(js_name = deleteKeyCommand)]
+pub fn delete_key_command(public_key: &str) -> Result<String, JsError> {
+    crate::delete_key(public_key)
+        .map(|command| command.to_string())
+        .map_err(js_error)
+}
+
+#[wasm_bindgen(js_name = transferCommand)]
+pub fn transfer_command(to: &str, amount: &str) -> Result<String, JsError> {
+    crate::transfer(to, amount)
+        .map(|command| command.to_string())
+        .map_err(js_error)
+}
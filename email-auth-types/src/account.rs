@@ -0,0 +1,28 @@
+/// Prefix of the account controlled by the emails of `sender`, the account
+/// id being `<prefix>.<controller account id>`: `alice@example.com` controls
+/// `alice_example_com.<controller>`. Returns the first unsupported character
+/// of the address.
+pub fn sender_to_account(sender: &str) -> Result<String, char> {
+    sender
+        .chars()
+        .map(|x| match x {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '-' => Ok(x),
+            '@' | '.' => Ok('_'),
+            _ => Err(x),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sender_to_account() {
+        assert_eq!(
+            sender_to_account("alice.b-c@example.com"),
+            Ok("alice_b-c_example_com".to_owned())
+        );
+        assert_eq!(sender_to_account("alice+near@example.com"), Err('+'));
+    }
+}
@@ -0,0 +1,56 @@
+//! JSON arguments of the contract methods, see [crate::method]
+use serde::{Deserialize, Serialize};
+
+use crate::Balance;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReceiveEmailArgs {
+    /// Raw bytes of the email, as received
+    pub full_email: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SetControllerArgs {
+    pub controller_id: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AddKeyArgs {
+    pub public_key: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeleteKeyArgs {
+    pub public_key: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransferArgs {
+    pub to: String,
+    /// In yoctoNEAR, a JSON number
+    pub amount: Balance,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json() {
+        assert_eq!(
+            serde_json::to_string(&ReceiveEmailArgs {
+                full_email: b"To".to_vec()
+            })
+            .unwrap(),
+            r#"{"full_email":[84,111]}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&TransferArgs {
+                to: "bob.near".to_owned(),
+                amount: crate::ONE_NEAR,
+            })
+            .unwrap(),
+            r#"{"to":"bob.near","amount":1000000000000000000000000}"#
+        );
+    }
+}
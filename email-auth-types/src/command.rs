@@ -0,0 +1,39 @@
+use std::fmt;
+
+/// Amount in yoctoNEAR
+pub type Balance = u128;
+
+pub const ONE_NEAR: Balance = 1_000_000_000_000_000_000_000_000;
+/// Decimals of an amount in NEAR
+pub const NEAR_DECIMALS: usize = 24;
+
+/// A command sent in the Subject of an email. The public keys are
+/// `ed25519:<base58>` strings and the account ids are NEAR account ids.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommandEnum {
+    Init,
+    AddKey(String),
+    DeleteKey(String),
+    Transfer(String, Balance),
+}
+
+/// Formats the command in the canonical form of the grammar of the
+/// `email-command` crate
+impl fmt::Display for CommandEnum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandEnum::Init => write!(f, "init"),
+            CommandEnum::AddKey(key) => write!(f, "add_key {}", key),
+            CommandEnum::DeleteKey(key) => write!(f, "delete_key {}", key),
+            CommandEnum::Transfer(account, amount) => {
+                write!(f, "transfer {} {}", account, amount / ONE_NEAR)?;
+                let decimals = amount % ONE_NEAR;
+                if decimals != 0 {
+                    let decimals = format!("{:0width$}", decimals, width = NEAR_DECIMALS);
+                    write!(f, ".{}", decimals.trim_end_matches('0'))?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
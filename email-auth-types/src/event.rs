@@ -0,0 +1,77 @@
+//! Events logged by the dkim-controller, in the format of NEP-297
+//! <https://nomicon.io/Standards/EventsFormat>
+use serde::{Deserialize, Serialize};
+
+pub const EVENT_STANDARD: &str = "email-auth";
+pub const EVENT_VERSION: &str = "1.0.0";
+const EVENT_PREFIX: &str = "EVENT_JSON:";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", content = "data", rename_all = "snake_case")]
+pub enum ControllerEvent {
+    /// The command of a verified email, executed on the account of its sender
+    Command {
+        sender: String,
+        account_id: String,
+        /// The command in its canonical form
+        command: String,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+struct EventLog {
+    standard: String,
+    version: String,
+    #[serde(flatten)]
+    event: ControllerEvent,
+}
+
+impl ControllerEvent {
+    /// The log line of the event
+    pub fn to_log(&self) -> String {
+        let log = EventLog {
+            standard: EVENT_STANDARD.to_owned(),
+            version: EVENT_VERSION.to_owned(),
+            event: self.clone(),
+        };
+        format!(
+            "{}{}",
+            EVENT_PREFIX,
+            serde_json::to_string(&log).expect("serializable event")
+        )
+    }
+
+    /// The event of a log line, if it is an event of the controller
+    pub fn from_log(line: &str) -> Option<Self> {
+        let log: EventLog = serde_json::from_str(line.strip_prefix(EVENT_PREFIX)?).ok()?;
+        (log.standard == EVENT_STANDARD).then_some(log.event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log() {
+        let event = ControllerEvent::Command {
+            sender: "alice@example.com".to_owned(),
+            account_id: "alice_example_com.controller.near".to_owned(),
+            command: "init".to_owned(),
+        };
+        let log = event.to_log();
+        assert_eq!(
+            log,
+            "EVENT_JSON:{\"standard\":\"email-auth\",\"version\":\"1.0.0\",\"event\":\"command\",\
+             \"data\":{\"sender\":\"alice@example.com\",\
+             \"account_id\":\"alice_example_com.controller.near\",\"command\":\"init\"}}"
+        );
+        assert_eq!(ControllerEvent::from_log(&log), Some(event));
+
+        assert_eq!(ControllerEvent::from_log("Email verified: alice"), None);
+        assert_eq!(
+            ControllerEvent::from_log(&log.replace("email-auth", "nep171")),
+            None
+        );
+    }
+}
@@ -0,0 +1,33 @@
+//! Types shared by the dkim-controller and control-delegator contracts and the
+//! email-relayer: the commands, the names and arguments of the contract
+//! methods, the events logged by the controller and the derivation of the
+//! accounts of the senders.
+//!
+//! Account ids and public keys are strings, serialized like the
+//! `near_sdk::AccountId` and `near_sdk::PublicKey` parameters of the
+//! contracts.
+mod account;
+mod args;
+mod command;
+mod event;
+
+pub use account::sender_to_account;
+pub use args::{AddKeyArgs, DeleteKeyArgs, ReceiveEmailArgs, SetControllerArgs, TransferArgs};
+pub use command::{Balance, CommandEnum, NEAR_DECIMALS, ONE_NEAR};
+pub use event::{ControllerEvent, EVENT_STANDARD, EVENT_VERSION};
+
+/// Names of the contract methods
+pub mod method {
+    /// `dkim-controller`, with [crate::ReceiveEmailArgs]
+    pub const RECEIVE_EMAIL: &str = "receive_email";
+    /// `dkim-controller`, with [crate::ReceiveEmailArgs]
+    pub const CHECK_EMAIL: &str = "check_email";
+    /// `control-delegator` initialization, with [crate::SetControllerArgs]
+    pub const SET_CONTROLLER: &str = "set_controller";
+    /// `control-delegator`, with [crate::AddKeyArgs]
+    pub const ADD_KEY: &str = "add_key";
+    /// `control-delegator`, with [crate::DeleteKeyArgs]
+    pub const DELETE_KEY: &str = "delete_key";
+    /// `control-delegator`, with [crate::TransferArgs]
+    pub const TRANSFER: &str = "transfer";
+}
@@ -0,0 +1,68 @@
+use std::fmt;
+
+/// What is wrong with a command
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommandErrorKind {
+    Empty,
+    UnknownCommand(String),
+    /// Name of the missing argument
+    MissingArgument(&'static str),
+    UnexpectedArgument(String),
+    UnterminatedQuote,
+    InvalidPublicKey(&'static str),
+    InvalidAccountId(&'static str),
+    InvalidAmount(&'static str),
+}
+
+impl fmt::Display for CommandErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use CommandErrorKind::*;
+        match self {
+            Empty => write!(f, "empty command"),
+            UnknownCommand(name) => write!(f, "unrecognized command \"{}\"", name),
+            MissingArgument(name) => write!(f, "missing {}", name),
+            UnexpectedArgument(value) => write!(f, "unexpected argument \"{}\"", value),
+            UnterminatedQuote => write!(f, "unterminated quote"),
+            InvalidPublicKey(reason) => write!(f, "invalid public key: {}", reason),
+            InvalidAccountId(reason) => write!(f, "invalid account id: {}", reason),
+            InvalidAmount(reason) => write!(f, "invalid amount: {}", reason),
+        }
+    }
+}
+
+/// Error of [crate::parse_command], at a byte offset of the command
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandError {
+    pub kind: CommandErrorKind,
+    /// Byte offset of the error in the command
+    pub position: usize,
+    /// Corrected command or argument, when the mistake is likely a typo
+    pub suggestion: Option<String>,
+}
+
+impl CommandError {
+    pub(crate) fn new(kind: CommandErrorKind, position: usize) -> Self {
+        CommandError {
+            kind,
+            position,
+            suggestion: None,
+        }
+    }
+
+    pub(crate) fn with_suggestion(mut self, suggestion: impl Into<String>) -> Self {
+        self.suggestion = Some(suggestion.into());
+        self
+    }
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at position {}", self.kind, self.position)?;
+        if let Some(suggestion) = &self.suggestion {
+            write!(f, ", did you mean \"{}\"?", suggestion)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for CommandError {}
@@ -0,0 +1,502 @@
+//! Commands sent in the Subject of an email to the dkim-controller contract.
+//!
+//! ```text
+//! command    = init / add-key / delete-key / transfer
+//! init       = "init"
+//! add-key    = "add_key" WSP public-key
+//! delete-key = "delete_key" WSP public-key
+//! transfer   = "transfer" WSP account-id WSP amount [WSP "near"]
+//! public-key = "ed25519:" base58           ; 32 bytes
+//! account-id = part *(("." / "-" / "_") part)  ; 2 to 64 characters
+//! part       = 1*(%x61-7A / DIGIT)
+//! amount     = 1*DIGIT ["." 1*24DIGIT]      ; in NEAR, positive
+//! ```
+//!
+//! The command names, the key type and `near` are case-insensitive. Any
+//! argument may be double-quoted and `WSP` is any Unicode whitespace. The
+//! subject should be normalized first with [SubjectNormalizer].
+mod error;
+mod subject;
+
+pub use email_auth_types::{Balance, CommandEnum, ONE_NEAR};
+pub use error::{CommandError, CommandErrorKind};
+pub use subject::{SubjectNormalizer, DEFAULT_PREFIXES, REPLY_PREFIXES};
+
+use email_auth_types::NEAR_DECIMALS;
+use CommandErrorKind::*;
+
+const COMMANDS: &[&str] = &["init", "add_key", "delete_key", "transfer"];
+const BASE58_ALPHABET: &str = "123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+
+/// A word of the command and its byte offset
+#[derive(Debug, Clone, Copy)]
+struct Token<'a> {
+    value: &'a str,
+    position: usize,
+}
+
+fn tokenize(command: &str) -> Result<Vec<Token<'_>>, CommandError> {
+    let mut tokens = vec![];
+    let mut start = 0;
+    while let Some(ix) = command[start..].find(|c: char| !c.is_whitespace()) {
+        start += ix;
+        let rest = &command[start..];
+        let (position, len) = if let Some(quoted) = rest.strip_prefix('"') {
+            let len = quoted
+                .find('"')
+                .ok_or_else(|| CommandError::new(UnterminatedQuote, start))?;
+            (start + 1, len)
+        } else {
+            (start, rest.find(char::is_whitespace).unwrap_or(rest.len()))
+        };
+        tokens.push(Token {
+            value: &command[position..position + len],
+            position,
+        });
+        // After the closing quote, if any
+        start = position + len + (position - start);
+    }
+    Ok(tokens)
+}
+
+/// The arguments of a command, consumed in order
+struct Arguments<'a> {
+    tokens: &'a [Token<'a>],
+    end: usize,
+}
+
+impl<'a> Arguments<'a> {
+    fn optional(&mut self) -> Option<Token<'a>> {
+        let (first, rest) = self.tokens.split_first()?;
+        self.tokens = rest;
+        Some(*first)
+    }
+
+    fn required(&mut self, name: &'static str) -> Result<Token<'a>, CommandError> {
+        self.optional()
+            .ok_or_else(|| CommandError::new(MissingArgument(name), self.end))
+    }
+
+    fn finish(&self) -> Result<(), CommandError> {
+        match self.tokens.first() {
+            Some(token) => Err(CommandError::new(
+                UnexpectedArgument(token.value.to_owned()),
+                token.position,
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Parses a command, see the grammar of the crate
+pub fn parse_command(command: &str) -> Result<CommandEnum, CommandError> {
+    let tokens = tokenize(command)?;
+    let (name, tokens) = tokens
+        .split_first()
+        .ok_or_else(|| CommandError::new(Empty, command.len()))?;
+    let mut args = Arguments {
+        tokens,
+        end: command.trim_end().len(),
+    };
+
+    let parsed = match name.value.to_lowercase().as_str() {
+        "init" => CommandEnum::Init,
+        "add_key" => CommandEnum::AddKey(public_key_arg(args.required("public key")?)?),
+        "delete_key" => CommandEnum::DeleteKey(public_key_arg(args.required("public key")?)?),
+        "transfer" => {
+            let account = account_id_arg(args.required("account id")?)?;
+            let amount = amount_arg(args.required("amount")?)?;
+            if args
+                .tokens
+                .first()
+                .is_some_and(|unit| unit.value.eq_ignore_ascii_case("near"))
+            {
+                args.optional();
+            }
+            CommandEnum::Transfer(account, amount)
+        }
+        _ => {
+            let err = CommandError::new(UnknownCommand(name.value.to_owned()), name.position);
+            return Err(match closest_command(name.value) {
+                Some(command) => err.with_suggestion(command),
+                None => err,
+            });
+        }
+    };
+    args.finish()?;
+    Ok(parsed)
+}
+
+/// Parses the public key argument of a command. The positions of the errors
+/// are in `value`.
+pub fn parse_public_key(value: &str) -> Result<String, CommandError> {
+    public_key_arg(Token { value, position: 0 })
+}
+
+/// Parses the account id argument of a command
+pub fn parse_account_id(value: &str) -> Result<String, CommandError> {
+    account_id_arg(Token { value, position: 0 })
+}
+
+/// Parses the amount argument of a command, in NEAR, to yoctoNEAR
+pub fn parse_amount(value: &str) -> Result<Balance, CommandError> {
+    amount_arg(Token { value, position: 0 })
+}
+
+/// The command at most two edits away from `name`
+fn closest_command(name: &str) -> Option<&'static str> {
+    let name = name.to_lowercase();
+    COMMANDS
+        .iter()
+        .map(|command| (edit_distance(&name, command), *command))
+        .filter(|(distance, _)| *distance <= 2)
+        .min()
+        .map(|(_, command)| command)
+}
+
+/// Levenshtein distance between two strings, in characters
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+fn public_key_arg(token: Token) -> Result<String, CommandError> {
+    let (key_type, data) = match token.value.split_once(':') {
+        Some(parts) => parts,
+        None => {
+            let err = CommandError::new(
+                InvalidPublicKey("missing the \"ed25519:\" prefix"),
+                token.position,
+            );
+            return Err(if decode_ed25519(token.value).is_some() {
+                err.with_suggestion(format!("ed25519:{}", token.value))
+            } else {
+                err
+            });
+        }
+    };
+    if !key_type.eq_ignore_ascii_case("ed25519") {
+        return Err(CommandError::new(
+            InvalidPublicKey("unsupported key type, expected ed25519"),
+            token.position,
+        ));
+    }
+    let position = token.position + key_type.len() + 1;
+    if let Some(ix) = data.find(|c| !BASE58_ALPHABET.contains(c)) {
+        return Err(CommandError::new(
+            InvalidPublicKey("invalid base58 character"),
+            position + ix,
+        ));
+    }
+    match decode_ed25519(data) {
+        Some(_) => Ok(format!("ed25519:{}", data)),
+        None => Err(CommandError::new(
+            InvalidPublicKey("expected 32 bytes"),
+            position,
+        )),
+    }
+}
+
+fn decode_ed25519(data: &str) -> Option<Vec<u8>> {
+    bs58::decode(data)
+        .into_vec()
+        .ok()
+        .filter(|bytes| bytes.len() == 32)
+}
+
+fn account_id_arg(token: Token) -> Result<String, CommandError> {
+    match account_id_error(token.value) {
+        None => Ok(token.value.to_owned()),
+        Some((ix, reason)) => {
+            let err = CommandError::new(InvalidAccountId(reason), token.position + ix);
+            let lowercase = token.value.to_lowercase();
+            Err(if account_id_error(&lowercase).is_none() {
+                err.with_suggestion(lowercase)
+            } else {
+                err
+            })
+        }
+    }
+}
+
+/// The offset and reason of the first error of an account id
+fn account_id_error(value: &str) -> Option<(usize, &'static str)> {
+    if !(2..=64).contains(&value.len()) {
+        return Some((0, "must be 2 to 64 characters long"));
+    }
+    let mut separator = true;
+    for (ix, c) in value.char_indices() {
+        match c {
+            'a'..='z' | '0'..='9' => separator = false,
+            '.' | '-' | '_' if !separator && ix + 1 < value.len() => separator = true,
+            '.' | '-' | '_' => {
+                return Some((ix, "separators must be between letters or digits"));
+            }
+            'A'..='Z' => return Some((ix, "must be lowercase")),
+            _ => return Some((ix, "invalid character")),
+        }
+    }
+    None
+}
+
+fn amount_arg(token: Token) -> Result<Balance, CommandError> {
+    let value = token.value;
+    let error = |reason, ix| CommandError::new(InvalidAmount(reason), token.position + ix);
+    if let Some(ix) = value.find(',') {
+        let err = error("use a dot as decimal separator", ix);
+        return Err(if value.matches(',').count() == 1 && !value.contains('.') {
+            err.with_suggestion(value.replace(',', "."))
+        } else {
+            err
+        });
+    }
+
+    let (integer, decimals) = value.split_once('.').unwrap_or((value, ""));
+    let not_digit = |c: char| !c.is_ascii_digit();
+    if let Some(ix) = integer.find(not_digit) {
+        return Err(error("expected a number like 1.5", ix));
+    }
+    if let Some(ix) = decimals.find(not_digit) {
+        return Err(error("expected a number like 1.5", integer.len() + 1 + ix));
+    }
+    if integer.is_empty() || (decimals.is_empty() && value.contains('.')) {
+        return Err(error("expected a number like 1.5", 0));
+    }
+    if decimals.len() > NEAR_DECIMALS {
+        return Err(error(
+            "more than 24 decimals",
+            integer.len() + 1 + NEAR_DECIMALS,
+        ));
+    }
+
+    let decimals: Balance = format!("{:0<width$}", decimals, width = NEAR_DECIMALS)
+        .parse()
+        .expect("24 digits");
+    let amount = integer
+        .parse::<Balance>()
+        .ok()
+        .and_then(|integer| integer.checked_mul(ONE_NEAR))
+        .and_then(|integer| integer.checked_add(decimals))
+        .ok_or_else(|| error("too large", 0))?;
+    if amount == 0 {
+        return Err(error("must be positive", 0));
+    }
+    Ok(amount)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "ed25519:3tXAA9zf5YSLxYELSbxwhEvMd7h9itTfCcUfEc3QfPgD";
+
+    fn error(command: &str) -> (CommandErrorKind, usize, Option<String>) {
+        let err = parse_command(command).unwrap_err();
+        (err.kind, err.position, err.suggestion)
+    }
+
+    #[test]
+    fn test_parse_command() {
+        for (command, expected) in [
+            ("init", CommandEnum::Init),
+            (" INIT ", CommandEnum::Init),
+            (
+                &format!("add_key {}", KEY),
+                CommandEnum::AddKey(KEY.to_owned()),
+            ),
+            (
+                &format!("Add_Key \"{}\"", KEY.replace("ed25519", "ED25519")),
+                CommandEnum::AddKey(KEY.to_owned()),
+            ),
+            (
+                &format!("delete_key\t{}", KEY),
+                CommandEnum::DeleteKey(KEY.to_owned()),
+            ),
+            (
+                "transfer bob.near 1.5",
+                CommandEnum::Transfer("bob.near".to_owned(), 15 * ONE_NEAR / 10),
+            ),
+            (
+                "Transfer \"bob.near\"  134 NEAR",
+                CommandEnum::Transfer("bob.near".to_owned(), 134 * ONE_NEAR),
+            ),
+            (
+                "transfer bob.near 0.000000000000000000000001",
+                CommandEnum::Transfer("bob.near".to_owned(), 1),
+            ),
+        ] {
+            assert_eq!(parse_command(command), Ok(expected), "{:?}", command);
+        }
+    }
+
+    #[test]
+    fn test_parse_command_errors() {
+        assert_eq!(error("  "), (Empty, 2, None));
+        assert_eq!(
+            error("add-key x"),
+            (
+                UnknownCommand("add-key".to_owned()),
+                0,
+                Some("add_key".to_owned())
+            )
+        );
+        assert_eq!(
+            error("hello"),
+            (UnknownCommand("hello".to_owned()), 0, None)
+        );
+        assert_eq!(error("add_key "), (MissingArgument("public key"), 7, None));
+        assert_eq!(
+            error("delete_key"),
+            (MissingArgument("public key"), 10, None)
+        );
+        assert_eq!(
+            error(&format!("add_key {}", &KEY[8..])),
+            (
+                InvalidPublicKey("missing the \"ed25519:\" prefix"),
+                8,
+                Some(KEY.to_owned())
+            )
+        );
+        assert_eq!(
+            error("add_key ed25519:3tX0"),
+            (InvalidPublicKey("invalid base58 character"), 19, None)
+        );
+        assert_eq!(
+            error("add_key ed25519:3tXA"),
+            (InvalidPublicKey("expected 32 bytes"), 16, None)
+        );
+        assert_eq!(
+            error("init now"),
+            (UnexpectedArgument("now".to_owned()), 5, None)
+        );
+        assert_eq!(error("transfer \"bob.near 1"), (UnterminatedQuote, 9, None));
+        assert_eq!(
+            error("transfer Bob.near 1"),
+            (
+                InvalidAccountId("must be lowercase"),
+                9,
+                Some("bob.near".to_owned())
+            )
+        );
+        assert_eq!(
+            error("transfer bob..near 1"),
+            (
+                InvalidAccountId("separators must be between letters or digits"),
+                13,
+                None
+            )
+        );
+        assert_eq!(
+            error("transfer bob.near"),
+            (MissingArgument("amount"), 17, None)
+        );
+        assert_eq!(
+            error("transfer bob.near 1,5"),
+            (
+                InvalidAmount("use a dot as decimal separator"),
+                19,
+                Some("1.5".to_owned())
+            )
+        );
+        assert_eq!(
+            error("transfer bob.near 1.5e3"),
+            (InvalidAmount("expected a number like 1.5"), 21, None)
+        );
+        assert_eq!(
+            error("transfer bob.near 0.00"),
+            (InvalidAmount("must be positive"), 18, None)
+        );
+        assert_eq!(
+            error("transfer bob.near 1 near now"),
+            (UnexpectedArgument("now".to_owned()), 25, None)
+        );
+
+        assert_eq!(
+            parse_command("ad_key").unwrap_err().to_string(),
+            "unrecognized command \"ad_key\" at position 0, did you mean \"add_key\"?"
+        );
+    }
+
+    #[test]
+    fn test_display_roundtrip() {
+        for command in [
+            CommandEnum::Init,
+            CommandEnum::AddKey(KEY.to_owned()),
+            CommandEnum::DeleteKey(KEY.to_owned()),
+            CommandEnum::Transfer("bob.near".to_owned(), 15 * ONE_NEAR / 10),
+            CommandEnum::Transfer("a-b_c.near".to_owned(), u128::MAX),
+        ] {
+            assert_eq!(parse_command(&command.to_string()), Ok(command));
+        }
+        assert_eq!(
+            CommandEnum::Transfer("bob.near".to_owned(), 1).to_string(),
+            "transfer bob.near 0.000000000000000000000001"
+        );
+    }
+
+    /// xorshift64, to generate the same inputs on every run
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self, bound: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % bound as u64) as usize
+        }
+    }
+
+    /// Random inputs and mutations of valid commands never panic, report
+    /// positions within the input and parse to commands which roundtrip
+    #[test]
+    fn test_fuzz() {
+        let valid = [
+            "init".to_owned(),
+            format!("add_key {}", KEY),
+            format!("delete_key {}", KEY),
+            "transfer bob.near 1.5 near".to_owned(),
+        ];
+        let alphabet: Vec<char> = "aAzZ019._-:\" \t\u{A0}é,near".chars().collect();
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        for i in 0..20_000 {
+            let mut input: Vec<char> = if i % 2 == 0 {
+                valid[rng.next(valid.len())].chars().collect()
+            } else {
+                vec![]
+            };
+            for _ in 0..=rng.next(4) {
+                let c = alphabet[rng.next(alphabet.len())];
+                let ix = rng.next(input.len() + 1);
+                match rng.next(3) {
+                    0 if ix < input.len() => input[ix] = c,
+                    1 if ix < input.len() => {
+                        input.remove(ix);
+                    }
+                    _ => input.insert(ix, c),
+                }
+            }
+            let input: String = input.into_iter().collect();
+
+            match parse_command(&input) {
+                Ok(command) => {
+                    assert_eq!(
+                        parse_command(&command.to_string()),
+                        Ok(command),
+                        "{:?}",
+                        input
+                    )
+                }
+                Err(err) => assert!(input.is_char_boundary(err.position), "{:?}", input),
+            }
+        }
+    }
+}
@@ -0,0 +1,127 @@
+//! Normalization of the Subject of an email before it is parsed as a
+//! command. Mail clients add reply and forward prefixes, in the language of
+//! the user, and some insert non-breaking or zero-width spaces.
+//!
+//! The subject must be decoded first: `mailparse::MailHeader::get_value`
+//! decodes the encoded words (RFC 2047, `=?UTF-8?B?...?=`) and unfolds the
+//! header.
+//!
+//! Reply prefixes are kept by default, so that a reply is not a valid
+//! command: the reply goes to the Reply-To chosen by the sender of the email
+//! replied to, who also chose its subject. Replying to `transfer mallory.near
+//! 100` with `Reply-To: <relayer>` must not transfer anything.
+
+/// Forward prefixes of the common mail clients, compared without case:
+/// English, German, Dutch, French, Italian and Chinese
+pub const DEFAULT_PREFIXES: &[&str] = &["fw", "fwd", "wg", "doorst", "tr", "i", "转发"];
+
+/// Reply prefixes of the common mail clients: English, German, Nordic,
+/// Dutch, Italian and Chinese. `VS` is a Finnish reply and a Danish forward.
+/// Only strip them if the sender of the email replied to is trusted, see the
+/// module documentation.
+pub const REPLY_PREFIXES: &[&str] = &["re", "aw", "sv", "vs", "antw", "rif", "回复", "答复"];
+
+/// Characters some clients insert between words, removed from the subject
+const ZERO_WIDTH: &[char] = &['\u{200B}', '\u{200C}', '\u{200D}', '\u{2060}', '\u{FEFF}'];
+
+/// Normalizes subjects, see [SubjectNormalizer::normalize]. The default one
+/// strips the [DEFAULT_PREFIXES].
+pub struct SubjectNormalizer {
+    prefixes: Vec<String>,
+}
+
+impl Default for SubjectNormalizer {
+    fn default() -> Self {
+        SubjectNormalizer::new(DEFAULT_PREFIXES)
+    }
+}
+
+impl SubjectNormalizer {
+    /// Normalizer stripping the given prefixes, `Re` strips `Re:`, `RE :`,
+    /// `Re[2]:` and `Re：` with the full-width colon of Chinese clients
+    pub fn new(prefixes: &[&str]) -> Self {
+        SubjectNormalizer {
+            prefixes: prefixes.iter().map(|prefix| prefix.to_string()).collect(),
+        }
+    }
+
+    /// Returns the decoded `subject` with its whitespace reduced to single
+    /// spaces and without its leading reply and forward prefixes
+    pub fn normalize(&self, subject: &str) -> String {
+        let subject: String = subject
+            .chars()
+            .filter(|c| !ZERO_WIDTH.contains(c))
+            .collect();
+        let subject = subject.split_whitespace().collect::<Vec<_>>().join(" ");
+
+        let mut rest = subject.as_str();
+        while let Some(stripped) = self.strip_prefix(rest) {
+            rest = stripped;
+        }
+        rest.to_owned()
+    }
+
+    fn strip_prefix<'a>(&self, subject: &'a str) -> Option<&'a str> {
+        let (word, rest) = subject.split_once([':', '：'])?;
+        let word = word.trim_end();
+        // Reply counter of Outlook and older clients: `Re[2]:` or `Re(2):`
+        let word = match word.split_once(['[', '(']) {
+            Some((word, counter))
+                if counter.len() > 1
+                    && counter.ends_with([']', ')'])
+                    && counter[..counter.len() - 1]
+                        .chars()
+                        .all(|c| c.is_ascii_digit()) =>
+            {
+                word.trim_end()
+            }
+            Some(_) => return None,
+            None => word,
+        };
+        self.prefixes
+            .iter()
+            .any(|prefix| prefix.eq_ignore_ascii_case(word))
+            .then(|| rest.trim_start())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        let normalizer = SubjectNormalizer::default();
+        for (subject, expected) in [
+            ("init", "init"),
+            ("Fwd: init", "init"),
+            ("FW: Fwd: fw[2]: WG : init", "init"),
+            ("转发: init", "init"),
+            ("转发：init", "init"),
+            ("transfer\u{A0}bob.near\u{2003} 1", "transfer bob.near 1"),
+            ("\u{FEFF}in\u{200B}it", "init"),
+            ("  \tadd_key ed25519:abc  ", "add_key ed25519:abc"),
+            ("Fwd:", ""),
+            // Not a prefix
+            ("Forward: init", "Forward: init"),
+            ("Fw[x]: init", "Fw[x]: init"),
+            // Replies are kept
+            ("Re: init", "Re: init"),
+            ("Fwd: 回复：init", "回复：init"),
+        ] {
+            assert_eq!(normalizer.normalize(subject), expected, "{:?}", subject);
+        }
+
+        let normalizer = SubjectNormalizer::new(&[DEFAULT_PREFIXES, REPLY_PREFIXES].concat());
+        for (subject, expected) in [
+            ("RE: Fwd: re[2]: AW : init", "init"),
+            ("回复：转发: init", "init"),
+            ("Regarding: init", "Regarding: init"),
+        ] {
+            assert_eq!(normalizer.normalize(subject), expected, "{:?}", subject);
+        }
+
+        let normalizer = SubjectNormalizer::new(&["Ynt"]);
+        assert_eq!(normalizer.normalize("YNT: Re: init"), "Re: init");
+    }
+}
@@ -1,6 +1,8 @@
+use email_auth_types::{method, ControllerEvent, ReceiveEmailArgs};
+use email_command::{parse_command, CommandEnum, SubjectNormalizer};
 use imap::Session;
+use mailparse::MailHeaderMap;
 use native_tls::TlsStream;
-use serde_json::json;
 use std::str::FromStr;
 use std::{env, net::TcpStream, time::Duration};
 use workspaces::{types::SecretKey, Network};
@@ -66,22 +68,43 @@ async fn send_mail<N: Network + ?Sized>(
     controller_account_id: &AccountId,
     mail: &str,
 ) -> anyhow::Result<()> {
-    let payload = mail.as_bytes().to_vec();
-    worker
-        .call(signer, controller_account_id, "receive_email")
-        .args(json!({ "full_email": payload }).to_string().into_bytes())
+    let args = ReceiveEmailArgs {
+        full_email: mail.as_bytes().to_vec(),
+    };
+    let outcome = worker
+        .call(signer, controller_account_id, method::RECEIVE_EMAIL)
+        .args(serde_json::to_vec(&args)?)
         .max_gas()
         .transact()
         .await?
         .into_result()?;
+    for event in outcome
+        .logs()
+        .into_iter()
+        .filter_map(ControllerEvent::from_log)
+    {
+        println!("Controller event: {:?}", event);
+    }
     Ok(())
 }
 
+/// Parses the command in the Subject of an email, as the controller does
+fn parse_mail_command(mail: &str) -> anyhow::Result<CommandEnum> {
+    let parsed = mailparse::parse_mail(mail.as_bytes())?;
+    let subject = parsed
+        .get_headers()
+        .get_first_value("Subject")
+        .ok_or_else(|| anyhow::anyhow!("The email lacks \"Subject\" header"))?;
+    Ok(parse_command(
+        &SubjectNormalizer::default().normalize(&subject),
+    )?)
+}
+
 fn parse_env_var<T: FromStr>(key: &str) -> anyhow::Result<T> {
-    Ok(env::var(key)
+    env::var(key)
         .map_err(|err| anyhow::anyhow!("Failed to get {}: {}", key, err))?
         .parse()
-        .map_err(|_| anyhow::anyhow!("Failed to parse {}", key))?)
+        .map_err(|_| anyhow::anyhow!("Failed to parse {}", key))
 }
 
 #[tokio::main]
@@ -110,9 +133,41 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         if !mails.is_empty() {
             println!("Got new mail: {:?}", mails.len());
             for mail in mails.iter() {
-                send_mail(&worker, &signer, &controller_account, mail).await?;
+                // Rejected commands would only waste the gas of a transaction
+                match parse_mail_command(mail) {
+                    Ok(command) => {
+                        println!("Sending command: {}", command);
+                        send_mail(&worker, &signer, &controller_account, mail).await?;
+                    }
+                    Err(err) => println!("Ignoring email: {}", err),
+                }
             }
         }
         tokio::time::sleep(Duration::from_secs(10)).await;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_mail_command() {
+        assert_eq!(
+            parse_mail_command(include_str!("init.eml")).unwrap(),
+            CommandEnum::Init
+        );
+        assert_eq!(
+            parse_mail_command(include_str!("add_key.eml")).unwrap(),
+            CommandEnum::AddKey("ed25519:3LFETdPRs5Zm7N3W9Li15aypmm9pLYz1haFpwqeRVtMm".to_owned())
+        );
+        let err = parse_mail_command("Subject: Fwd: transfer bob.near\r\n\r\n").unwrap_err();
+        assert_eq!(err.to_string(), "missing amount at position 17");
+        // A reply is not a command
+        let err = parse_mail_command("Subject: Re: init\r\n\r\n").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unrecognized command \"Re:\" at position 0"
+        );
+    }
+}